@@ -0,0 +1,1743 @@
+//! Configuring and running a backup.
+//!
+use crate::cancel::CancelToken;
+use crate::checkpoint::{Checkpoint, CheckpointItem};
+use crate::error::{self, Error, FileError, RuleItemError};
+use crate::explain::{self, Explanation, RuleOutcome};
+use crate::files::{
+    canonicalize_missing, copy_file, create_backup_file_path, is_newer, is_storage_full,
+};
+use crate::history::METADATA_DIR;
+use crate::lock::{RunLock, LOCK_FILE};
+use crate::manifest::Manifest;
+use crate::marker::DestinationCheck;
+use crate::observer::{observe_rule, BackupEvent, BackupObserver, SharedObserver};
+use crate::report::{BackupReport, FileOutcome, FileReport, Limit, LimitedFile};
+use crate::rules::default_rules;
+use crate::scan::Scan;
+use rebackup::{walk, WalkerConfig, WalkerErr, WalkerItemType, WalkerRule, WalkerRuleResult};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+/// A configured backup, created with a [`BackupBuilder`].
+pub struct Backup {
+    sources: Vec<PathBuf>,
+    destination: PathBuf,
+    /// The walk of the sources without rules of their own.
+    config: WalkerConfig,
+    /// The walk of each source in `sources` that has rules of its own.
+    source_configs: Vec<Option<WalkerConfig>>,
+    observer: Option<SharedObserver>,
+    exclude_destination: bool,
+    destination_check: DestinationCheck,
+    reserve: u64,
+    name: Option<String>,
+    lock_wait: Duration,
+    cancel: CancelToken,
+    resume: bool,
+    /// The destination as resolved by the last run, if it has to be excluded from the walk.
+    excluded_destination: Rc<RefCell<Option<PathBuf>>>,
+    max_file_size: Option<u64>,
+    max_file_age: Option<Duration>,
+    /// The files left out by the limits in the current run.
+    limited: RefCell<Vec<LimitedFile>>,
+    /// The resolved sources inside the source being walked, which are walked on their own.
+    nested_sources: Rc<RefCell<Vec<PathBuf>>>,
+    /// The items the rules failed on in the current run, see [`RuleItemError`].
+    rule_failures: Rc<RefCell<Vec<RuleFailure>>>,
+}
+
+/// An item a rule has failed on with a [`RuleItemError`].
+struct RuleFailure {
+    path: PathBuf,
+    rule: &'static str,
+    error: io::Error,
+}
+
+impl Backup {
+    /// Starts configuring a backup.
+    pub fn builder() -> BackupBuilder {
+        BackupBuilder::default()
+    }
+
+    /// The source directories that are backed up.
+    pub fn sources(&self) -> &[PathBuf] {
+        &self.sources
+    }
+
+    /// The backup directory or drive.
+    pub fn destination(&self) -> &Path {
+        &self.destination
+    }
+
+    /// Backs up all the sources, copying the files that are newer than the ones in the backup.
+    ///
+    /// Failing to copy a single file does not stop the backup, the error is recorded in the
+    /// returned report instead. Only when the destination is full the backup stops, and the
+    /// files that have not been copied are recorded as failed.
+    ///
+    /// If the backup is cancelled with its [`CancelToken`] it stops right away, leaving the
+    /// previous backup of the file being copied in place, and the returned report is
+    /// [interrupted](BackupReport::interrupted).
+    pub fn run(&self) -> error::Result<BackupReport> {
+        let _lock = self.prepare()?;
+
+        let resumed = if self.resume {
+            self.resumed_plan()
+        } else {
+            None
+        };
+        let (plan, checkpoint, walked) = match resumed {
+            Some((plan, checkpoint)) => (plan, Some(checkpoint), false),
+            None => match self.plan() {
+                Ok(mut plan) => {
+                    let checkpoint = self.create_checkpoint(&mut plan);
+                    (plan, checkpoint, true)
+                }
+                // The walk is aborted by the cancel rule
+                Err(Error::Walk { .. }) if self.cancel.is_cancelled() => {
+                    self.emit(BackupEvent::Interrupted { remaining: 0 });
+                    return Ok(BackupReport {
+                        interrupted: true,
+                        ..BackupReport::default()
+                    });
+                }
+                Err(err) => return Err(err),
+            },
+        };
+
+        let report = self.execute(plan, checkpoint)?;
+        // Only a backup that walked all sources knows all the items in the backup
+        if walked && !report.interrupted {
+            self.write_manifest(&report);
+        }
+        Ok(report)
+    }
+
+    /// Backs up only the items at `paths`, which have changed in the sources: files, and
+    /// directories whose content is backed up as a whole. Paths that are excluded by the
+    /// rules, have been removed, or are not in a source are left out.
+    ///
+    /// This is used to back up the changes found by [`watch`](crate::watch::watch) without
+    /// walking the whole sources. No checkpoint is written, so it cannot be resumed.
+    pub fn run_paths(&self, paths: &[PathBuf]) -> error::Result<BackupReport> {
+        let _lock = self.prepare()?;
+
+        let plan = match self.plan_paths(paths) {
+            Ok(plan) => plan,
+            Err(Error::Walk { .. }) if self.cancel.is_cancelled() => {
+                self.emit(BackupEvent::Interrupted { remaining: 0 });
+                return Ok(BackupReport {
+                    interrupted: true,
+                    ..BackupReport::default()
+                });
+            }
+            Err(err) => return Err(err),
+        };
+
+        self.execute(plan, None)
+    }
+
+    /// Explains whether the item at `path` is backed up: how every rule decides on it and
+    /// on its ancestors, and whether it would be copied. Nothing is copied or locked.
+    pub fn explain(&self, path: &Path) -> error::Result<Explanation> {
+        self.check_overlap()?;
+        self.limited.borrow_mut().clear();
+        self.rule_failures.borrow_mut().clear();
+        // An item is explained with the innermost source it is in, so no source is nested
+        self.nested_sources.borrow_mut().clear();
+        let sources: Vec<_> = self
+            .sources
+            .iter()
+            .enumerate()
+            .map(|(i, source)| (source.as_path(), self.source_config(i)))
+            .collect();
+        let mut explanation = explain::explain(&sources, &self.destination, path)?;
+
+        // A rule failing on an item excludes it, but the explanation shows the failure
+        for failure in self.rule_failures.take() {
+            let evaluation = explanation
+                .items
+                .iter_mut()
+                .filter(|item| item.path == failure.path)
+                .flat_map(|item| &mut item.rules)
+                .find(|rule| rule.rule == failure.rule);
+            if let Some(evaluation) = evaluation {
+                evaluation.outcome = RuleOutcome::Failed(failure.error.to_string());
+            }
+        }
+
+        // The limits are checked after the rules, whichever rule included the file
+        if explanation.included {
+            if let Some(file) = self.over_limit(&explanation.path) {
+                explanation.included = false;
+                explanation.change = None;
+                if let Some(item) = explanation.items.last_mut() {
+                    item.included = false;
+                    item.note = Some(format!("Left out by the limits: {}", file));
+                }
+            }
+        }
+        Ok(explanation)
+    }
+
+    /// Checks the destination and locks it for a run.
+    fn prepare(&self) -> error::Result<RunLock> {
+        self.check_overlap()?;
+        self.destination_check.verify(&self.destination)?;
+
+        fs::create_dir_all(&self.destination).map_err(|source| Error::Destination {
+            path: self.destination.clone(),
+            source,
+        })?;
+
+        RunLock::acquire(
+            &self.destination.join(METADATA_DIR).join(LOCK_FILE),
+            self.name.as_deref(),
+            self.lock_wait,
+            |holder| {
+                self.emit(BackupEvent::WaitingForLock {
+                    path: self.destination.clone(),
+                    holder: holder.to_string(),
+                })
+            },
+        )
+    }
+
+    /// Writes the [manifest](crate::manifest) of the backup. A backup whose manifest cannot
+    /// be written has still backed up its items, so the manifest is just left as it was.
+    fn write_manifest(&self, report: &BackupReport) {
+        let Ok(resolved) = self.resolve_sources() else {
+            return;
+        };
+        let sources: Vec<(PathBuf, PathBuf)> = resolved
+            .into_iter()
+            .map(|(i, source)| (self.sources[i].clone(), source))
+            .collect();
+        let _ = Manifest::new(&sources, report).write(&self.destination);
+    }
+
+    /// Copies the planned items, recording the ones copied in the checkpoint.
+    fn execute(
+        &self,
+        plan: Vec<PlannedItem>,
+        mut checkpoint: Option<Checkpoint>,
+    ) -> error::Result<BackupReport> {
+        let mut report = BackupReport {
+            limited: self.limited.take(),
+            ..BackupReport::default()
+        };
+
+        self.emit(BackupEvent::Planned {
+            items: plan.len(),
+            files_to_copy: plan.iter().filter(|i| i.needs_copy && !i.is_dir).count(),
+            bytes_to_copy: plan.iter().filter(|i| i.needs_copy).map(|i| i.size).sum(),
+        });
+
+        // The items the rules failed on are reported like files that could not be copied
+        for failure in self.rule_failures.take() {
+            let err = FileError {
+                path: failure.path.clone(),
+                source: failure.error,
+            };
+            self.emit(BackupEvent::Error {
+                path: failure.path.clone(),
+                kind: err.kind(),
+                message: err.to_string(),
+            });
+            report.files.push(FileReport {
+                destination: create_backup_file_path(&failure.path, &self.destination),
+                source: failure.path,
+                outcome: FileOutcome::Failed(err),
+            });
+        }
+
+        self.check_space(&plan)?;
+
+        let mut storage_full = false;
+        let mut remaining = 0;
+        for item in plan {
+            if self.cancel.is_cancelled() {
+                remaining += usize::from(item.needs_copy);
+                continue;
+            }
+
+            let checkpoint_index = item.checkpoint_index;
+            let file = if storage_full && item.needs_copy {
+                self.not_copied(item)
+            } else {
+                self.backup_item(item)
+            };
+
+            match &file.outcome {
+                // The file has not been copied, it is left for the next backup
+                FileOutcome::Failed(err) if self.is_interrupted(err) => {
+                    remaining += 1;
+                    continue;
+                }
+                // Stop copying once the destination is full, as all following copies would
+                // fail after filling it up to the last byte
+                FileOutcome::Failed(err) => storage_full |= is_storage_full(err.kind()),
+                _ => {
+                    if let (Some(checkpoint), Some(index)) = (&mut checkpoint, checkpoint_index) {
+                        // Without the journal the file is just copied again when resuming
+                        let _ = checkpoint.done(index);
+                    }
+                }
+            }
+            report.files.push(file);
+        }
+
+        if self.cancel.is_cancelled() {
+            report.interrupted = true;
+            self.emit(BackupEvent::Interrupted { remaining });
+        } else if report.is_success() {
+            if let Some(checkpoint) = checkpoint {
+                let _ = checkpoint.remove();
+            }
+        }
+
+        self.emit(BackupEvent::RunFinished {
+            copied: report.copied(),
+            directories_created: report.directories_created(),
+            unchanged: report.unchanged(),
+            failed: report.failed(),
+            bytes_copied: report.bytes_copied(),
+        });
+
+        Ok(report)
+    }
+
+    /// Checks that the destination is not inside one of the sources, after resolving
+    /// symbolic links. If [`BackupBuilder::exclude_destination`] is set the destination is
+    /// excluded from the walk instead.
+    fn check_overlap(&self) -> error::Result<()> {
+        let destination =
+            canonicalize_missing(&self.destination).map_err(|source| Error::Destination {
+                path: self.destination.clone(),
+                source,
+            })?;
+
+        let mut excluded = None;
+        for source in &self.sources {
+            // A missing source is reported by the walk
+            let Ok(source) = fs::canonicalize(source) else {
+                continue;
+            };
+
+            if destination.starts_with(&source) {
+                // Excluding the destination would leave nothing to back up
+                if !self.exclude_destination || destination == source {
+                    return Err(Error::Overlap {
+                        path: source,
+                        destination,
+                    });
+                }
+                excluded = Some(destination.clone());
+            }
+        }
+
+        *self.excluded_destination.borrow_mut() = excluded;
+        Ok(())
+    }
+
+    /// Checks that the files to be copied fit on the destination, keeping the reserve free.
+    ///
+    /// If the free space cannot be determined the backup runs anyway.
+    fn check_space(&self, plan: &[PlannedItem]) -> error::Result<()> {
+        let Ok(available) = fs4::available_space(&self.destination) else {
+            return Ok(());
+        };
+
+        // Files that are replaced free the space of their previous backup
+        let required = plan
+            .iter()
+            .filter(|i| i.needs_copy)
+            .map(|i| i.size.saturating_sub(i.replaced_size))
+            .sum::<u64>();
+
+        if required.saturating_add(self.reserve) > available {
+            return Err(Error::InsufficientSpace {
+                path: self.destination.clone(),
+                required,
+                reserve: self.reserve,
+                available,
+            });
+        }
+        Ok(())
+    }
+
+    /// Writes the items of `plan` that have to be copied to a checkpoint, so that the backup
+    /// can be resumed if it is interrupted.
+    ///
+    /// A backup that cannot write its checkpoint still runs, it just cannot be resumed.
+    fn create_checkpoint(&self, plan: &mut [PlannedItem]) -> Option<Checkpoint> {
+        let items = plan
+            .iter()
+            .filter(|i| i.needs_copy)
+            .map(|i| CheckpointItem {
+                source: i.source.clone(),
+                destination: i.destination.clone(),
+                is_dir: i.is_dir,
+                size: i.size,
+            })
+            .collect();
+        let checkpoint = Checkpoint::create(&self.destination, &self.sources, items).ok()?;
+
+        // The journal refers to the items by their position in the checkpoint
+        for (index, item) in plan.iter_mut().filter(|i| i.needs_copy).enumerate() {
+            item.checkpoint_index = Some(index);
+        }
+        Some(checkpoint)
+    }
+
+    /// The items left to copy by an interrupted backup of the same sources, `None` if there
+    /// is no such backup to resume.
+    fn resumed_plan(&self) -> Option<(Vec<PlannedItem>, Checkpoint)> {
+        let (checkpoint, items) = Checkpoint::load(&self.destination, &self.sources).ok()??;
+
+        let plan: Vec<PlannedItem> = items
+            .into_iter()
+            .map(|(index, item)| PlannedItem {
+                replaced_size: if item.is_dir {
+                    0
+                } else {
+                    fs::metadata(&item.destination).map_or(0, |m| m.len())
+                },
+                source: item.source,
+                destination: item.destination,
+                is_dir: item.is_dir,
+                size: item.size,
+                needs_copy: true,
+                checkpoint_index: Some(index),
+            })
+            .collect();
+
+        self.emit(BackupEvent::Resumed { items: plan.len() });
+        Some((plan, checkpoint))
+    }
+
+    /// The walk of the source at `index` in `sources`.
+    fn source_config(&self, index: usize) -> &WalkerConfig {
+        self.source_configs[index].as_ref().unwrap_or(&self.config)
+    }
+
+    /// Resolves the sources, leaving out those given more than once. Each source is returned
+    /// with its index in `sources`.
+    fn resolve_sources(&self) -> error::Result<Vec<(usize, PathBuf)>> {
+        let mut resolved: Vec<(usize, PathBuf)> = Vec::new();
+        for (i, source_dir_path) in self.sources.iter().enumerate() {
+            let source = fs::canonicalize(source_dir_path).map_err(|err| Error::Walk {
+                path: source_dir_path.clone(),
+                source: WalkerErr::FailedToCanonicalize(source_dir_path.clone(), err),
+            })?;
+            if !resolved.iter().any(|(_, other)| *other == source) {
+                resolved.push((i, source));
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Sets the sources inside `source`, so that the walk of `source` leaves them to their
+    /// own walk and no item is found twice.
+    fn set_nested_sources(&self, source: &Path, resolved: &[(usize, PathBuf)]) {
+        *self.nested_sources.borrow_mut() = resolved
+            .iter()
+            .map(|(_, other)| other)
+            .filter(|other| other.starts_with(source) && *other != source)
+            .cloned()
+            .collect();
+    }
+
+    /// Walks all the sources and works out which of the items found have to be copied.
+    fn plan(&self) -> error::Result<Vec<PlannedItem>> {
+        let mut plan = Vec::new();
+        self.limited.borrow_mut().clear();
+        self.rule_failures.borrow_mut().clear();
+
+        let resolved = self.resolve_sources()?;
+        for (i, source) in &resolved {
+            let source_dir_path = &self.sources[*i];
+            self.set_nested_sources(source, &resolved);
+            self.emit(BackupEvent::ScanStarted {
+                source: source_dir_path.clone(),
+            });
+
+            let source_files_list: Vec<PathBuf> = walk(source_dir_path, self.source_config(*i))
+                .map_err(|source| Error::Walk {
+                    path: source_dir_path.clone(),
+                    source,
+                })?
+                .into_iter()
+                .filter(|path| self.within_limits(path))
+                .collect();
+
+            let items = source_files_list.len();
+
+            for source_file_path in source_files_list {
+                plan.push(self.plan_item(source_file_path));
+            }
+
+            self.emit(BackupEvent::ScanFinished {
+                source: source_dir_path.clone(),
+                items,
+            });
+        }
+
+        Ok(plan)
+    }
+
+    /// Works out which of the items at the changed `paths` have to be copied, see
+    /// [`Backup::run_paths`].
+    fn plan_paths(&self, paths: &[PathBuf]) -> error::Result<Vec<PlannedItem>> {
+        let mut items = BTreeSet::new();
+        self.limited.borrow_mut().clear();
+        self.rule_failures.borrow_mut().clear();
+
+        let resolved = self.resolve_sources()?;
+        for (i, source) in &resolved {
+            let source_dir_path = &self.sources[*i];
+            self.set_nested_sources(source, &resolved);
+            let mut scan = Scan::new(self.source_config(*i), source);
+
+            for path in paths {
+                // The changed item itself is not resolved, as symbolic links may not be followed
+                let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+                    continue;
+                };
+                let Ok(parent) = fs::canonicalize(parent) else {
+                    continue;
+                };
+
+                let found = scan
+                    .items(&parent.join(name))
+                    .map_err(|source| Error::Walk {
+                        path: source_dir_path.clone(),
+                        source,
+                    })?;
+                items.extend(found);
+            }
+        }
+
+        Ok(items
+            .into_iter()
+            .filter(|path| self.within_limits(path))
+            .map(|source_file_path| self.plan_item(source_file_path))
+            .collect())
+    }
+
+    /// `false` if the item at `path` is a file over the size or age limit, which is then
+    /// recorded in the report. The limits apply to all the items the rules include, also to
+    /// those included before all other rules are checked.
+    fn within_limits(&self, path: &Path) -> bool {
+        let Some(file) = self.over_limit(path) else {
+            return true;
+        };
+        self.emit(BackupEvent::Excluded {
+            path: path.to_path_buf(),
+            rule: "limits",
+        });
+        self.limited.borrow_mut().push(file);
+        false
+    }
+
+    /// The file at `path` with the limit it is over, `None` if it is within the limits or not
+    /// a file.
+    fn over_limit(&self, path: &Path) -> Option<LimitedFile> {
+        if self.max_file_size.is_none() && self.max_file_age.is_none() {
+            return None;
+        }
+        let metadata = fs::symlink_metadata(path).ok().filter(|m| m.is_file())?;
+        let size = metadata.len();
+        let modified = metadata.modified().ok();
+        let age = modified.and_then(|modified| modified.elapsed().ok());
+
+        let limit = match (self.max_file_size, self.max_file_age) {
+            (Some(max_size), _) if size > max_size => Limit::Size(max_size),
+            (_, Some(max_age)) if age.is_some_and(|age| age > max_age) => Limit::Age(max_age),
+            _ => return None,
+        };
+        Some(LimitedFile {
+            path: path.to_path_buf(),
+            size,
+            modified,
+            limit,
+        })
+    }
+
+    /// Works out whether an item found in a source has to be copied.
+    fn plan_item(&self, source_file_path: PathBuf) -> PlannedItem {
+        self.emit(BackupEvent::Included {
+            path: source_file_path.clone(),
+        });
+
+        let backup_file_path = create_backup_file_path(&source_file_path, &self.destination);
+        let is_dir = source_file_path.is_dir();
+        let size = if is_dir {
+            0
+        } else {
+            fs::metadata(&source_file_path).map_or(0, |m| m.len())
+        };
+
+        let needs_copy = is_newer(&source_file_path, &backup_file_path);
+        let replaced_size = if needs_copy && !is_dir {
+            fs::metadata(&backup_file_path).map_or(0, |m| m.len())
+        } else {
+            0
+        };
+
+        PlannedItem {
+            needs_copy,
+            replaced_size,
+            checkpoint_index: None,
+            source: source_file_path,
+            destination: backup_file_path,
+            is_dir,
+            size,
+        }
+    }
+
+    /// Backs up a single file or (empty) directory found by the walk.
+    fn backup_item(&self, item: PlannedItem) -> FileReport {
+        let PlannedItem {
+            source: source_file_path,
+            destination: backup_file_path,
+            is_dir,
+            size,
+            needs_copy,
+            ..
+        } = item;
+
+        let outcome = if !needs_copy {
+            self.emit(BackupEvent::Unchanged {
+                source: source_file_path.clone(),
+            });
+            FileOutcome::Unchanged
+        } else {
+            if !is_dir {
+                self.emit(BackupEvent::CopyStarted {
+                    source: source_file_path.clone(),
+                    destination: backup_file_path.clone(),
+                    size,
+                });
+            }
+
+            let copied = copy_file(
+                &source_file_path,
+                &backup_file_path,
+                &self.cancel,
+                |bytes| {
+                    self.emit(BackupEvent::CopyProgress {
+                        source: source_file_path.clone(),
+                        bytes_copied: bytes,
+                        size,
+                    })
+                },
+            );
+
+            match copied {
+                Ok(_) if is_dir => {
+                    self.emit(BackupEvent::DirectoryCreated {
+                        source: source_file_path.clone(),
+                    });
+                    FileOutcome::DirectoryCreated
+                }
+                Ok(bytes) => {
+                    self.emit(BackupEvent::CopyFinished {
+                        source: source_file_path.clone(),
+                        bytes,
+                    });
+                    FileOutcome::Copied { bytes }
+                }
+                Err(source) => {
+                    let err = FileError {
+                        path: source_file_path.clone(),
+                        source,
+                    };
+                    // An interrupted copy is not an error, the file is left for the next backup
+                    if !self.is_interrupted(&err) {
+                        self.emit(BackupEvent::Error {
+                            path: source_file_path.clone(),
+                            kind: err.kind(),
+                            message: err.to_string(),
+                        });
+                    }
+                    FileOutcome::Failed(err)
+                }
+            }
+        };
+
+        FileReport {
+            source: source_file_path,
+            destination: backup_file_path,
+            outcome,
+        }
+    }
+
+    /// Records an item that was not copied because the destination is full.
+    fn not_copied(&self, item: PlannedItem) -> FileReport {
+        let err = FileError {
+            path: item.source.clone(),
+            source: io::Error::new(
+                io::ErrorKind::StorageFull,
+                "not copied, the backup directory is full",
+            ),
+        };
+        self.emit(BackupEvent::Error {
+            path: item.source.clone(),
+            kind: err.kind(),
+            message: err.to_string(),
+        });
+
+        FileReport {
+            source: item.source,
+            destination: item.destination,
+            outcome: FileOutcome::Failed(err),
+        }
+    }
+
+    /// `true` if `err` is the result of cancelling the backup.
+    fn is_interrupted(&self, err: &FileError) -> bool {
+        err.kind() == io::ErrorKind::Interrupted && self.cancel.is_cancelled()
+    }
+
+    fn emit(&self, event: BackupEvent) {
+        if let Some(observer) = &self.observer {
+            observer.borrow_mut().on_event(&event);
+        }
+    }
+}
+
+/// An item found by the walk, with what has to be done to back it up.
+struct PlannedItem {
+    source: PathBuf,
+    destination: PathBuf,
+    is_dir: bool,
+    /// The size of the file in bytes, 0 for directories.
+    size: u64,
+    /// `false` if the backup is already up to date.
+    needs_copy: bool,
+    /// The size of the previous backup of the file that is replaced by the copy.
+    replaced_size: u64,
+    /// The position of the item in the checkpoint, if it has to be copied.
+    checkpoint_index: Option<usize>,
+}
+
+/// Builder for a [`Backup`].
+///
+/// ```no_run
+/// let report = rackup::Backup::builder()
+///     .source("/home/bob/Documents")
+///     .destination("/media/backup")
+///     .run()?;
+///
+/// println!("{} files copied", report.copied());
+/// # Ok::<(), rackup::Error>(())
+/// ```
+#[derive(Default)]
+pub struct BackupBuilder {
+    /// The sources, with their own rules if they have any.
+    sources: Vec<(PathBuf, Option<Vec<WalkerRule>>)>,
+    destination: Option<PathBuf>,
+    rules: Option<Vec<WalkerRule>>,
+    follow_symlinks: bool,
+    drop_empty_dirs: bool,
+    observer: Option<SharedObserver>,
+    exclude_destination: bool,
+    destination_check: DestinationCheck,
+    reserve: u64,
+    name: Option<String>,
+    lock_wait: Duration,
+    cancel: CancelToken,
+    resume: bool,
+    max_file_size: Option<u64>,
+    max_file_age: Option<Duration>,
+}
+
+impl BackupBuilder {
+    /// Adds a directory to be backed up.
+    ///
+    /// Sources may overlap: a source inside another one is left out of the walk of the
+    /// outer source and walked on its own, with its own rules, and a source given twice is
+    /// walked once.
+    pub fn source(mut self, source: impl Into<PathBuf>) -> Self {
+        self.sources.push((source.into(), None));
+        self
+    }
+
+    /// Adds a directory to be backed up with its own rules, instead of the rules of the
+    /// backup added with [`BackupBuilder::rule`].
+    pub fn source_with_rules(
+        mut self,
+        source: impl Into<PathBuf>,
+        rules: impl IntoIterator<Item = WalkerRule>,
+    ) -> Self {
+        self.sources
+            .push((source.into(), Some(rules.into_iter().collect())));
+        self
+    }
+
+    /// Sets the backup directory or drive.
+    pub fn destination(mut self, destination: impl Into<PathBuf>) -> Self {
+        self.destination = Some(destination.into());
+        self
+    }
+
+    /// Adds a rule deciding which files are backed up, in the sources without rules of their
+    /// own.
+    ///
+    /// If no rule is added the [default rules](crate::rules::default_rules) are used.
+    pub fn rule(mut self, rule: WalkerRule) -> Self {
+        self.rules.get_or_insert_with(Vec::new).push(rule);
+        self
+    }
+
+    /// Adds several rules, see [`BackupBuilder::rule`].
+    pub fn rules(mut self, rules: impl IntoIterator<Item = WalkerRule>) -> Self {
+        self.rules.get_or_insert_with(Vec::new).extend(rules);
+        self
+    }
+
+    /// Whether symbolic links are followed. Defaults to `false`.
+    pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Whether empty directories are left out of the backup. Defaults to `false`.
+    pub fn drop_empty_dirs(mut self, drop_empty_dirs: bool) -> Self {
+        self.drop_empty_dirs = drop_empty_dirs;
+        self
+    }
+
+    /// Whether a destination inside a source directory is left out of the backup. Otherwise
+    /// such a backup fails with [`Error::Overlap`]. Defaults to `false`.
+    pub fn exclude_destination(mut self, exclude_destination: bool) -> Self {
+        self.exclude_destination = exclude_destination;
+        self
+    }
+
+    /// Sets what is checked about the destination before backing up, e.g. that it has been
+    /// initialized with a [marker](crate::marker::DestinationMarker). Nothing is checked by
+    /// default.
+    pub fn verify_destination(mut self, check: DestinationCheck) -> Self {
+        self.destination_check = check;
+        self
+    }
+
+    /// Sets the space in bytes that has to stay free on the destination. The backup does not
+    /// start if the files to be copied would not fit. Defaults to 0.
+    pub fn reserve(mut self, reserve: u64) -> Self {
+        self.reserve = reserve;
+        self
+    }
+
+    /// Leaves out the files larger than `size` bytes. They are listed in
+    /// [`BackupReport::limited`] so that they are not missed. By default files of any size
+    /// are backed up.
+    pub fn max_file_size(mut self, size: u64) -> Self {
+        self.max_file_size = Some(size);
+        self
+    }
+
+    /// Leaves out the files that have not been modified for longer than `age`. They are
+    /// listed in [`BackupReport::limited`] so that they are not missed. By default files of
+    /// any age are backed up.
+    pub fn max_file_age(mut self, age: Duration) -> Self {
+        self.max_file_age = Some(age);
+        self
+    }
+
+    /// Sets a name for the backup, e.g. its profile. It is shown to other backups that find
+    /// the destination locked by this one.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Sets how long to wait for another backup to the same destination to finish. If it
+    /// is still running after that the backup fails with [`Error::Locked`]. Defaults to not
+    /// waiting at all, [`Duration::MAX`] waits as long as it takes.
+    pub fn lock_wait(mut self, wait: Duration) -> Self {
+        self.lock_wait = wait;
+        self
+    }
+
+    /// Sets the token to cancel the backup with, e.g. when the user presses Ctrl-C.
+    pub fn cancel_token(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Whether to resume an interrupted backup of the same sources to the destination. Only
+    /// the files the interrupted backup has not copied yet are copied then, without scanning
+    /// the sources again. If there is nothing to resume the backup runs as usual. Defaults to
+    /// `false`.
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Sets the observer that is sent the [`BackupEvent`]s while the backup runs.
+    pub fn observer(mut self, observer: impl BackupObserver + 'static) -> Self {
+        self.observer = Some(Rc::new(RefCell::new(observer)));
+        self
+    }
+
+    /// Creates the backup, checking that at least one source and a destination have been given.
+    pub fn build(self) -> error::Result<Backup> {
+        if self.sources.is_empty() {
+            return Err(Error::Config(
+                "No source directory has been given".to_string(),
+            ));
+        }
+        let destination = self
+            .destination
+            .ok_or_else(|| Error::Config("No backup directory has been given".to_string()))?;
+
+        let excluded_destination = Rc::new(RefCell::new(None));
+        let nested_sources = Rc::new(RefCell::new(Vec::new()));
+        let rule_failures = Rc::new(RefCell::new(Vec::new()));
+        let walker_config = |user_rules: Vec<WalkerRule>| {
+            let mut rules = vec![
+                cancel_rule(self.cancel.clone()),
+                destination_rule(excluded_destination.clone()),
+                nested_source_rule(nested_sources.clone()),
+            ];
+            rules.extend(user_rules);
+            if let Some(observer) = &self.observer {
+                rules = rules
+                    .into_iter()
+                    .map(|rule| observe_rule(rule, observer.clone()))
+                    .collect();
+            }
+            // Items the rules fail on are reported as failed, not as excluded
+            let rules = rules
+                .into_iter()
+                .map(|rule| item_failure_rule(rule, rule_failures.clone()))
+                .collect();
+            WalkerConfig {
+                rules,
+                follow_symlinks: self.follow_symlinks,
+                drop_empty_dirs: self.drop_empty_dirs,
+            }
+        };
+
+        let config = walker_config(self.rules.unwrap_or_else(default_rules));
+        let (sources, source_configs) = self
+            .sources
+            .into_iter()
+            .map(|(source, rules)| (source, rules.map(&walker_config)))
+            .unzip();
+
+        Ok(Backup {
+            sources,
+            destination,
+            config,
+            source_configs,
+            observer: self.observer,
+            exclude_destination: self.exclude_destination,
+            destination_check: self.destination_check,
+            reserve: self.reserve,
+            name: self.name,
+            lock_wait: self.lock_wait,
+            cancel: self.cancel,
+            resume: self.resume,
+            excluded_destination,
+            max_file_size: self.max_file_size,
+            max_file_age: self.max_file_age,
+            limited: RefCell::new(Vec::new()),
+            nested_sources,
+            rule_failures,
+        })
+    }
+
+    /// Builds and runs the backup.
+    pub fn run(self) -> error::Result<BackupReport> {
+        self.build()?.run()
+    }
+}
+
+/// Rule aborting the walk once the backup has been cancelled.
+fn cancel_rule(cancel: CancelToken) -> WalkerRule {
+    WalkerRule {
+        name: "cancel",
+        description: Some("Stop when the backup is cancelled".to_string()),
+        only_for: None,
+        matches: Box::new(move |_, _, _| cancel.is_cancelled()),
+        action: Box::new(|_, _, _| {
+            Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "the backup has been interrupted",
+            ))
+        }),
+    }
+}
+
+/// Rule excluding the destination from the walk when it is inside a source, see
+/// [`BackupBuilder::exclude_destination`].
+fn destination_rule(destination: Rc<RefCell<Option<PathBuf>>>) -> WalkerRule {
+    WalkerRule {
+        name: "destination",
+        description: Some("Do not backup the backup directory".to_string()),
+        only_for: None,
+        matches: Box::new(move |path, _, _| match &*destination.borrow() {
+            // Symbolic links are only walked if they are followed
+            Some(destination) => {
+                path.starts_with(destination)
+                    || (path.is_symlink()
+                        && fs::canonicalize(path).is_ok_and(|path| path.starts_with(destination)))
+            }
+            None => false,
+        }),
+        action: Box::new(|_, _, _| Ok(WalkerRuleResult::ExcludeItem)),
+    }
+}
+
+/// Wraps `rule` so that a [`RuleItemError`] leaves the item out and records it in `failures`,
+/// instead of stopping the walk.
+fn item_failure_rule(rule: WalkerRule, failures: Rc<RefCell<Vec<RuleFailure>>>) -> WalkerRule {
+    let WalkerRule {
+        name,
+        description,
+        only_for,
+        matches,
+        action,
+    } = rule;
+
+    WalkerRule {
+        name,
+        description,
+        only_for,
+        matches,
+        action: Box::new(
+            move |path, config, source| match action(path, config, source) {
+                Err(err) if err.get_ref().is_some_and(|err| err.is::<RuleItemError>()) => {
+                    failures.borrow_mut().push(RuleFailure {
+                        path: path.to_path_buf(),
+                        rule: name,
+                        error: err,
+                    });
+                    Ok(WalkerRuleResult::ExcludeItem)
+                }
+                result => result,
+            },
+        ),
+    }
+}
+
+/// Rule leaving out the sources inside the source being walked, which are walked on their own.
+fn nested_source_rule(nested_sources: Rc<RefCell<Vec<PathBuf>>>) -> WalkerRule {
+    WalkerRule {
+        name: "sources",
+        description: Some("Do not walk a source inside another source twice".to_string()),
+        only_for: Some(WalkerItemType::Directory),
+        matches: Box::new(move |path, _, _| nested_sources.borrow().iter().any(|s| s == path)),
+        action: Box::new(|_, _, _| Ok(WalkerRuleResult::ExcludeItem)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, Read, Write};
+
+    #[test]
+    fn test_perform_new_backup() -> Result<(), std::io::Error> {
+        let test_dir = setup_file_structure()?;
+
+        // Test the backup
+        let source_dir_path = test_dir.path().join("TestUser");
+        let backup_dir_path = test_dir.path().join("Backup");
+        perform_backup(&source_dir_path, &backup_dir_path);
+
+        // Check if the files and directories have been created.
+        let full_backup_path = get_full_backup_path(&test_dir, &backup_dir_path);
+
+        assert!(test_dir.path().join("Backup").exists());
+        assert!(full_backup_path.join("TestUser/DocumentsA").exists());
+        assert!(full_backup_path.join("TestUser/DocumentsB").exists());
+        assert!(full_backup_path.join("TestUser/DocumentsC").exists());
+
+        assert!(full_backup_path
+            .join("TestUser/DocumentsA/fileAA.txt")
+            .exists());
+        assert!(full_backup_path
+            .join("TestUser/DocumentsA/fileAB.txt")
+            .exists());
+
+        assert!(full_backup_path
+            .join("TestUser/DocumentsB/fileBA.pdf")
+            .exists());
+        assert!(full_backup_path
+            .join("TestUser/DocumentsB/fileBB.doc")
+            .exists());
+        assert!(full_backup_path
+            .join("TestUser/DocumentsB/fileBC.txt")
+            .exists());
+
+        assert!(full_backup_path.join("TestUser/DocumentsC").exists());
+
+        // Sample if the files contain the data
+        let p = full_backup_path.join("TestUser/DocumentsA/fileAA.txt");
+        let mut contents = String::new();
+        let mut file = fs::File::open(p)?;
+        file.read_to_string(&mut contents)?;
+        assert_eq!(contents, "fileAA.txt");
+
+        let p = full_backup_path.join("TestUser/DocumentsB/fileBB.doc");
+        let mut file = fs::File::open(p)?;
+        contents.clear();
+        file.read_to_string(&mut contents)?;
+        assert_eq!(contents, "fileBB.doc");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_perform_overwrite_backup() -> Result<(), std::io::Error> {
+        let test_dir = setup_file_structure()?;
+
+        // Test the backup
+        let source_dir_path = test_dir.path().join("TestUser");
+        let backup_dir_path = test_dir.path().join("Backup");
+        perform_backup(&source_dir_path, &backup_dir_path);
+
+        let full_backup_path = get_full_backup_path(&test_dir, &backup_dir_path);
+
+        // Change two of the files in the source
+        let p = full_backup_path.join("TestUser/DocumentsA/fileAA.txt");
+        let mut file = OpenOptions::new().append(true).open(p).unwrap();
+        //file.write_all("fileAA.txt".as_bytes()).unwrap();
+        file.write_all(" has been updated".as_bytes()).unwrap();
+
+        let p = full_backup_path.join("TestUser/DocumentsB/fileBB.doc");
+        let mut file = OpenOptions::new().append(true).open(p).unwrap();
+        //file.write_all("fileBB.doc".as_bytes()).unwrap();
+        file.write_all(" has been updated".as_bytes()).unwrap();
+
+        // Now perform the backup again
+        perform_backup(&source_dir_path, &backup_dir_path);
+
+        // Now check that the changed file have been overwritten
+        let p = full_backup_path.join("TestUser/DocumentsA/fileAA.txt");
+        let mut contents = String::new();
+        let mut file = fs::File::open(p)?;
+        file.read_to_string(&mut contents)?;
+        assert_eq!(contents, "fileAA.txt has been updated");
+
+        let p = full_backup_path.join("TestUser/DocumentsB/fileBB.doc");
+        let mut file = fs::File::open(p)?;
+        contents.clear();
+        file.read_to_string(&mut contents)?;
+        assert_eq!(contents, "fileBB.doc has been updated");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_counts() -> Result<(), std::io::Error> {
+        let test_dir = setup_file_structure()?;
+
+        let source_dir_path = test_dir.path().join("TestUser");
+        let backup_dir_path = test_dir.path().join("Backup");
+        let report = perform_backup(&source_dir_path, &backup_dir_path);
+
+        // Five files and the empty DocumentsC directory
+        assert_eq!(report.copied(), 5);
+        assert_eq!(report.directories_created(), 1);
+        assert_eq!(report.bytes_copied(), 50);
+        assert!(report.is_success());
+
+        // Nothing has changed since the last backup
+        let report = perform_backup(&source_dir_path, &backup_dir_path);
+        assert_eq!(report.copied(), 0);
+        assert_eq!(report.unchanged(), 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_rules_follow_markers() -> Result<(), std::io::Error> {
+        let test_dir = setup_file_structure()?;
+        let source_dir_path = test_dir.path().join("TestUser");
+        File::create(source_dir_path.join("DocumentsB/.nobackup"))?;
+
+        let backup_dir_path = test_dir.path().join("Backup");
+        perform_backup(&source_dir_path, &backup_dir_path);
+
+        let full_backup_path = get_full_backup_path(&test_dir, &backup_dir_path);
+        assert!(full_backup_path
+            .join("TestUser/DocumentsA/fileAA.txt")
+            .exists());
+        assert!(!full_backup_path.join("TestUser/DocumentsB").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_limited_files() -> Result<(), std::io::Error> {
+        let test_dir = setup_file_structure()?;
+        let source_dir_path = test_dir.path().join("TestUser");
+        let backup_dir_path = test_dir.path().join("Backup");
+
+        let mut f = File::create(source_dir_path.join("DocumentsA/large.bin"))?;
+        f.write_all(&[0; 100])?;
+        let old = File::options()
+            .write(true)
+            .open(source_dir_path.join("DocumentsB/fileBB.doc"))?;
+        old.set_modified(std::time::SystemTime::now() - Duration::from_secs(3 * 365 * 86400))?;
+
+        // Including the file before all other rules does not bypass the limits
+        let keep_bin = WalkerRule {
+            name: "keep",
+            description: None,
+            only_for: Some(WalkerItemType::File),
+            matches: Box::new(|path, _, _| path.extension().is_some_and(|ext| ext == "bin")),
+            action: Box::new(|_, _, _| Ok(WalkerRuleResult::IncludeItemAbsolute)),
+        };
+        let backup = Backup::builder()
+            .source(&source_dir_path)
+            .destination(&backup_dir_path)
+            .rule(keep_bin)
+            .max_file_size(50)
+            .max_file_age(Duration::from_secs(365 * 86400))
+            .build()
+            .expect("Failed to build the backup");
+        let report = backup.run().expect("Failed to run the backup");
+
+        assert_eq!(report.copied(), 4);
+        assert_eq!(report.bytes_limited(), 110);
+        assert_eq!(report.limited.len(), 2);
+        let large = report
+            .limited
+            .iter()
+            .find(|file| file.path.ends_with("large.bin"))
+            .unwrap();
+        assert_eq!(large.size, 100);
+        assert_eq!(large.limit, Limit::Size(50));
+        assert_eq!(large.to_string(), "100 B, larger than 50 B");
+        let old = report
+            .limited
+            .iter()
+            .find(|file| file.path.ends_with("fileBB.doc"))
+            .unwrap();
+        assert_eq!(old.limit, Limit::Age(Duration::from_secs(365 * 86400)));
+        let full_backup_path = get_full_backup_path(&test_dir, &backup_dir_path);
+        assert!(full_backup_path
+            .join("TestUser/DocumentsA/fileAA.txt")
+            .exists());
+        assert!(!full_backup_path
+            .join("TestUser/DocumentsA/large.bin")
+            .exists());
+        assert!(!full_backup_path
+            .join("TestUser/DocumentsB/fileBB.doc")
+            .exists());
+
+        let explanation = backup
+            .explain(&source_dir_path.join("DocumentsA/large.bin"))
+            .expect("Failed to explain");
+        assert!(!explanation.included);
+        assert_eq!(
+            explanation
+                .items
+                .last()
+                .and_then(|item| item.note.as_deref()),
+            Some("Left out by the limits: 100 B, larger than 50 B")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlapping_sources() -> Result<(), std::io::Error> {
+        let test_dir = setup_file_structure()?;
+        let source_dir_path = test_dir.path().join("TestUser");
+        let backup_dir_path = test_dir.path().join("Backup");
+
+        let exclude_pdf = WalkerRule {
+            name: "nopdf",
+            description: None,
+            only_for: Some(WalkerItemType::File),
+            matches: Box::new(|path, _, _| path.extension().is_some_and(|ext| ext == "pdf")),
+            action: Box::new(|_, _, _| Ok(WalkerRuleResult::ExcludeItem)),
+        };
+        let report = Backup::builder()
+            .source(&source_dir_path)
+            .source_with_rules(source_dir_path.join("DocumentsB"), [exclude_pdf])
+            .source(source_dir_path.join("DocumentsB/../DocumentsA"))
+            .source(&source_dir_path)
+            .destination(&backup_dir_path)
+            .run()
+            .expect("Failed to run the backup");
+
+        // Every item is found once, with the rules of the innermost source
+        let mut copied: Vec<&Path> = report.files.iter().map(|f| f.source.as_path()).collect();
+        copied.sort();
+        copied.dedup();
+        assert_eq!(copied.len(), report.files.len());
+        assert_eq!(report.copied(), 4);
+        assert_eq!(report.directories_created(), 1);
+        let full_backup_path = get_full_backup_path(&test_dir, &backup_dir_path);
+        assert!(full_backup_path
+            .join("TestUser/DocumentsB/fileBB.doc")
+            .exists());
+        assert!(!full_backup_path
+            .join("TestUser/DocumentsB/fileBA.pdf")
+            .exists());
+
+        // The manifest lists each resolved source once, with the items backed up from it
+        let manifest = Manifest::load(&backup_dir_path)?.expect("No manifest written");
+        let resolved = fs::canonicalize(&source_dir_path)?;
+        let listed: Vec<(&Path, Vec<&Path>)> = manifest
+            .sources
+            .iter()
+            .map(|source| {
+                let items = source.items.iter().map(PathBuf::as_path).collect();
+                (source.resolved.as_path(), items)
+            })
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                (resolved.as_path(), vec![Path::new("DocumentsC")]),
+                (
+                    resolved.join("DocumentsB").as_path(),
+                    vec![Path::new("fileBB.doc"), Path::new("fileBC.txt")]
+                ),
+                (
+                    resolved.join("DocumentsA").as_path(),
+                    vec![Path::new("fileAA.txt"), Path::new("fileAB.txt")]
+                ),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rule_item_failure() -> Result<(), std::io::Error> {
+        let test_dir = setup_file_structure()?;
+        let source_dir_path = test_dir.path().join("TestUser");
+
+        let failing = WalkerRule {
+            name: "failing",
+            description: None,
+            only_for: Some(WalkerItemType::File),
+            matches: Box::new(|path, _, _| path.ends_with("fileAB.txt")),
+            action: Box::new(|_, _, _| Err(RuleItemError("cannot decide".to_string()).into())),
+        };
+        let backup = Backup::builder()
+            .source(&source_dir_path)
+            .destination(test_dir.path().join("Backup"))
+            .rule(failing)
+            .build()
+            .expect("Failed to build the backup");
+
+        // Only the item fails, the others are backed up
+        let report = backup.run().expect("Failed to run the backup");
+        assert_eq!(report.copied(), 4);
+        assert_eq!(report.failed(), 1);
+        let (file, err) = report.errors().next().unwrap();
+        assert!(file.source.ends_with("DocumentsA/fileAB.txt"));
+        assert_eq!(err.source.to_string(), "cannot decide");
+
+        let explanation = backup
+            .explain(&source_dir_path.join("DocumentsA/fileAB.txt"))
+            .expect("Failed to explain");
+        assert!(!explanation.included);
+        let evaluation = explanation.items[1]
+            .rules
+            .iter()
+            .find(|rule| rule.rule == "failing")
+            .unwrap();
+        assert_eq!(
+            evaluation.outcome,
+            RuleOutcome::Failed("cannot decide".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_builder_requires_source_and_destination() {
+        assert!(matches!(
+            Backup::builder().destination("Backup").build(),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            Backup::builder().source("TestUser").build(),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_missing_source_is_walk_error() -> Result<(), std::io::Error> {
+        let test_dir = tempfile::tempdir()?;
+
+        let result = Backup::builder()
+            .source(test_dir.path().join("Missing"))
+            .destination(test_dir.path().join("Backup"))
+            .run();
+        assert!(matches!(result, Err(Error::Walk { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unusable_destination_is_destination_error() -> Result<(), std::io::Error> {
+        let test_dir = setup_file_structure()?;
+
+        // The destination is a file, so it cannot be used as the backup directory
+        File::create(test_dir.path().join("Backup"))?;
+        let result = Backup::builder()
+            .source(test_dir.path().join("TestUser"))
+            .destination(test_dir.path().join("Backup"))
+            .run();
+        assert!(matches!(result, Err(Error::Destination { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_destination_inside_source_is_refused() -> Result<(), std::io::Error> {
+        let test_dir = setup_file_structure()?;
+        let source_dir_path = test_dir.path().join("TestUser");
+
+        let result = Backup::builder()
+            .source(&source_dir_path)
+            .destination(source_dir_path.join("Backup"))
+            .run();
+        assert!(matches!(result, Err(Error::Overlap { .. })));
+        assert!(!source_dir_path.join("Backup").exists());
+
+        // Also when the destination is reached through a symbolic link
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&source_dir_path, test_dir.path().join("Link"))?;
+            let result = Backup::builder()
+                .source(&source_dir_path)
+                .destination(test_dir.path().join("Link/Backup"))
+                .run();
+            assert!(matches!(result, Err(Error::Overlap { .. })));
+        }
+
+        // Excluding the destination cannot help if it is the source itself
+        let result = Backup::builder()
+            .source(&source_dir_path)
+            .destination(&source_dir_path)
+            .exclude_destination(true)
+            .run();
+        assert!(matches!(result, Err(Error::Overlap { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_destination_inside_source_is_excluded() -> Result<(), std::io::Error> {
+        let test_dir = setup_file_structure()?;
+        let source_dir_path = test_dir.path().join("TestUser");
+        let backup_dir_path = source_dir_path.join("Backup");
+
+        let backup = |excluded: Rc<RefCell<Vec<PathBuf>>>| {
+            Backup::builder()
+                .source(&source_dir_path)
+                .destination(&backup_dir_path)
+                .exclude_destination(true)
+                .observer(move |event: &BackupEvent| {
+                    if let BackupEvent::Excluded { path, .. } = event {
+                        excluded.borrow_mut().push(path.clone());
+                    }
+                })
+                .run()
+                .expect("Failed to run the backup")
+        };
+
+        let excluded = Rc::new(RefCell::new(Vec::new()));
+        let first = backup(excluded.clone());
+        assert_eq!(first.copied(), 5);
+
+        // The second run does not copy the first backup into the new one
+        let second = backup(excluded.clone());
+        assert_eq!(second.copied(), 0);
+        assert_eq!(second.unchanged(), 5);
+
+        let backup_dir_path = fs::canonicalize(&backup_dir_path)?;
+        assert_eq!(
+            *excluded.borrow(),
+            vec![backup_dir_path.clone(), backup_dir_path]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_uninitialized_destination_is_refused() -> Result<(), std::io::Error> {
+        let test_dir = setup_file_structure()?;
+        let backup_dir_path = test_dir.path().join("Backup");
+        let backup = || {
+            Backup::builder()
+                .source(test_dir.path().join("TestUser"))
+                .destination(&backup_dir_path)
+                .verify_destination(DestinationCheck {
+                    require_marker: true,
+                    ..DestinationCheck::default()
+                })
+                .run()
+        };
+
+        // Nothing is written to a destination that is not there
+        assert!(matches!(backup(), Err(Error::UnverifiedDestination { .. })));
+        assert!(!backup_dir_path.exists());
+
+        crate::marker::DestinationMarker::new("Backup", None).write(&backup_dir_path, false)?;
+        assert_eq!(backup().expect("Failed to run the backup").copied(), 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insufficient_space() -> Result<(), std::io::Error> {
+        let test_dir = setup_file_structure()?;
+        let backup_dir_path = test_dir.path().join("Backup");
+
+        let result = Backup::builder()
+            .source(test_dir.path().join("TestUser"))
+            .destination(&backup_dir_path)
+            .reserve(u64::MAX / 2)
+            .run();
+        match result {
+            Err(Error::InsufficientSpace { required, .. }) => assert_eq!(required, 50),
+            _ => panic!("Expected an insufficient space error"),
+        }
+
+        // Nothing has been copied
+        let full_backup_path = get_full_backup_path(&test_dir, &backup_dir_path);
+        assert!(!full_backup_path.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_locked_destination() -> Result<(), std::io::Error> {
+        let test_dir = setup_file_structure()?;
+        let backup_dir_path = test_dir.path().join("Backup");
+
+        let lock = RunLock::acquire(
+            &backup_dir_path.join(".rackup/lock"),
+            Some("documents"),
+            Duration::ZERO,
+            |_| {},
+        )
+        .expect("Failed to take the lock");
+
+        let result = Backup::builder()
+            .source(test_dir.path().join("TestUser"))
+            .destination(&backup_dir_path)
+            .run();
+        assert!(matches!(result, Err(Error::Locked { .. })));
+
+        // The lock is released by the backup when it has finished
+        drop(lock);
+        Backup::builder()
+            .source(test_dir.path().join("TestUser"))
+            .destination(&backup_dir_path)
+            .run()
+            .expect("Failed to run the backup");
+        assert!(!backup_dir_path.join(".rackup/lock").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_observer_events() -> Result<(), std::io::Error> {
+        let test_dir = setup_file_structure()?;
+        File::create(test_dir.path().join("TestUser/DocumentsA/program.exe"))?;
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let observed = events.clone();
+
+        let source_dir_path = test_dir.path().join("TestUser");
+        Backup::builder()
+            .source(&source_dir_path)
+            .destination(test_dir.path().join("Backup"))
+            .observer(move |event: &BackupEvent| observed.borrow_mut().push(event.clone()))
+            .run()
+            .expect("Failed to run the backup");
+
+        let events = events.borrow();
+        assert_eq!(
+            events.first(),
+            Some(&BackupEvent::ScanStarted {
+                source: source_dir_path.clone()
+            })
+        );
+        assert!(events.iter().any(|e| matches!(e,
+            BackupEvent::Excluded { path, rule: "noexe" } if path.ends_with("program.exe"))));
+        assert_eq!(
+            events
+                .iter()
+                .filter(|e| matches!(e, BackupEvent::CopyStarted { .. }))
+                .count(),
+            5
+        );
+        assert!(events.iter().any(|e| matches!(e,
+            BackupEvent::CopyFinished { source, bytes: 10 } if source.ends_with("fileAA.txt"))));
+        assert_eq!(
+            events.last(),
+            Some(&BackupEvent::RunFinished {
+                copied: 5,
+                directories_created: 1,
+                unchanged: 0,
+                failed: 0,
+                bytes_copied: 50,
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interrupt_and_resume() -> Result<(), std::io::Error> {
+        let test_dir = setup_file_structure()?;
+        let source_dir_path = test_dir.path().join("TestUser");
+        let backup_dir_path = test_dir.path().join("Backup");
+
+        // Cancelled while scanning
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let report = Backup::builder()
+            .source(&source_dir_path)
+            .destination(&backup_dir_path)
+            .cancel_token(cancel)
+            .run()
+            .expect("Failed to run the backup");
+        assert!(report.interrupted);
+        assert!(report.files.is_empty());
+
+        // Cancelled once the first file has been copied
+        let cancel = CancelToken::new();
+        let token = cancel.clone();
+        let remaining = Rc::new(RefCell::new(None));
+        let observed = remaining.clone();
+        let report = Backup::builder()
+            .source(&source_dir_path)
+            .destination(&backup_dir_path)
+            .cancel_token(cancel)
+            .observer(move |event: &BackupEvent| match event {
+                BackupEvent::CopyFinished { .. } => token.cancel(),
+                BackupEvent::Interrupted { remaining } => *observed.borrow_mut() = Some(*remaining),
+                _ => {}
+            })
+            .run()
+            .expect("Failed to run the backup");
+        assert!(report.interrupted);
+        assert!(!report.is_success());
+        assert_eq!(report.copied(), 1);
+        let remaining = remaining.borrow().expect("No interrupted event");
+        // The 5 files and the empty directory
+        assert_eq!(report.files.len() + remaining, 6);
+        assert!(backup_dir_path.join(".rackup/checkpoint.json").exists());
+
+        // Only the remaining items are copied when resuming
+        let resumed = Rc::new(RefCell::new(None));
+        let observed = resumed.clone();
+        let report = Backup::builder()
+            .source(&source_dir_path)
+            .destination(&backup_dir_path)
+            .resume(true)
+            .observer(move |event: &BackupEvent| {
+                if let BackupEvent::Resumed { items } = event {
+                    *observed.borrow_mut() = Some(*items);
+                }
+            })
+            .run()
+            .expect("Failed to resume the backup");
+        assert_eq!(*resumed.borrow(), Some(remaining));
+        assert!(report.is_success());
+        assert_eq!(report.files.len(), remaining);
+        assert_eq!(report.copied(), 4);
+        assert!(!backup_dir_path.join(".rackup/checkpoint.json").exists());
+
+        // Nothing is left to resume, so the sources are scanned again
+        let report = Backup::builder()
+            .source(&source_dir_path)
+            .destination(&backup_dir_path)
+            .resume(true)
+            .run()
+            .expect("Failed to run the backup");
+        assert!(report.is_success());
+        assert_eq!(report.copied(), 0);
+
+        Ok(())
+    }
+
+    // Test utilities
+
+    fn perform_backup(source_dir_path: &Path, backup_dir_path: &Path) -> BackupReport {
+        Backup::builder()
+            .source(source_dir_path)
+            .destination(backup_dir_path)
+            .run()
+            .expect("Failed to run the backup")
+    }
+
+    fn get_full_backup_path(test_dir: &tempfile::TempDir, backup_dir_path: &Path) -> PathBuf {
+        // First get the path of the temp directory.
+        let tail = test_dir.path().to_str().unwrap().to_string();
+        // Assuming that the temp dir used for test in the C: drive. For the backup path remove
+        // the C: and replace it with C
+        let tail_norm = tail.replace(':', "");
+        // On Unix the temp dir starts at the root which is not part of the backup path
+        let tail_norm = tail_norm.trim_start_matches('/');
+        // Get the full backup path, i.e.
+        // <temp test dir>/Backup/<temp test dir with C: changed to C>
+        //let full_backup_path = test_dir.path().join("Backup").join(tail_norm);
+        backup_dir_path.join(tail_norm)
+    }
+
+    // Create a temporary directory/file stucture to back up. Each file contains a with the name of the file.
+    // Structure is:
+    // TempDir
+    //   -> TestUser
+    //       -> DocumentsA
+    //          --> fileAA.txt
+    //          --> fileBA.txt
+    //       --> DocumentsB
+    //           --> fileBA.pdf
+    //           --> fileBB.doc
+    //           --> fileBC.txt
+    //       --> DocumentsC
+    //           --> (empty)
+    //
+    fn setup_file_structure() -> Result<tempfile::TempDir, io::Error> {
+        let test_dir = tempfile::tempdir()?;
+        fs::create_dir(test_dir.path().join("TestUser"))?;
+        fs::create_dir(test_dir.path().join("TestUser/DocumentsA"))?;
+        fs::create_dir(test_dir.path().join("TestUser/DocumentsB"))?;
+        fs::create_dir(test_dir.path().join("TestUser/DocumentsC"))?;
+        let mut f = File::create(test_dir.path().join("TestUser/DocumentsA/fileAA.txt"))?;
+        write!(f, "fileAA.txt")?;
+        f = File::create(test_dir.path().join("TestUser/DocumentsA/fileAB.txt"))?;
+        write!(f, "fileAB.txt")?;
+        f = File::create(test_dir.path().join("TestUser/DocumentsB/fileBA.pdf"))?;
+        write!(f, "fileBA.pdf")?;
+        f = File::create(test_dir.path().join("TestUser/DocumentsB/fileBB.doc"))?;
+        write!(f, "fileBB.doc")?;
+        f = File::create(test_dir.path().join("TestUser/DocumentsB/fileBC.txt"))?;
+        write!(f, "fileBC.txt")?;
+        assert!(test_dir
+            .path()
+            .join("TestUser/DocumentsA/fileAB.txt")
+            .exists());
+        assert!(test_dir.path().join("TestUser/DocumentsC").exists());
+        Ok(test_dir)
+    }
+}
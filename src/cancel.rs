@@ -0,0 +1,27 @@
+//! Cancelling a backup that is running.
+//!
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A token that stops a backup when cancelled, e.g. from a signal handler or another thread.
+///
+/// The file being copied when the backup is cancelled is rolled back, so that the previous
+/// backup of it is kept, and the files not copied yet are recorded in a checkpoint to resume
+/// the backup from. See [`BackupBuilder::resume`](crate::BackupBuilder::resume).
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// Asks the backups using this token to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,183 @@
+//! Checkpoints to resume an interrupted backup from.
+//!
+//! When the files to copy have been planned, they are written to a checkpoint in the
+//! `.rackup` directory of the destination, and the position of every file copied is appended
+//! to a journal next to it. A backup that is interrupted, or stopped by a full destination,
+//! can then be resumed by copying only the files not in the journal, without scanning the
+//! sources again.
+//!
+use crate::history::METADATA_DIR;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// The name of the file with the planned files.
+pub const CHECKPOINT_FILE: &str = "checkpoint.json";
+
+/// The name of the journal of the files copied.
+pub const JOURNAL_FILE: &str = "checkpoint.done";
+
+/// A file or directory that has to be copied.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct CheckpointItem {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub is_dir: bool,
+    pub size: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Plan {
+    #[serde(with = "crate::history::timestamp")]
+    created: SystemTime,
+    sources: Vec<PathBuf>,
+    items: Vec<CheckpointItem>,
+}
+
+/// The items left to copy with their position in the checkpoint.
+type Remaining = Vec<(usize, CheckpointItem)>;
+
+/// The checkpoint of the running backup.
+pub(crate) struct Checkpoint {
+    dir: PathBuf,
+    journal: fs::File,
+}
+
+impl Checkpoint {
+    /// Writes the checkpoint of a backup of `sources` to `destination` that has to copy
+    /// `items`, replacing an older checkpoint.
+    pub fn create(
+        destination: &Path,
+        sources: &[PathBuf],
+        items: Vec<CheckpointItem>,
+    ) -> io::Result<Checkpoint> {
+        let dir = destination.join(METADATA_DIR);
+        fs::create_dir_all(&dir)?;
+
+        let plan = Plan {
+            created: SystemTime::now(),
+            sources: sources.to_vec(),
+            items,
+        };
+
+        // The journal has to be emptied before the plan it refers to is replaced
+        let journal = fs::File::create(dir.join(JOURNAL_FILE))?;
+        let temp_path = dir.join(format!("{}.tmp", CHECKPOINT_FILE));
+        fs::write(&temp_path, serde_json::to_vec(&plan)?)?;
+        fs::rename(&temp_path, dir.join(CHECKPOINT_FILE))?;
+
+        Ok(Checkpoint { dir, journal })
+    }
+
+    /// Reads the checkpoint of a backup of `sources` to `destination`, returning the items
+    /// that have not been copied yet with their position in the checkpoint.
+    ///
+    /// `None` is returned if there is no checkpoint, or it is the checkpoint of other sources.
+    pub fn load(
+        destination: &Path,
+        sources: &[PathBuf],
+    ) -> io::Result<Option<(Checkpoint, Remaining)>> {
+        let dir = destination.join(METADATA_DIR);
+
+        let plan: Plan = match fs::read(dir.join(CHECKPOINT_FILE)) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        if plan.sources != sources {
+            return Ok(None);
+        }
+
+        // A line may be cut off if the backup was killed while writing it
+        let mut done = HashSet::new();
+        match fs::File::open(dir.join(JOURNAL_FILE)) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    if let Ok(index) = line?.parse::<usize>() {
+                        done.insert(index);
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        let remaining = plan
+            .items
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| !done.contains(index))
+            .collect();
+
+        let journal = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(JOURNAL_FILE))?;
+
+        Ok(Some((Checkpoint { dir, journal }, remaining)))
+    }
+
+    /// Records that the item at `index` has been copied.
+    pub fn done(&mut self, index: usize) -> io::Result<()> {
+        writeln!(self.journal, "{}", index)
+    }
+
+    /// Removes the checkpoint once all items have been copied.
+    pub fn remove(self) -> io::Result<()> {
+        drop(self.journal);
+        fs::remove_file(self.dir.join(CHECKPOINT_FILE))?;
+        fs::remove_file(self.dir.join(JOURNAL_FILE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(name: &str) -> CheckpointItem {
+        CheckpointItem {
+            source: PathBuf::from("/home/bob").join(name),
+            destination: PathBuf::from("/media/backup/home/bob").join(name),
+            is_dir: false,
+            size: 10,
+        }
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() -> io::Result<()> {
+        let test_dir = tempfile::tempdir()?;
+        let destination = test_dir.path();
+        let sources = vec![PathBuf::from("/home/bob")];
+
+        assert!(Checkpoint::load(destination, &sources)?.is_none());
+
+        let items = vec![item("a.txt"), item("b.txt"), item("c.txt")];
+        let mut checkpoint = Checkpoint::create(destination, &sources, items.clone())?;
+        checkpoint.done(1)?;
+        drop(checkpoint);
+
+        // Another backup to the same destination does not resume the checkpoint
+        let other_sources = vec![PathBuf::from("/home/alice")];
+        assert!(Checkpoint::load(destination, &other_sources)?.is_none());
+
+        let (mut checkpoint, remaining) = Checkpoint::load(destination, &sources)?.unwrap();
+        assert_eq!(
+            remaining,
+            vec![(0, items[0].clone()), (2, items[2].clone())]
+        );
+
+        checkpoint.done(0)?;
+        drop(checkpoint);
+        let (checkpoint, remaining) = Checkpoint::load(destination, &sources)?.unwrap();
+        assert_eq!(remaining, vec![(2, items[2].clone())]);
+
+        checkpoint.remove()?;
+        assert!(Checkpoint::load(destination, &sources)?.is_none());
+        assert!(!destination.join(".rackup/checkpoint.done").exists());
+
+        Ok(())
+    }
+}
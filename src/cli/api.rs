@@ -0,0 +1,590 @@
+//! The HTTP API of `rackup daemon`, for applications that start and follow backups.
+//!
+//! The API is served on a loopback address configured with `api` in the `[daemon]` table.
+//! Every request has to send the token from the token file in an
+//! `Authorization: Bearer <token>` header. All responses are JSON:
+//!
+//! * `GET /v1/profiles` lists the profiles with their schedule and state.
+//! * `POST /v1/profiles/<name>/run` starts a backup of the profile, as soon as fewer than
+//!   `max_concurrent` backups are running.
+//! * `POST /v1/profiles/<name>/cancel` cancels the running backup of the profile.
+//! * `GET /v1/events` streams the events of all backups as NDJSON, like `--output ndjson`
+//!   with the profile in every line, until the client disconnects.
+//! * `GET /v1/history?profile=<name>&limit=<runs>` returns the latest runs, newest first.
+//!
+use crate::cli::daemon::{Message, Scheduler};
+use crate::cli::output::{serialize_time, SCHEMA_VERSION};
+use rackup::config::Config;
+use rackup::history::History;
+use rackup::Error;
+use serde::Serialize;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Cursor, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+use uuid::Uuid;
+
+/// The name of the token file in the local data directory.
+pub const TOKEN_FILE: &str = "api-token";
+
+/// The number of runs returned by `GET /v1/history` without a `limit`.
+const DEFAULT_HISTORY_LIMIT: usize = 20;
+
+/// The listening API.
+pub struct Api {
+    server: Arc<Server>,
+    token: String,
+}
+
+impl Api {
+    /// Listens on `address`, accepting requests with the token in `token_file`. A token is
+    /// generated if the file does not exist yet.
+    pub fn bind(address: SocketAddr, token_file: &Path) -> rackup::Result<Api> {
+        let token = load_token(token_file).map_err(|err| {
+            Error::Config(format!(
+                "Cannot read the API token from {}: {}",
+                token_file.to_string_lossy(),
+                err
+            ))
+        })?;
+        let server = Server::http(address).map_err(|err| {
+            Error::Config(format!("Cannot serve the API on {}: {}", address, err))
+        })?;
+        Ok(Api {
+            server: Arc::new(server),
+            token,
+        })
+    }
+
+    /// The address the API is served on.
+    pub fn address(&self) -> String {
+        self.server.server_addr().to_string()
+    }
+
+    /// Sends the authorized requests to the daemon as [`Message::Request`]s, answering the
+    /// others on a separate thread.
+    pub(super) fn serve(&self, sender: Sender<Message>) {
+        let server = self.server.clone();
+        let token = self.token.clone();
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                if is_authorized(&request, &token) {
+                    if sender.send(Message::Request(request)).is_err() {
+                        break;
+                    }
+                } else {
+                    let response = json_response(401, &ApiError::new("Missing or wrong token"))
+                        .with_header(header("WWW-Authenticate", "Bearer"));
+                    let _ = request.respond(response);
+                }
+            }
+        });
+    }
+
+    /// Stops serving requests.
+    pub fn stop(self) {
+        self.server.unblock();
+    }
+}
+
+/// The token file of the API configured in `config`.
+pub fn token_path(config: &Config) -> PathBuf {
+    config
+        .daemon
+        .api_token_file
+        .clone()
+        .unwrap_or_else(|| crate::cli::data_dir().join(TOKEN_FILE))
+}
+
+/// The token in `path`, which is created with a new token readable only by the user if it
+/// does not exist.
+fn load_token(path: &Path) -> io::Result<String> {
+    match fs::read_to_string(path) {
+        Ok(token) if !token.trim().is_empty() => return Ok(token.trim().to_string()),
+        Ok(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "empty token")),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let token = Uuid::new_v4().simple().to_string();
+    writeln!(options.open(path)?, "{}", token)?;
+    Ok(token)
+}
+
+/// `true` if the request sends `token`, compared in constant time.
+fn is_authorized(request: &Request, token: &str) -> bool {
+    let Some(sent) = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Authorization"))
+        .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    sent.len() == token.len()
+        && sent
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The events of the backups run by the daemon, sent to the clients of `GET /v1/events`.
+#[derive(Clone, Default)]
+pub struct Events {
+    subscribers: Arc<Mutex<Vec<Sender<String>>>>,
+}
+
+impl Events {
+    fn subscribe(&self) -> Receiver<String> {
+        let (sender, receiver) = mpsc::channel();
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push(sender);
+        }
+        receiver
+    }
+
+    /// Sends an NDJSON line to all clients, forgetting the clients that have disconnected.
+    fn publish(&self, line: &str) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.retain(|subscriber| subscriber.send(line.to_string()).is_ok());
+        }
+    }
+
+    /// A writer for an [`NdjsonOutput`](crate::cli::output::NdjsonOutput), publishing every
+    /// line written to it.
+    pub fn writer(&self) -> EventWriter {
+        EventWriter {
+            events: self.clone(),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Publishes that the daemon has started a backup of `profile`.
+    pub fn run_started(&self, profile: &str) {
+        self.publish_run(profile, "run_started", None);
+    }
+
+    /// Publishes that the backup of `profile` has exited with `exit_code`.
+    pub fn run_exited(&self, profile: &str, exit_code: u8) {
+        self.publish_run(profile, "run_exited", Some(exit_code));
+    }
+
+    fn publish_run(&self, profile: &str, event: &str, exit_code: Option<u8>) {
+        #[derive(Serialize)]
+        struct RunLine<'a> {
+            schema_version: u32,
+            profile: &'a str,
+            event: &'a str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            exit_code: Option<u8>,
+        }
+
+        let line = RunLine {
+            schema_version: SCHEMA_VERSION,
+            profile,
+            event,
+            exit_code,
+        };
+        if let Ok(line) = serde_json::to_string(&line) {
+            self.publish(&line);
+        }
+    }
+}
+
+/// Publishes the lines written to it as events.
+pub struct EventWriter {
+    events: Events,
+    buffer: Vec<u8>,
+}
+
+impl Write for EventWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            self.events
+                .publish(String::from_utf8_lossy(&line[..end]).as_ref());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct ApiError {
+    schema_version: u32,
+    error: String,
+}
+
+impl ApiError {
+    fn new(error: impl Into<String>) -> Self {
+        ApiError {
+            schema_version: SCHEMA_VERSION,
+            error: error.into(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ProfilesResponse<'a> {
+    schema_version: u32,
+    profiles: Vec<ApiProfile<'a>>,
+}
+
+#[derive(Serialize)]
+struct ApiProfile<'a> {
+    name: &'a str,
+    source: String,
+    /// All the directories backed up, `source` first
+    sources: Vec<String>,
+    destination: String,
+    schedule: Option<String>,
+    running: bool,
+    #[serde(serialize_with = "serialize_time")]
+    running_since: Option<SystemTime>,
+    #[serde(serialize_with = "serialize_time")]
+    next_run: Option<SystemTime>,
+    last_exit_code: Option<u8>,
+}
+
+#[derive(Serialize)]
+struct RunResponse {
+    schema_version: u32,
+    /// `true` if the backup waits for other backups to finish
+    queued: bool,
+}
+
+#[derive(Serialize)]
+struct CancelResponse {
+    schema_version: u32,
+    /// The backup stops once the file being copied has been copied
+    cancelling: bool,
+}
+
+#[derive(Serialize)]
+struct HistoryResponse {
+    schema_version: u32,
+    runs: Vec<rackup::history::RunRecord>,
+}
+
+/// Answers an authorized request to the daemon.
+pub(super) fn handle(
+    request: Request,
+    scheduler: &mut Scheduler,
+    history: &History,
+    events: &Events,
+    now: SystemTime,
+) {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    let response = match (request.method(), segments.as_slice()) {
+        (Method::Get, ["v1", "profiles"]) => json_response(
+            200,
+            &ProfilesResponse {
+                schema_version: SCHEMA_VERSION,
+                profiles: scheduler
+                    .entries
+                    .iter()
+                    .map(|(name, e)| ApiProfile {
+                        name,
+                        source: e.profile.source.to_string_lossy().to_string(),
+                        sources: e
+                            .profile
+                            .source_paths()
+                            .iter()
+                            .map(|source| source.to_string_lossy().to_string())
+                            .collect(),
+                        destination: e.profile.destination.to_string_lossy().to_string(),
+                        schedule: e.schedule.as_ref().map(|s| s.to_string()),
+                        running: e.running_since.is_some(),
+                        running_since: e.running_since,
+                        next_run: e.next_run,
+                        last_exit_code: e.last_exit_code,
+                    })
+                    .collect(),
+            },
+        ),
+        (Method::Post, ["v1", "profiles", name, "run"]) => {
+            let running = scheduler
+                .entries
+                .values()
+                .filter(|e| e.running_since.is_some());
+            let queued = running.count() >= scheduler.max_concurrent;
+            match scheduler.entries.get_mut(*name) {
+                None => unknown_profile(name),
+                Some(entry) if entry.running_since.is_some() => {
+                    json_response(409, &ApiError::new(format!("{} is already running", name)))
+                }
+                Some(entry) => {
+                    entry.next_run = Some(now);
+                    json_response(
+                        202,
+                        &RunResponse {
+                            schema_version: SCHEMA_VERSION,
+                            queued,
+                        },
+                    )
+                }
+            }
+        }
+        (Method::Post, ["v1", "profiles", name, "cancel"]) => match scheduler.entries.get(*name) {
+            None => unknown_profile(name),
+            Some(entry) => match &entry.cancel {
+                Some(cancel) => {
+                    cancel.cancel();
+                    json_response(
+                        202,
+                        &CancelResponse {
+                            schema_version: SCHEMA_VERSION,
+                            cancelling: true,
+                        },
+                    )
+                }
+                None => json_response(409, &ApiError::new(format!("{} is not running", name))),
+            },
+        },
+        (Method::Get, ["v1", "history"]) => {
+            let mut profile = None;
+            let mut limit = DEFAULT_HISTORY_LIMIT;
+            for (key, value) in query.split('&').filter_map(|p| p.split_once('=')) {
+                match key {
+                    "profile" => profile = Some(value),
+                    "limit" => limit = value.parse().unwrap_or(limit),
+                    _ => {}
+                }
+            }
+            match history.records() {
+                Ok(records) => json_response(
+                    200,
+                    &HistoryResponse {
+                        schema_version: SCHEMA_VERSION,
+                        runs: records
+                            .into_iter()
+                            .rev()
+                            .filter(|r| profile.is_none_or(|p| r.profile.as_deref() == Some(p)))
+                            .take(limit)
+                            .collect(),
+                    },
+                ),
+                Err(err) => json_response(500, &ApiError::new(err.to_string())),
+            }
+        }
+        (Method::Get, ["v1", "events"]) => {
+            stream_events(request, events.subscribe());
+            return;
+        }
+        _ => json_response(404, &ApiError::new(format!("Unknown request {}", path))),
+    };
+
+    // A client that has gone away is not an error of the daemon
+    let _ = request.respond(response);
+}
+
+/// Streams the events received by `receiver` to the client on a separate thread, until the
+/// client disconnects or the daemon stops.
+fn stream_events(request: Request, receiver: Receiver<String>) {
+    thread::spawn(move || {
+        // The response is written by hand, so that every line is sent right away
+        let mut writer = request.into_writer();
+        write!(
+            writer,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\n\
+             Cache-Control: no-cache\r\nConnection: close\r\n\r\n"
+        )?;
+        writer.flush()?;
+        for line in receiver {
+            writeln!(writer, "{}", line)?;
+            writer.flush()?;
+        }
+        Ok::<(), io::Error>(())
+    });
+}
+
+fn unknown_profile(name: &str) -> Response<Cursor<Vec<u8>>> {
+    json_response(404, &ApiError::new(format!("Unknown profile {}", name)))
+}
+
+fn json_response(status: u16, body: &impl Serialize) -> Response<Cursor<Vec<u8>>> {
+    // Serializing the responses cannot fail
+    let body = serde_json::to_vec(body).unwrap_or_default();
+    Response::from_data(body)
+        .with_status_code(StatusCode(status))
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn header(field: &str, value: &str) -> Header {
+    // The headers are all valid ASCII
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).expect("Invalid header")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rackup::history::RunRecord;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    /// Sends a request to the API, returning the status code and the body.
+    fn request(address: &str, method: &str, path: &str, token: &str) -> io::Result<(u16, String)> {
+        let mut stream = TcpStream::connect(address)?;
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\n\
+             Content-Length: 0\r\nConnection: close\r\n\r\n",
+            method, path, token
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+
+        let status = response[9..12].parse().unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        Ok((status, body.to_string()))
+    }
+
+    #[test]
+    fn test_api() -> io::Result<()> {
+        let test_dir = tempfile::tempdir()?;
+        let config = Config::parse(
+            r#"
+            [profiles.documents]
+            source = "/home/bob/Documents"
+            destination = "/media/backup"
+            schedule = "every 1day"
+
+            [profiles.music]
+            source = "/home/bob/Music"
+            sources = ["/home/bob/Podcasts"]
+            destination = "/media/backup"
+            "#,
+        )
+        .unwrap();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut scheduler = Scheduler::new(&config, |_| Some(now), now);
+
+        let history = History::new(test_dir.path().join("history.jsonl"));
+        for (profile, exit_code) in [("documents", 0), ("music", 0), ("documents", 1)] {
+            let result = Err(Error::Config("invalid".to_string()));
+            history.append(&RunRecord::new(
+                now,
+                Some(profile),
+                &[Path::new("/home/bob")],
+                Path::new("/media/backup"),
+                &result,
+                exit_code,
+            ))?;
+        }
+
+        let token_file = test_dir.path().join("api-token");
+        let api = Api::bind("127.0.0.1:0".parse().unwrap(), &token_file).unwrap();
+        let token = fs::read_to_string(&token_file)?.trim().to_string();
+        assert_eq!(token.len(), 32);
+        let (sender, receiver) = mpsc::channel();
+        api.serve(sender);
+
+        let address = api.address();
+        let client = thread::spawn(move || {
+            let mut responses = vec![
+                request(&address, "GET", "/v1/profiles", "wrong")?,
+                request(&address, "GET", "/v1/profiles", &token)?,
+                request(&address, "POST", "/v1/profiles/music/run", &token)?,
+                request(&address, "POST", "/v1/profiles/documents/cancel", &token)?,
+                request(&address, "POST", "/v1/profiles/photos/run", &token)?,
+                request(
+                    &address,
+                    "GET",
+                    "/v1/history?profile=documents&limit=1",
+                    &token,
+                )?,
+            ];
+
+            // The events are streamed line by line
+            let mut stream = TcpStream::connect(&address)?;
+            write!(
+                stream,
+                "GET /v1/events HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n",
+                token
+            )?;
+            let mut lines = BufReader::new(stream).lines();
+            let status = lines.next().unwrap()?;
+            let event = lines.find(|line| line.as_ref().is_ok_and(|l| l.starts_with('{')));
+            responses.push((status[9..12].parse().unwrap(), event.unwrap()?));
+            Ok::<_, io::Error>(responses)
+        });
+
+        let events = Events::default();
+        while !client.is_finished() {
+            if let Ok(Message::Request(request)) = receiver.recv_timeout(Duration::from_millis(10))
+            {
+                handle(request, &mut scheduler, &history, &events, now);
+            }
+            events.run_started("music");
+        }
+        api.stop();
+
+        let responses = client.join().unwrap()?;
+        let statuses: Vec<u16> = responses.iter().map(|(status, _)| *status).collect();
+        assert_eq!(statuses, vec![401, 200, 202, 409, 404, 200, 200]);
+
+        let profiles: serde_json::Value = serde_json::from_str(&responses[1].1)?;
+        assert_eq!(profiles["profiles"][0]["name"], "documents");
+        assert_eq!(profiles["profiles"][0]["schedule"], "every 1day");
+        assert_eq!(profiles["profiles"][0]["running"], false);
+        assert_eq!(profiles["profiles"][1]["next_run"], serde_json::Value::Null);
+        assert_eq!(
+            profiles["profiles"][1]["sources"],
+            serde_json::json!(["/home/bob/Music", "/home/bob/Podcasts"])
+        );
+
+        // The requested backup is due right away
+        assert_eq!(scheduler.entries["music"].next_run, Some(now));
+
+        let runs: serde_json::Value = serde_json::from_str(&responses[5].1)?;
+        assert_eq!(runs["runs"].as_array().map(Vec::len), Some(1));
+        assert_eq!(runs["runs"][0]["exit_code"], 1);
+
+        assert_eq!(
+            responses[6].1,
+            r#"{"schema_version":1,"profile":"music","event":"run_started"}"#
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_event_writer() {
+        let events = Events::default();
+        let receiver = events.subscribe();
+        let mut writer = events.writer();
+        write!(writer, "{{\"event\":").unwrap();
+        assert!(receiver.try_recv().is_err());
+        writeln!(writer, "\"copied\"}}").unwrap();
+        assert_eq!(receiver.try_recv().as_deref(), Ok("{\"event\":\"copied\"}"));
+
+        // Disconnected clients are forgotten
+        drop(receiver);
+        events.run_exited("music", 0);
+        assert!(events.subscribers.lock().unwrap().is_empty());
+    }
+}
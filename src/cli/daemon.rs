@@ -0,0 +1,607 @@
+//! The `rackup daemon` command, backing up the profiles that have a schedule.
+//!
+//! The daemon reports its state on a Unix socket: a client writes the line `status` and reads
+//! back a [`DaemonStatus`] as a single JSON line. `rackup status` uses it to show which
+//! profiles are running and when they run next. Clients can also start and cancel backups
+//! through the HTTP [`api`](crate::cli::api).
+//!
+use crate::cli::api::{self, Api, Events};
+use crate::cli::output::{NdjsonOutput, OutputFormat, SCHEMA_VERSION};
+use crate::cli::progress::{TerminalProgress, Verbosity};
+use crate::{backup_builder, finish_run, lock_profile, start_run, EXIT_LOCKED};
+use humantime::format_rfc3339_seconds;
+use rackup::config::{Config, Profile};
+use rackup::history::History;
+use rackup::schedule::Schedule;
+use rackup::{BackupEvent, BackupObserver, CancelToken, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The name of the socket in the local data directory.
+pub const SOCKET_FILE: &str = "rackup.sock";
+
+/// How often the daemon checks for profiles that are due.
+const TICK: Duration = Duration::from_secs(1);
+
+/// How soon a profile is tried again when another backup held its lock.
+const LOCKED_RETRY: Duration = Duration::from_secs(60);
+
+/// The state of the daemon, as reported on its socket. Times are Unix timestamps in seconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    pub schema_version: u32,
+    pub pid: u32,
+    pub started: u64,
+    pub max_concurrent: usize,
+    pub profiles: Vec<DaemonProfile>,
+}
+
+/// The state of a profile in the daemon.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DaemonProfile {
+    pub name: String,
+    /// `None` if the profile is only backed up when requested through the API.
+    pub schedule: Option<String>,
+    /// When the profile runs next, `None` while it is running or if it is not scheduled.
+    pub next_run: Option<u64>,
+    /// When the running backup of the profile started.
+    pub running_since: Option<u64>,
+    /// The exit code of the last backup run by the daemon.
+    pub last_exit_code: Option<u8>,
+}
+
+/// What wakes up the daemon.
+pub(super) enum Message {
+    /// The backup of a profile has finished.
+    Finished { name: String, exit_code: u8 },
+    /// A client of the API sent a request.
+    Request(tiny_http::Request),
+}
+
+/// A profile of the configuration.
+pub(super) struct Entry {
+    pub profile: Profile,
+    pub schedule: Option<Schedule>,
+    pub next_run: Option<SystemTime>,
+    pub running_since: Option<SystemTime>,
+    /// Cancels the running backup.
+    pub cancel: Option<CancelToken>,
+    pub last_exit_code: Option<u8>,
+}
+
+/// Decides which profiles run when.
+pub(super) struct Scheduler {
+    pub entries: BTreeMap<String, Entry>,
+    pub max_concurrent: usize,
+}
+
+impl Scheduler {
+    /// Schedules the profiles of `config` that have a schedule. `last_started` gives when
+    /// each profile was last backed up, so that runs missed while the daemon was not
+    /// running are caught up right away. Profiles that have never been backed up are due
+    /// immediately.
+    pub fn new(
+        config: &Config,
+        last_started: impl Fn(&str) -> Option<SystemTime>,
+        now: SystemTime,
+    ) -> Self {
+        let entries = config
+            .profiles
+            .iter()
+            .map(|(name, profile)| {
+                let schedule = profile.schedule.clone();
+                let next_run = schedule
+                    .as_ref()
+                    .and_then(|schedule| match last_started(name) {
+                        Some(last) => schedule.next_after(last),
+                        None => Some(now),
+                    });
+                (
+                    name.clone(),
+                    Entry {
+                        profile: profile.clone(),
+                        schedule,
+                        next_run,
+                        running_since: None,
+                        cancel: None,
+                        last_exit_code: None,
+                    },
+                )
+            })
+            .collect();
+
+        Scheduler {
+            entries,
+            max_concurrent: config.daemon.max_concurrent.max(1),
+        }
+    }
+
+    fn running(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|e| e.running_since.is_some())
+            .count()
+    }
+
+    /// The profiles to start at `now`, the longest overdue first, as many as may run.
+    ///
+    /// A profile whose runs have been missed, e.g. while the computer was asleep, only runs
+    /// once to catch up.
+    fn due(&self, now: SystemTime) -> Vec<String> {
+        let mut due: Vec<(&String, SystemTime)> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| e.running_since.is_none())
+            .filter_map(|(name, e)| Some((name, e.next_run?)))
+            .filter(|(_, next_run)| *next_run <= now)
+            .collect();
+        due.sort_by_key(|(_, next_run)| *next_run);
+
+        let free = self.max_concurrent.saturating_sub(self.running());
+        due.into_iter()
+            .take(free)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    fn started(&mut self, name: &str, now: SystemTime, cancel: CancelToken) {
+        if let Some(entry) = self.entries.get_mut(name) {
+            entry.running_since = Some(now);
+            entry.cancel = Some(cancel);
+            entry.next_run = None;
+        }
+    }
+
+    /// Schedules the next run of a profile whose backup finished at `now`.
+    fn finished(&mut self, name: &str, exit_code: u8, now: SystemTime) {
+        if let Some(entry) = self.entries.get_mut(name) {
+            entry.running_since = None;
+            entry.cancel = None;
+            entry.last_exit_code = Some(exit_code);
+            entry.next_run = if exit_code == EXIT_LOCKED && entry.schedule.is_some() {
+                now.checked_add(LOCKED_RETRY)
+            } else {
+                entry
+                    .schedule
+                    .as_ref()
+                    .and_then(|schedule| schedule.next_after(now))
+            };
+        }
+    }
+
+    /// Cancels all running backups.
+    fn cancel_all(&self) {
+        for cancel in self.entries.values().filter_map(|e| e.cancel.as_ref()) {
+            cancel.cancel();
+        }
+    }
+
+    fn status(&self, started: SystemTime) -> DaemonStatus {
+        DaemonStatus {
+            schema_version: SCHEMA_VERSION,
+            pid: std::process::id(),
+            started: unix_seconds(started),
+            max_concurrent: self.max_concurrent,
+            profiles: self
+                .entries
+                .iter()
+                .map(|(name, e)| DaemonProfile {
+                    name: name.clone(),
+                    schedule: e.schedule.as_ref().map(Schedule::to_string),
+                    next_run: e.next_run.map(unix_seconds),
+                    running_since: e.running_since.map(unix_seconds),
+                    last_exit_code: e.last_exit_code,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Runs the profiles of `config` on their schedules, and those requested through the `api`,
+/// until the process is interrupted.
+pub fn run_daemon(
+    config: &Config,
+    history: &History,
+    socket: &Path,
+    api: Option<Api>,
+    cancel: &CancelToken,
+    verbosity: Verbosity,
+) -> rackup::Result<()> {
+    let log = |message: String| {
+        if verbosity > Verbosity::Quiet {
+            println!("{}  {}", format_rfc3339_seconds(SystemTime::now()), message);
+        }
+    };
+
+    let statuses = history.status(1)?;
+    let last_started = |name: &str| {
+        statuses
+            .iter()
+            .find(|s| s.label == name)
+            .and_then(|s| s.last_runs.first())
+            .map(|run| run.started)
+    };
+    let started = SystemTime::now();
+    let mut scheduler = Scheduler::new(config, last_started, started);
+    if api.is_none() && scheduler.entries.values().all(|e| e.schedule.is_none()) {
+        return Err(Error::Config(
+            "No profile has a schedule, add one such as `schedule = \"0 3 * * *\"`".to_string(),
+        ));
+    }
+
+    let server = socket::Server::bind(socket)?;
+    log(format!("Status on {}", socket.to_string_lossy()));
+    for (name, entry) in &scheduler.entries {
+        if let (Some(schedule), Some(next_run)) = (&entry.schedule, entry.next_run) {
+            log(format!(
+                "{} ({}) runs next at {}",
+                name,
+                schedule,
+                format_rfc3339_seconds(next_run)
+            ));
+        }
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let events = Events::default();
+    if let Some(api) = &api {
+        log(format!("API on http://{}", api.address()));
+        api.serve(sender.clone());
+    }
+
+    loop {
+        let now = SystemTime::now();
+        if cancel.is_cancelled() {
+            scheduler.cancel_all();
+            if scheduler.running() == 0 {
+                break;
+            }
+        } else {
+            for name in scheduler.due(now) {
+                let run_cancel = CancelToken::new();
+                scheduler.started(&name, now, run_cancel.clone());
+                log(format!("Backing up {}", name));
+                events.run_started(&name);
+
+                let profile = scheduler.entries[&name].profile.clone();
+                let sender = sender.clone();
+                let events = events.clone();
+                // A backup is not Send, so it is built by the thread running it
+                thread::spawn(move || {
+                    let exit_code = run_profile(&name, &profile, run_cancel, &events);
+                    let _ = sender.send(Message::Finished { name, exit_code });
+                });
+            }
+        }
+
+        server.serve(|| scheduler.status(started));
+
+        match receiver.recv_timeout(TICK) {
+            Ok(Message::Finished { name, exit_code }) => {
+                scheduler.finished(&name, exit_code, SystemTime::now());
+                events.run_exited(&name, exit_code);
+                let entry = &scheduler.entries[&name];
+                log(format!(
+                    "Backed up {} with exit code {}{}",
+                    name,
+                    exit_code,
+                    match entry.next_run {
+                        Some(next_run) =>
+                            format!(", next run at {}", format_rfc3339_seconds(next_run)),
+                        None => String::new(),
+                    }
+                ));
+            }
+            Ok(Message::Request(request)) => {
+                api::handle(request, &mut scheduler, history, &events, SystemTime::now())
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            // The scheduler keeps a sender, so the channel cannot be disconnected
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    if let Some(api) = api {
+        api.stop();
+    }
+    log("Stopped".to_string());
+    Ok(())
+}
+
+/// Backs up a profile like `rackup run -q --resume` does, returning the exit code. A backup
+/// interrupted when the daemon was stopped continues where it left off.
+fn run_profile(name: &str, profile: &Profile, cancel: CancelToken, events: &Events) -> u8 {
+    let started = SystemTime::now();
+    let mut terminal = TerminalProgress::new(Verbosity::Quiet);
+    let mut ndjson = NdjsonOutput::for_profile(events.writer(), name);
+    let builder = backup_builder(Some(name), profile, Verbosity::Quiet, OutputFormat::Text)
+        .observer(move |event: &BackupEvent| {
+            terminal.on_event(event);
+            ndjson.on_event(event);
+        })
+        .cancel_token(cancel)
+        .resume(true);
+    let mut id = None;
+    let result = lock_profile(
+        Some(name),
+        Duration::ZERO,
+        Verbosity::Quiet,
+        OutputFormat::Text,
+    )
+    .and_then(|_lock| {
+        id = start_run(Some(name), profile, started);
+        builder.run()
+    });
+    finish_run(
+        Some(name),
+        profile,
+        started,
+        id,
+        &result,
+        OutputFormat::Text,
+    )
+}
+
+/// The socket of the daemon configured in `config`.
+pub fn socket_path(config: &Config) -> PathBuf {
+    config
+        .daemon
+        .socket
+        .clone()
+        .unwrap_or_else(|| crate::cli::data_dir().join(SOCKET_FILE))
+}
+
+/// Asks the daemon listening on `socket` for its state.
+pub fn query_status(socket: &Path) -> std::io::Result<DaemonStatus> {
+    socket::query(socket)
+}
+
+/// `time` as a Unix timestamp in seconds.
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// A Unix timestamp in seconds as a time.
+pub fn from_unix_seconds(seconds: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(seconds)
+}
+
+#[cfg(unix)]
+mod socket {
+    use super::DaemonStatus;
+    use rackup::Error;
+    use std::fs;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    /// How long a client may take to send its request or read the answer.
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// The listening socket, removed when dropped.
+    pub struct Server {
+        listener: UnixListener,
+        path: PathBuf,
+    }
+
+    impl Server {
+        /// Listens on `path`. A socket left behind by a daemon that has been killed is
+        /// replaced, but not the socket of a daemon that is still running.
+        pub fn bind(path: &Path) -> rackup::Result<Server> {
+            let lock_error = |source| Error::Lock {
+                path: path.to_path_buf(),
+                source,
+            };
+
+            if path.exists() {
+                if UnixStream::connect(path).is_ok() {
+                    return Err(Error::Locked {
+                        path: path.to_path_buf(),
+                        holder: "another rackup daemon".to_string(),
+                    });
+                }
+                fs::remove_file(path).map_err(lock_error)?;
+            }
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).map_err(lock_error)?;
+            }
+
+            let listener = UnixListener::bind(path).map_err(lock_error)?;
+            listener.set_nonblocking(true).map_err(lock_error)?;
+            Ok(Server {
+                listener,
+                path: path.to_path_buf(),
+            })
+        }
+
+        /// Answers the clients that are waiting with the status returned by `status`.
+        pub fn serve(&self, status: impl Fn() -> DaemonStatus) {
+            while let Ok((stream, _)) = self.listener.accept() {
+                // A client that goes away is not an error of the daemon
+                let _ = answer(stream, &status);
+            }
+        }
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    fn answer(stream: UnixStream, status: impl Fn() -> DaemonStatus) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let mut request = String::new();
+        BufReader::new(&stream).read_line(&mut request)?;
+        let mut stream = &stream;
+        match request.trim() {
+            "status" => {
+                serde_json::to_writer(&mut stream, &status())?;
+                writeln!(stream)
+            }
+            other => writeln!(
+                stream,
+                "{}",
+                serde_json::json!({ "error": format!("unknown request {}", other) })
+            ),
+        }
+    }
+
+    pub fn query(path: &Path) -> io::Result<DaemonStatus> {
+        let mut stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.write_all(b"status\n")?;
+
+        let mut answer = String::new();
+        BufReader::new(&stream).read_line(&mut answer)?;
+        Ok(serde_json::from_str(&answer)?)
+    }
+}
+
+/// Unix sockets are not available on this platform, so the state of the daemon cannot be
+/// queried.
+#[cfg(not(unix))]
+mod socket {
+    use super::DaemonStatus;
+    use std::io;
+    use std::path::Path;
+
+    pub struct Server;
+
+    impl Server {
+        pub fn bind(_path: &Path) -> rackup::Result<Server> {
+            Ok(Server)
+        }
+
+        pub fn serve(&self, _status: impl Fn() -> DaemonStatus) {}
+    }
+
+    pub fn query(_path: &Path) -> io::Result<DaemonStatus> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(max_concurrent: usize) -> Config {
+        Config::parse(&format!(
+            r#"
+            [daemon]
+            max_concurrent = {}
+
+            [profiles.documents]
+            source = "/home/bob/Documents"
+            destination = "/media/backup"
+            schedule = "every 1h"
+
+            [profiles.music]
+            source = "/home/bob/Music"
+            destination = "/media/backup"
+            schedule = "every 1day"
+
+            [profiles.photos]
+            source = "/home/bob/Photos"
+            destination = "/media/backup"
+            "#,
+            max_concurrent
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_schedule() {
+        let hour = Duration::from_secs(60 * 60);
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        // The documents have been backed up half an hour ago, the music never
+        let last_started = |name: &str| (name == "documents").then(|| now - hour / 2);
+        let mut scheduler = Scheduler::new(&config(1), last_started, now);
+        // The photos are not scheduled
+        assert_eq!(scheduler.entries["photos"].next_run, None);
+        assert_eq!(scheduler.due(now), vec!["music"]);
+
+        scheduler.started("music", now, CancelToken::new());
+        assert!(scheduler.due(now + hour).is_empty());
+
+        scheduler.finished("music", 0, now + hour);
+        assert_eq!(scheduler.due(now + hour), vec!["documents"]);
+        assert_eq!(scheduler.entries["music"].next_run, Some(now + 25 * hour));
+
+        // After missing runs while asleep, the longest overdue profile runs first
+        assert_eq!(scheduler.due(now + 30 * hour), vec!["documents"]);
+
+        // A profile that was locked is retried soon
+        scheduler.started("documents", now + hour, CancelToken::new());
+        scheduler.finished("documents", EXIT_LOCKED, now + hour);
+        assert_eq!(
+            scheduler.entries["documents"].next_run,
+            Some(now + hour + LOCKED_RETRY)
+        );
+    }
+
+    #[test]
+    fn test_max_concurrent() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut scheduler = Scheduler::new(&config(2), |_| None, now);
+        assert_eq!(scheduler.due(now), vec!["documents", "music"]);
+
+        scheduler.started("documents", now, CancelToken::new());
+        assert_eq!(scheduler.due(now), vec!["music"]);
+
+        let status = scheduler.status(now);
+        assert_eq!(status.max_concurrent, 2);
+        assert_eq!(status.profiles[0].running_since, Some(1_700_000_000));
+        assert_eq!(status.profiles[0].next_run, None);
+        assert_eq!(status.profiles[1].schedule.as_deref(), Some("every 1day"));
+        assert_eq!(status.profiles[2].schedule, None);
+
+        scheduler.cancel_all();
+        assert!(scheduler.entries["documents"]
+            .cancel
+            .as_ref()
+            .is_some_and(CancelToken::is_cancelled));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_socket() -> std::io::Result<()> {
+        let test_dir = tempfile::tempdir()?;
+        let path = test_dir.path().join(SOCKET_FILE);
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let scheduler = Scheduler::new(&config(1), |_| None, now);
+
+        let server = socket::Server::bind(&path).expect("Failed to bind the socket");
+        assert!(matches!(
+            socket::Server::bind(&path),
+            Err(Error::Locked { .. })
+        ));
+
+        let client = thread::spawn({
+            let path = path.clone();
+            move || query_status(&path)
+        });
+        // The server does not block while waiting for clients
+        while !client.is_finished() {
+            server.serve(|| scheduler.status(now));
+            thread::sleep(Duration::from_millis(10));
+        }
+        let status = client.join().unwrap()?;
+        assert_eq!(status, scheduler.status(now));
+
+        drop(server);
+        assert!(!path.exists());
+        Ok(())
+    }
+}
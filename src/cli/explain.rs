@@ -0,0 +1,162 @@
+//! The `rackup explain` command.
+//!
+use crate::cli::output::{OutputFormat, SCHEMA_VERSION};
+use rackup::explain::{Explanation, RuleOutcome};
+use serde::Serialize;
+use std::path::Path;
+
+/// An explanation printed with `--output json`.
+#[derive(Serialize)]
+struct JsonExplanation<'a> {
+    schema_version: u32,
+    profile: &'a str,
+    path: String,
+    source: String,
+    included: bool,
+    items: Vec<JsonItem<'a>>,
+    change: Option<JsonChange<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonItem<'a> {
+    path: String,
+    included: bool,
+    note: Option<&'a str>,
+    rules: Vec<JsonRule<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonRule<'a> {
+    rule: &'a str,
+    description: Option<&'a str>,
+    outcome: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    detail: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct JsonChange<'a> {
+    backup_path: String,
+    copy: bool,
+    reason: &'a str,
+}
+
+/// Prints why an item is or is not backed up by the profile `name`.
+pub fn print_explanation(name: &str, explanation: &Explanation, output: OutputFormat) {
+    if output != OutputFormat::Text {
+        let json = JsonExplanation {
+            schema_version: SCHEMA_VERSION,
+            profile: name,
+            path: path_string(&explanation.path),
+            source: path_string(&explanation.source),
+            included: explanation.included,
+            items: explanation
+                .items
+                .iter()
+                .map(|item| JsonItem {
+                    path: path_string(&item.path),
+                    included: item.included,
+                    note: item.note.as_deref(),
+                    rules: item
+                        .rules
+                        .iter()
+                        .map(|rule| JsonRule {
+                            rule: rule.rule,
+                            description: rule.description.as_deref(),
+                            outcome: rule.outcome.name(),
+                            error: match &rule.outcome {
+                                RuleOutcome::Failed(err) => Some(err),
+                                _ => None,
+                            },
+                            detail: rule.detail.as_deref(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+            change: explanation.change.as_ref().map(|change| JsonChange {
+                backup_path: path_string(&change.backup_path),
+                copy: change.copy,
+                reason: &change.reason,
+            }),
+        };
+        // Serializing the explanation cannot fail
+        let json = serde_json::to_string_pretty(&json).unwrap_or_default();
+        println!("{}", json);
+        return;
+    }
+
+    println!(
+        "{} is {}backed up by the profile {}.",
+        explanation.path.to_string_lossy(),
+        if explanation.included { "" } else { "not " },
+        name
+    );
+
+    let width = explanation
+        .items
+        .iter()
+        .flat_map(|item| &item.rules)
+        .map(|rule| rule.rule.len())
+        .max()
+        .unwrap_or_default();
+    for item in &explanation.items {
+        let relative = item
+            .path
+            .strip_prefix(&explanation.source)
+            .unwrap_or(&item.path);
+        println!();
+        println!(
+            "{}: {}",
+            relative.to_string_lossy(),
+            if item.included {
+                "included"
+            } else {
+                "excluded"
+            }
+        );
+        for rule in &item.rules {
+            let outcome = match &rule.outcome {
+                RuleOutcome::NotApplicable => "not applicable".to_string(),
+                RuleOutcome::NotMatching => "not matching".to_string(),
+                RuleOutcome::Skipped => "no decision".to_string(),
+                RuleOutcome::Included => "included".to_string(),
+                RuleOutcome::IncludedAbsolute => "included, no more rules checked".to_string(),
+                RuleOutcome::Excluded => "excluded".to_string(),
+                RuleOutcome::Mapped => "replaced by other items".to_string(),
+                RuleOutcome::Failed(err) => format!("failed: {}", err),
+            };
+            match &rule.detail {
+                Some(detail) => println!(
+                    "  {:width$}  {} ({})",
+                    rule.rule,
+                    outcome,
+                    detail,
+                    width = width
+                ),
+                None => println!("  {:width$}  {}", rule.rule, outcome, width = width),
+            }
+        }
+        if let Some(note) = &item.note {
+            println!("  {}", note);
+        }
+    }
+
+    if let Some(change) = &explanation.change {
+        println!();
+        println!(
+            "{} to {}: {}.",
+            if change.copy {
+                "Would be copied"
+            } else {
+                "Would not be copied"
+            },
+            change.backup_path.to_string_lossy(),
+            change.reason
+        );
+    }
+}
+
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().to_string()
+}
@@ -0,0 +1,474 @@
+//! The `rackup health` command, a check for monitoring systems.
+//!
+//! The result is printed in the format of a Nagios plugin: a first line with the overall
+//! state, followed by one line for every profile, and the exit code is the one of the state.
+//! The metrics can also be written to a file for the textfile collector of the Prometheus
+//! node exporter.
+//!
+use crate::cli::output::{OutputFormat, SCHEMA_VERSION};
+use crate::cli::status::profile_statuses;
+use humantime::format_duration;
+use indicatif::HumanBytes;
+use rackup::config::{Config, Profile};
+use rackup::history::{History, ProfileStatus, RunRecord, RunStatus};
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// The state of a check, ordered from the best to the worst.
+///
+/// A known problem ranks above an unknown state, so that a failing backup is not hidden by
+/// a destination that is not mounted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthState {
+    Ok,
+    Unknown,
+    Warning,
+    Critical,
+}
+
+impl HealthState {
+    /// The exit code of a Nagios plugin in this state.
+    pub fn exit_code(self) -> u8 {
+        match self {
+            HealthState::Ok => 0,
+            HealthState::Warning => 1,
+            HealthState::Critical => 2,
+            HealthState::Unknown => 3,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            HealthState::Ok => "OK",
+            HealthState::Warning => "WARNING",
+            HealthState::Critical => "CRITICAL",
+            HealthState::Unknown => "UNKNOWN",
+        }
+    }
+}
+
+/// The limits a profile is checked against.
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    /// Critical when the last successful backup is older than this.
+    pub max_age: Duration,
+    /// Warning when less space is available on the destination.
+    pub min_free: u64,
+}
+
+/// The result of the check of a profile.
+#[derive(Debug, Serialize)]
+pub struct ProfileHealth {
+    profile: String,
+    state: HealthState,
+    /// What is wrong with the profile, empty if the state is OK.
+    problems: Vec<String>,
+    #[serde(skip)]
+    last_success: Option<SystemTime>,
+    #[serde(skip)]
+    last_run: Option<RunRecord>,
+    available_bytes: Option<u64>,
+}
+
+impl ProfileHealth {
+    /// Checks a profile, using the free space on its destination.
+    fn check(
+        profile: &Profile,
+        status: &ProfileStatus,
+        thresholds: &Thresholds,
+        now: SystemTime,
+    ) -> Self {
+        let available = fs4::available_space(&profile.destination);
+        ProfileHealth::evaluate(profile, status, available, thresholds, now)
+    }
+
+    fn evaluate(
+        profile: &Profile,
+        status: &ProfileStatus,
+        available: io::Result<u64>,
+        thresholds: &Thresholds,
+        now: SystemTime,
+    ) -> Self {
+        let mut health = ProfileHealth {
+            profile: status.label.clone(),
+            state: HealthState::Ok,
+            problems: Vec::new(),
+            last_success: status.last_success,
+            // A backup that is running is judged by the run before it
+            last_run: status
+                .last_runs
+                .iter()
+                .find(|run| run.status != RunStatus::Running)
+                .cloned(),
+            available_bytes: available.as_ref().ok().copied(),
+        };
+
+        match status.last_success {
+            None => health.problem(HealthState::Critical, "never backed up successfully".into()),
+            Some(time) if status.is_stale(thresholds.max_age, now) => health.problem(
+                HealthState::Critical,
+                format!(
+                    "last success {} ago",
+                    format_duration(Duration::from_secs(
+                        now.duration_since(time).unwrap_or_default().as_secs() / 60 * 60
+                    ))
+                ),
+            ),
+            Some(_) => {}
+        }
+
+        if let Some(run) = &health.last_run {
+            match run.status {
+                RunStatus::Success | RunStatus::Running => {}
+                RunStatus::PartialFailure => {
+                    let message = format!("{} files failed in the last run", run.failed);
+                    health.problem(HealthState::Warning, message)
+                }
+                RunStatus::Incomplete => {
+                    health.problem(HealthState::Warning, "last run was interrupted".into())
+                }
+                RunStatus::Error => {
+                    let message = format!(
+                        "last run failed: {}",
+                        run.errors
+                            .first()
+                            .map(String::as_str)
+                            .unwrap_or("unknown error")
+                    );
+                    health.problem(HealthState::Critical, message)
+                }
+            }
+        }
+
+        match available {
+            Ok(available) if available < thresholds.min_free => health.problem(
+                HealthState::Warning,
+                format!(
+                    "only {} free on {}",
+                    HumanBytes(available),
+                    profile.destination.to_string_lossy()
+                ),
+            ),
+            Ok(_) => {}
+            Err(err) => health.problem(
+                HealthState::Unknown,
+                format!(
+                    "cannot read the free space on {}: {}",
+                    profile.destination.to_string_lossy(),
+                    err
+                ),
+            ),
+        }
+
+        health
+    }
+
+    fn problem(&mut self, state: HealthState, message: String) {
+        self.state = self.state.max(state);
+        self.problems.push(message);
+    }
+}
+
+/// The result of `rackup health` printed with `--output json`.
+#[derive(Serialize)]
+struct JsonHealth<'a> {
+    schema_version: u32,
+    state: HealthState,
+    profiles: &'a [ProfileHealth],
+}
+
+/// Checks every profile of the configuration, prints the result and returns the overall
+/// state. The metrics are also written to `prometheus` if given.
+pub fn check_health(
+    history: &History,
+    config: &Config,
+    thresholds: &Thresholds,
+    prometheus: Option<&Path>,
+    output: OutputFormat,
+) -> rackup::Result<HealthState> {
+    let now = SystemTime::now();
+    let statuses = profile_statuses(history, config, 2)?;
+
+    // Backups given on the command line cannot be checked, as their schedule is not known
+    let profiles: Vec<ProfileHealth> = statuses
+        .iter()
+        .filter_map(|status| {
+            let profile = config.profiles.get(&status.label)?;
+            Some(ProfileHealth::check(profile, status, thresholds, now))
+        })
+        .collect();
+
+    let mut state = profiles
+        .iter()
+        .map(|p| p.state)
+        .max()
+        .unwrap_or(HealthState::Unknown);
+
+    if let Some(path) = prometheus {
+        if let Err(err) = write_prometheus(path, &profiles) {
+            eprintln!(
+                "Error writing the metrics to {}: {}",
+                path.to_string_lossy(),
+                err
+            );
+            state = state.max(HealthState::Unknown);
+        }
+    }
+
+    if output != OutputFormat::Text {
+        let json = JsonHealth {
+            schema_version: SCHEMA_VERSION,
+            state,
+            profiles: &profiles,
+        };
+        // Serializing the result cannot fail
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&json).unwrap_or_default()
+        );
+        return Ok(state);
+    }
+
+    if profiles.is_empty() {
+        println!("RACKUP UNKNOWN - no profiles configured");
+        return Ok(state);
+    }
+
+    let failing = profiles
+        .iter()
+        .filter(|p| p.state != HealthState::Ok)
+        .count();
+    if failing == 0 {
+        println!("RACKUP OK - {} profiles backed up", profiles.len());
+    } else {
+        println!(
+            "RACKUP {} - {} of {} profiles need attention",
+            state.name(),
+            failing,
+            profiles.len()
+        );
+    }
+    for profile in &profiles {
+        if profile.problems.is_empty() {
+            println!("{} {}", profile.state.name(), profile.profile);
+        } else {
+            println!(
+                "{} {}: {}",
+                profile.state.name(),
+                profile.profile,
+                profile.problems.join(", ")
+            );
+        }
+    }
+
+    Ok(state)
+}
+
+/// Writes the metrics to `path`, replacing the file at once so that the collector never
+/// reads a half written file.
+fn write_prometheus(path: &Path, profiles: &[ProfileHealth]) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+
+    fs::write(&temp_path, prometheus_metrics(profiles))?;
+    fs::rename(&temp_path, path)
+}
+
+/// The metrics of the profiles in the Prometheus text format.
+fn prometheus_metrics(profiles: &[ProfileHealth]) -> String {
+    type Metric = fn(&ProfileHealth) -> Option<f64>;
+    let metrics: [(&str, &str, Metric); 7] = [
+        (
+            "rackup_health_state",
+            "The state of the profile: 0 OK, 1 warning, 2 critical, 3 unknown.",
+            |p| Some(p.state.exit_code().into()),
+        ),
+        (
+            "rackup_last_success_timestamp_seconds",
+            "When the last successful backup finished.",
+            |p| p.last_success.map(unix_seconds),
+        ),
+        (
+            "rackup_last_run_timestamp_seconds",
+            "When the last backup finished.",
+            |p| p.last_run.as_ref().map(|r| unix_seconds(r.finished)),
+        ),
+        (
+            "rackup_last_run_files_copied",
+            "The number of files copied by the last backup.",
+            |p| p.last_run.as_ref().map(|r| r.copied as f64),
+        ),
+        (
+            "rackup_last_run_bytes_copied",
+            "The number of bytes copied by the last backup.",
+            |p| p.last_run.as_ref().map(|r| r.bytes_copied as f64),
+        ),
+        (
+            "rackup_last_run_files_failed",
+            "The number of files that could not be backed up by the last backup.",
+            |p| p.last_run.as_ref().map(|r| r.failed as f64),
+        ),
+        (
+            "rackup_destination_available_bytes",
+            "The free space on the destination.",
+            |p| p.available_bytes.map(|b| b as f64),
+        ),
+    ];
+
+    let mut text = String::new();
+    for (name, help, value) in metrics {
+        let _ = writeln!(text, "# HELP {} {}", name, help);
+        let _ = writeln!(text, "# TYPE {} gauge", name);
+        for profile in profiles {
+            if let Some(value) = value(profile) {
+                let _ = writeln!(
+                    text,
+                    "{}{{profile=\"{}\"}} {}",
+                    name,
+                    escape_label(&profile.profile),
+                    value
+                );
+            }
+        }
+    }
+    text
+}
+
+fn unix_seconds(time: SystemTime) -> f64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Escapes a label value of the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn run(status: RunStatus, finished: SystemTime, failed: usize) -> RunRecord {
+        RunRecord {
+            id: None,
+            started: finished,
+            finished,
+            profile: Some("documents".to_string()),
+            sources: vec![PathBuf::from("/home/bob")],
+            destination: PathBuf::from("/media/backup"),
+            copied: 3,
+            unchanged: 0,
+            failed,
+            bytes_copied: 1024,
+            errors: vec!["Cannot use the backup directory /media/backup".to_string()],
+            status,
+            exit_code: 0,
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let profile = Profile::new("/home/bob", "/media/backup");
+        let thresholds = Thresholds {
+            max_age: 7 * day,
+            min_free: 1000,
+        };
+        let status = |last_success, last_run| ProfileStatus {
+            label: "documents".to_string(),
+            last_runs: vec![last_run],
+            last_success,
+        };
+
+        let healthy = status(Some(now - day), run(RunStatus::Success, now - day, 0));
+        let health = ProfileHealth::evaluate(&profile, &healthy, Ok(5000), &thresholds, now);
+        assert_eq!(health.state, HealthState::Ok);
+        assert!(health.problems.is_empty());
+
+        let health = ProfileHealth::evaluate(&profile, &healthy, Ok(10), &thresholds, now);
+        assert_eq!(health.state, HealthState::Warning);
+
+        let partial = status(Some(now - day), run(RunStatus::PartialFailure, now, 2));
+        let health = ProfileHealth::evaluate(&profile, &partial, Ok(5000), &thresholds, now);
+        assert_eq!(health.state, HealthState::Warning);
+        assert_eq!(health.problems, vec!["2 files failed in the last run"]);
+
+        let stale = status(Some(now - 8 * day), run(RunStatus::Error, now, 0));
+        let unmounted = Err(io::Error::from(io::ErrorKind::NotFound));
+        let health = ProfileHealth::evaluate(&profile, &stale, unmounted, &thresholds, now);
+        assert_eq!(health.state, HealthState::Critical);
+        assert_eq!(health.problems.len(), 3);
+        assert_eq!(health.problems[0], "last success 8days ago");
+    }
+
+    #[test]
+    fn test_evaluate_running() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let profile = Profile::new("/home/bob", "/media/backup");
+        let thresholds = Thresholds {
+            max_age: 7 * day,
+            min_free: 1000,
+        };
+        let running = RunRecord::unfinished(
+            now,
+            Some("documents"),
+            &[Path::new("/home/bob")],
+            Path::new("/media/backup"),
+            130,
+        );
+
+        // A running backup is not a problem, the run before it is checked instead
+        let status = ProfileStatus {
+            label: "documents".to_string(),
+            last_runs: vec![running.clone(), run(RunStatus::Success, now - day, 0)],
+            last_success: Some(now - day),
+        };
+        let health = ProfileHealth::evaluate(&profile, &status, Ok(5000), &thresholds, now);
+        assert_eq!(health.state, HealthState::Ok);
+        assert_eq!(health.last_run.unwrap().status, RunStatus::Success);
+
+        let status = ProfileStatus {
+            label: "documents".to_string(),
+            last_runs: vec![running, run(RunStatus::PartialFailure, now - day, 2)],
+            last_success: Some(now - 2 * day),
+        };
+        let health = ProfileHealth::evaluate(&profile, &status, Ok(5000), &thresholds, now);
+        assert_eq!(health.problems, vec!["2 files failed in the last run"]);
+    }
+
+    #[test]
+    fn test_prometheus_metrics() {
+        let finished = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let health = ProfileHealth {
+            profile: "my \"documents\"".to_string(),
+            state: HealthState::Warning,
+            problems: Vec::new(),
+            last_success: Some(finished),
+            last_run: Some(run(RunStatus::PartialFailure, finished, 2)),
+            available_bytes: None,
+        };
+
+        let metrics = prometheus_metrics(&[health]);
+        assert!(metrics.contains("# TYPE rackup_last_run_bytes_copied gauge\n"));
+        assert!(metrics.contains(
+            "rackup_last_success_timestamp_seconds{profile=\"my \\\"documents\\\"\"} 1700000000\n"
+        ));
+        assert!(
+            metrics.contains("rackup_last_run_files_failed{profile=\"my \\\"documents\\\"\"} 2\n")
+        );
+        assert!(metrics.contains("rackup_health_state{profile=\"my \\\"documents\\\"\"} 1\n"));
+        assert!(!metrics.contains("rackup_destination_available_bytes{"));
+    }
+}
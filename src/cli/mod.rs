@@ -0,0 +1,37 @@
+//! Parts of the command line interface that are not needed by the library.
+//!
+pub mod api;
+pub mod daemon;
+pub mod explain;
+pub mod health;
+pub mod output;
+pub mod progress;
+pub mod status;
+
+use std::env;
+use std::path::PathBuf;
+
+/// The directory where rackup keeps its local files, such as the history of the runs.
+///
+/// This can be changed with the `RACKUP_DATA_DIR` environment variable.
+pub fn data_dir() -> PathBuf {
+    match env::var_os("RACKUP_DATA_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => dirs::data_local_dir()
+            .unwrap_or_else(env::temp_dir)
+            .join("rackup"),
+    }
+}
+
+/// The local lock of the profile `name`, held while it is backed up.
+pub fn profile_lock_path(name: &str) -> PathBuf {
+    data_dir().join("locks").join(format!("{}.lock", name))
+}
+
+/// The default location of the configuration file.
+pub fn default_config_path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(env::temp_dir)
+        .join("rackup")
+        .join("config.toml")
+}
@@ -0,0 +1,418 @@
+//! Machine-readable output of a backup.
+//!
+//! With `--output ndjson` every event is written to stdout as one JSON object per line,
+//! with `--output json` a single summary object is written when the backup has finished.
+//! Every object has a `schema_version` field, which is incremented whenever a field is
+//! removed or changes its meaning. See the README for the description of the schema.
+//!
+use rackup::{BackupEvent, BackupObserver, BackupReport, Error, Limit};
+use serde::Serialize;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// The version of the JSON output schema.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The format in which the result of a backup is printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable progress
+    Text,
+    /// A JSON summary when the backup has finished
+    Json,
+    /// One JSON object per event
+    Ndjson,
+}
+
+/// A line of the NDJSON output.
+#[derive(Serialize)]
+struct Line<'a> {
+    schema_version: u32,
+    /// Only set for the events of the daemon, which runs several profiles
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<&'a str>,
+    #[serde(flatten)]
+    record: Record<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Record<'a> {
+    ScanStarted {
+        source: String,
+    },
+    ScanFinished {
+        source: String,
+        items: usize,
+    },
+    Included {
+        path: String,
+    },
+    Excluded {
+        path: String,
+        rule: &'a str,
+    },
+    WaitingForLock {
+        path: String,
+        holder: &'a str,
+    },
+    Planned {
+        items: usize,
+        files_to_copy: usize,
+        bytes_to_copy: u64,
+    },
+    Resumed {
+        items: usize,
+    },
+    Copied {
+        path: String,
+        bytes: u64,
+    },
+    DirectoryCreated {
+        path: String,
+    },
+    Skipped {
+        path: String,
+        reason: &'a str,
+    },
+    Failed {
+        path: String,
+        error_kind: String,
+        message: &'a str,
+    },
+    Interrupted {
+        remaining: usize,
+    },
+    Finished {
+        copied: usize,
+        directories_created: usize,
+        unchanged: usize,
+        failed: usize,
+        bytes_copied: u64,
+    },
+}
+
+/// Writes the events of a backup as NDJSON.
+pub struct NdjsonOutput<W: Write> {
+    out: W,
+    profile: Option<String>,
+}
+
+impl<W: Write> NdjsonOutput<W> {
+    pub fn new(out: W) -> Self {
+        NdjsonOutput { out, profile: None }
+    }
+
+    /// Writes the events of the backup of `profile`, with the profile in every line.
+    pub fn for_profile(out: W, profile: &str) -> Self {
+        NdjsonOutput {
+            out,
+            profile: Some(profile.to_string()),
+        }
+    }
+}
+
+impl<W: Write> BackupObserver for NdjsonOutput<W> {
+    fn on_event(&mut self, event: &BackupEvent) {
+        let record = match event {
+            BackupEvent::ScanStarted { source } => Record::ScanStarted {
+                source: path_string(source),
+            },
+            BackupEvent::ScanFinished { source, items } => Record::ScanFinished {
+                source: path_string(source),
+                items: *items,
+            },
+            BackupEvent::Included { path } => Record::Included {
+                path: path_string(path),
+            },
+            BackupEvent::Excluded { path, rule } => Record::Excluded {
+                path: path_string(path),
+                rule,
+            },
+            BackupEvent::WaitingForLock { path, holder } => Record::WaitingForLock {
+                path: path_string(path),
+                holder,
+            },
+            BackupEvent::Planned {
+                items,
+                files_to_copy,
+                bytes_to_copy,
+            } => Record::Planned {
+                items: *items,
+                files_to_copy: *files_to_copy,
+                bytes_to_copy: *bytes_to_copy,
+            },
+            BackupEvent::Resumed { items } => Record::Resumed { items: *items },
+            BackupEvent::Interrupted { remaining } => Record::Interrupted {
+                remaining: *remaining,
+            },
+            BackupEvent::CopyFinished { source, bytes } => Record::Copied {
+                path: path_string(source),
+                bytes: *bytes,
+            },
+            BackupEvent::DirectoryCreated { source } => Record::DirectoryCreated {
+                path: path_string(source),
+            },
+            BackupEvent::Unchanged { source } => Record::Skipped {
+                path: path_string(source),
+                reason: "unchanged",
+            },
+            BackupEvent::Error {
+                path,
+                kind,
+                message,
+            } => Record::Failed {
+                path: path_string(path),
+                error_kind: error_kind_name(*kind),
+                message,
+            },
+            BackupEvent::RunFinished {
+                copied,
+                directories_created,
+                unchanged,
+                failed,
+                bytes_copied,
+            } => Record::Finished {
+                copied: *copied,
+                directories_created: *directories_created,
+                unchanged: *unchanged,
+                failed: *failed,
+                bytes_copied: *bytes_copied,
+            },
+            // The progress of single files is left out to keep the output small
+            _ => return,
+        };
+
+        let line = Line {
+            schema_version: SCHEMA_VERSION,
+            profile: self.profile.as_deref(),
+            record,
+        };
+        // There is nowhere to report a failing stdout to, so errors are ignored
+        if serde_json::to_writer(&mut self.out, &line).is_ok() {
+            let _ = writeln!(self.out);
+            let _ = self.out.flush();
+        }
+    }
+}
+
+/// The summary written with `--output json`.
+#[derive(Serialize)]
+pub struct Summary {
+    schema_version: u32,
+    /// `success`, `partial_failure`, `incomplete` or `error`
+    status: &'static str,
+    sources: Vec<String>,
+    destination: String,
+    duration_secs: f64,
+    copied: usize,
+    directories_created: usize,
+    unchanged: usize,
+    failed: usize,
+    bytes_copied: u64,
+    failures: Vec<Failure>,
+    /// Files left out because they are over the size or age limit
+    limited: Vec<Limited>,
+    /// Set if the backup could not run at all
+    error: Option<RunError>,
+}
+
+#[derive(Serialize)]
+struct Failure {
+    path: String,
+    error_kind: String,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct Limited {
+    path: String,
+    size: u64,
+    /// `size` or `age`
+    limit: &'static str,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct RunError {
+    /// `config`, `walk`, `destination`, `overlap`, `unverified_destination`,
+    /// `insufficient_space`, `locked`, `lock`, `watch` or `history`
+    kind: &'static str,
+    message: String,
+}
+
+impl Summary {
+    fn new(sources: &[&Path], destination: &Path, duration: Duration) -> Self {
+        Summary {
+            schema_version: SCHEMA_VERSION,
+            status: "success",
+            sources: sources.iter().map(|s| path_string(s)).collect(),
+            destination: path_string(destination),
+            duration_secs: duration.as_secs_f64(),
+            copied: 0,
+            directories_created: 0,
+            unchanged: 0,
+            failed: 0,
+            bytes_copied: 0,
+            failures: Vec::new(),
+            limited: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// The summary of a backup that has run.
+    pub fn from_report(
+        report: &BackupReport,
+        sources: &[&Path],
+        destination: &Path,
+        duration: Duration,
+    ) -> Self {
+        Summary {
+            status: if report.interrupted {
+                "incomplete"
+            } else if report.is_success() {
+                "success"
+            } else {
+                "partial_failure"
+            },
+            copied: report.copied(),
+            directories_created: report.directories_created(),
+            unchanged: report.unchanged(),
+            failed: report.failed(),
+            bytes_copied: report.bytes_copied(),
+            failures: report
+                .errors()
+                .map(|(file, err)| Failure {
+                    path: path_string(&file.source),
+                    error_kind: error_kind_name(err.kind()),
+                    message: err.to_string(),
+                })
+                .collect(),
+            limited: report
+                .limited
+                .iter()
+                .map(|file| Limited {
+                    path: path_string(&file.path),
+                    size: file.size,
+                    limit: match file.limit {
+                        Limit::Size(_) => "size",
+                        Limit::Age(_) => "age",
+                    },
+                    message: file.to_string(),
+                })
+                .collect(),
+            ..Summary::new(sources, destination, duration)
+        }
+    }
+
+    /// The summary of a backup that could not run.
+    pub fn from_error(
+        err: &Error,
+        sources: &[&Path],
+        destination: &Path,
+        duration: Duration,
+    ) -> Self {
+        Summary {
+            status: "error",
+            error: Some(RunError {
+                kind: match err {
+                    Error::Config(_) => "config",
+                    Error::Walk { .. } => "walk",
+                    Error::Watch { .. } => "watch",
+                    Error::Destination { .. } => "destination",
+                    Error::Overlap { .. } => "overlap",
+                    Error::UnverifiedDestination { .. } => "unverified_destination",
+                    Error::InsufficientSpace { .. } => "insufficient_space",
+                    Error::Locked { .. } => "locked",
+                    Error::Lock { .. } => "lock",
+                    Error::History { .. } => "history",
+                },
+                message: err.to_string(),
+            }),
+            ..Summary::new(sources, destination, duration)
+        }
+    }
+
+    /// Writes the summary to stdout.
+    pub fn print(&self) -> io::Result<()> {
+        let mut stdout = io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, self)?;
+        writeln!(stdout)
+    }
+}
+
+/// Serializes an optional time as an RFC 3339 string.
+pub fn serialize_time<S: serde::Serializer>(
+    time: &Option<SystemTime>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match time {
+        Some(time) => {
+            serializer.serialize_str(&humantime::format_rfc3339_seconds(*time).to_string())
+        }
+        None => serializer.serialize_none(),
+    }
+}
+
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().to_string()
+}
+
+/// The name of an I/O error kind in snake case, e.g. `permission_denied`.
+fn error_kind_name(kind: io::ErrorKind) -> String {
+    let mut name = String::new();
+    for (i, c) in format!("{:?}", kind).chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                name.push('_');
+            }
+            name.extend(c.to_lowercase());
+        } else {
+            name.push(c);
+        }
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_ndjson_lines() {
+        let mut output = NdjsonOutput::new(Vec::new());
+        output.on_event(&BackupEvent::Excluded {
+            path: PathBuf::from("/home/bob/program.exe"),
+            rule: "noexe",
+        });
+        output.on_event(&BackupEvent::CopyProgress {
+            source: PathBuf::from("/home/bob/file.txt"),
+            bytes_copied: 5,
+            size: 10,
+        });
+        output.on_event(&BackupEvent::Error {
+            path: PathBuf::from("/home/bob/secret.txt"),
+            kind: io::ErrorKind::PermissionDenied,
+            message: "Error copying /home/bob/secret.txt".to_string(),
+        });
+
+        let lines = String::from_utf8(output.out).unwrap();
+        let lines: Vec<&str> = lines.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"schema_version":1,"event":"excluded","path":"/home/bob/program.exe","rule":"noexe"}"#,
+                r#"{"schema_version":1,"event":"failed","path":"/home/bob/secret.txt","error_kind":"permission_denied","message":"Error copying /home/bob/secret.txt"}"#,
+            ]
+        );
+
+        let mut output = NdjsonOutput::for_profile(Vec::new(), "documents");
+        output.on_event(&BackupEvent::Resumed { items: 3 });
+        assert_eq!(
+            String::from_utf8(output.out).unwrap(),
+            "{\"schema_version\":1,\"profile\":\"documents\",\"event\":\"resumed\",\"items\":3}\n"
+        );
+    }
+}
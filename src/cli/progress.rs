@@ -0,0 +1,256 @@
+//! Display of the progress of a backup in the terminal.
+//!
+//! When stdout is a terminal a progress bar is shown, otherwise a status line is
+//! printed every [`LOG_INTERVAL`].
+//!
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+use rackup::{BackupEvent, BackupObserver};
+use std::io::{self, IsTerminal};
+use std::time::{Duration, Instant};
+
+/// How often the status is printed when stdout is not a terminal.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How much is printed while backing up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only errors and the files that could not be backed up.
+    Quiet,
+    /// The progress and a summary at the end.
+    Normal,
+    /// Also every file copied and every item excluded by a rule.
+    Verbose,
+}
+
+/// Prints the progress of the backup to the terminal.
+pub struct TerminalProgress {
+    verbosity: Verbosity,
+    is_terminal: bool,
+    bar: Option<ProgressBar>,
+    started: Instant,
+    last_log: Instant,
+    files_to_copy: usize,
+    bytes_to_copy: u64,
+    files_done: usize,
+    /// The bytes of the files that have been copied completely.
+    bytes_done: u64,
+    /// The bytes copied so far of the file being copied.
+    current_bytes: u64,
+}
+
+impl TerminalProgress {
+    pub fn new(verbosity: Verbosity) -> Self {
+        TerminalProgress {
+            verbosity,
+            is_terminal: io::stdout().is_terminal(),
+            bar: None,
+            started: Instant::now(),
+            last_log: Instant::now(),
+            files_to_copy: 0,
+            bytes_to_copy: 0,
+            files_done: 0,
+            bytes_done: 0,
+            current_bytes: 0,
+        }
+    }
+
+    /// Prints a line to stdout without garbling the progress bar.
+    fn print(&self, line: String) {
+        match &self.bar {
+            Some(bar) => bar.println(line),
+            None => println!("{}", line),
+        }
+    }
+
+    /// Prints a line to stderr without garbling the progress bar.
+    fn print_error(&self, line: &str) {
+        match &self.bar {
+            Some(bar) => bar.suspend(|| eprintln!("{}", line)),
+            None => eprintln!("{}", line),
+        }
+    }
+
+    fn update(&mut self) {
+        let bytes = self.bytes_done + self.current_bytes;
+
+        if let Some(bar) = &self.bar {
+            bar.set_position(bytes);
+            bar.set_prefix(format!("{}/{}", self.files_done, self.files_to_copy));
+        } else if self.verbosity > Verbosity::Quiet && self.last_log.elapsed() >= LOG_INTERVAL {
+            self.last_log = Instant::now();
+            println!(
+                "{}",
+                status_line(
+                    self.files_done,
+                    self.files_to_copy,
+                    bytes,
+                    self.bytes_to_copy,
+                    self.started.elapsed()
+                )
+            );
+        }
+    }
+}
+
+impl BackupObserver for TerminalProgress {
+    fn on_event(&mut self, event: &BackupEvent) {
+        match event {
+            BackupEvent::ScanStarted { source } if self.verbosity == Verbosity::Verbose => {
+                self.print(format!("Scanning {} ...", source.to_string_lossy()))
+            }
+            BackupEvent::Excluded { path, rule } if self.verbosity == Verbosity::Verbose => self
+                .print(format!(
+                    "Excluded {} (rule {})",
+                    path.to_string_lossy(),
+                    rule
+                )),
+            BackupEvent::WaitingForLock { path, holder } if self.verbosity > Verbosity::Quiet => {
+                self.print(format!(
+                    "Waiting for {} locked by {} ...",
+                    path.to_string_lossy(),
+                    holder
+                ))
+            }
+            BackupEvent::Planned {
+                files_to_copy,
+                bytes_to_copy,
+                ..
+            } => {
+                // The same progress may be used for several runs, e.g. when watching
+                self.files_to_copy = *files_to_copy;
+                self.bytes_to_copy = *bytes_to_copy;
+                self.files_done = 0;
+                self.bytes_done = 0;
+                self.started = Instant::now();
+                self.last_log = Instant::now();
+
+                if self.verbosity == Verbosity::Quiet {
+                    return;
+                }
+                if self.is_terminal {
+                    let bar = ProgressBar::new(*bytes_to_copy);
+                    bar.set_style(
+                        ProgressStyle::with_template(
+                            "[{elapsed_precise}] [{wide_bar}] {prefix} files, {bytes}/{total_bytes} ({binary_bytes_per_sec}, ETA {eta})\n{wide_msg}",
+                        )
+                        .unwrap_or_else(|_| ProgressStyle::default_bar()),
+                    );
+                    bar.set_prefix(format!("0/{}", files_to_copy));
+                    self.bar = Some(bar);
+                } else {
+                    println!(
+                        "{} files ({}) to back up",
+                        files_to_copy,
+                        HumanBytes(*bytes_to_copy)
+                    );
+                }
+            }
+            BackupEvent::Resumed { items } if self.verbosity > Verbosity::Quiet => self.print(
+                format!("Resuming the interrupted backup, {} items left to copy", items),
+            ),
+            BackupEvent::CopyStarted { source, .. } => {
+                self.current_bytes = 0;
+                if let Some(bar) = &self.bar {
+                    bar.set_message(source.to_string_lossy().to_string());
+                }
+            }
+            BackupEvent::CopyProgress { bytes_copied, .. } => {
+                self.current_bytes = *bytes_copied;
+                self.update();
+            }
+            BackupEvent::CopyFinished { source, bytes } => {
+                self.files_done += 1;
+                self.bytes_done += bytes;
+                self.current_bytes = 0;
+                if self.verbosity == Verbosity::Verbose {
+                    self.print(format!(
+                        "File {} copied successfully.",
+                        source.to_string_lossy()
+                    ));
+                }
+                self.update();
+            }
+            BackupEvent::DirectoryCreated { source } if self.verbosity == Verbosity::Verbose => {
+                self.print(format!(
+                    "Directory {} created successfully.",
+                    source.to_string_lossy()
+                ))
+            }
+            BackupEvent::Error { message, .. } => self.print_error(message),
+            BackupEvent::Interrupted { remaining } if self.verbosity > Verbosity::Quiet => {
+                self.print(format!(
+                    "Interrupted, {} items have not been backed up. Run the backup again with --resume to continue.",
+                    remaining
+                ))
+            }
+            BackupEvent::RunFinished {
+                copied,
+                unchanged,
+                failed,
+                bytes_copied,
+                ..
+            } => {
+                if let Some(bar) = self.bar.take() {
+                    bar.finish_and_clear();
+                }
+                if self.verbosity > Verbosity::Quiet {
+                    println!(
+                        "Backed up {} files ({}) in {}, {} unchanged, {} failed.",
+                        copied,
+                        HumanBytes(*bytes_copied),
+                        HumanDuration(self.started.elapsed()),
+                        unchanged,
+                        failed
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The status printed periodically when stdout is not a terminal.
+fn status_line(
+    files_done: usize,
+    files_to_copy: usize,
+    bytes_done: u64,
+    bytes_to_copy: u64,
+    elapsed: Duration,
+) -> String {
+    let throughput = bytes_done as f64 / elapsed.as_secs_f64().max(0.001);
+
+    let eta = if throughput > 0.0 {
+        let remaining = bytes_to_copy.saturating_sub(bytes_done) as f64;
+        HumanDuration(Duration::from_secs_f64(remaining / throughput)).to_string()
+    } else {
+        "unknown".to_string()
+    };
+
+    format!(
+        "{}/{} files, {}/{} ({}/s), ETA {}",
+        files_done,
+        files_to_copy,
+        HumanBytes(bytes_done),
+        HumanBytes(bytes_to_copy),
+        HumanBytes(throughput as u64),
+        eta
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_line() {
+        let line = status_line(2, 4, 2048, 4096, Duration::from_secs(2));
+        assert_eq!(
+            line,
+            "2/4 files, 2.00 KiB/4.00 KiB (1.00 KiB/s), ETA 2 seconds"
+        );
+
+        // Nothing copied yet
+        let line = status_line(0, 4, 0, 4096, Duration::from_secs(0));
+        assert_eq!(line, "0/4 files, 0 B/4.00 KiB (0 B/s), ETA unknown");
+    }
+}
@@ -0,0 +1,217 @@
+//! The `rackup status` command.
+//!
+use crate::cli::daemon::{from_unix_seconds, DaemonStatus};
+use crate::cli::output::{serialize_time, OutputFormat, SCHEMA_VERSION};
+use humantime::{format_duration, format_rfc3339_seconds};
+use indicatif::HumanBytes;
+use rackup::config::Config;
+use rackup::history::{History, ProfileStatus, RunRecord, RunStatus, METADATA_DIR};
+use rackup::lock::{RunLock, LOCK_FILE};
+use serde::Serialize;
+use std::time::{Duration, SystemTime};
+
+/// The status of a profile printed with `--output json`.
+#[derive(Serialize)]
+struct JsonStatus<'a> {
+    schema_version: u32,
+    profile: &'a str,
+    #[serde(serialize_with = "serialize_time")]
+    last_success: Option<SystemTime>,
+    stale: bool,
+    last_runs: &'a [rackup::history::RunRecord],
+    /// Only set while the daemon is running.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    daemon: Option<JsonDaemon<'a>>,
+}
+
+/// The state of a profile in the daemon, printed with `--output json`.
+#[derive(Serialize)]
+struct JsonDaemon<'a> {
+    schedule: Option<&'a str>,
+    #[serde(serialize_with = "serialize_time")]
+    next_run: Option<SystemTime>,
+    #[serde(serialize_with = "serialize_time")]
+    running_since: Option<SystemTime>,
+}
+
+/// The status of all profiles in the history and the configuration, with the profiles
+/// that have not been backed up in the configuration added without runs. Runs that are
+/// still recorded as running, but whose lock is not held anymore, have been killed and are
+/// incomplete.
+pub fn profile_statuses(
+    history: &History,
+    config: &Config,
+    runs: usize,
+) -> rackup::Result<Vec<ProfileStatus>> {
+    let mut statuses = history.status(runs)?;
+    for run in statuses.iter_mut().flat_map(|s| &mut s.last_runs) {
+        if run.status == RunStatus::Running && !is_running(run) {
+            run.status = RunStatus::Incomplete;
+        }
+    }
+    for name in config.profiles.keys() {
+        if !statuses.iter().any(|s| &s.label == name) {
+            statuses.push(ProfileStatus {
+                label: name.clone(),
+                last_runs: Vec::new(),
+                last_success: None,
+            });
+        }
+    }
+    statuses.sort_by(|a, b| a.label.cmp(&b.label));
+    Ok(statuses)
+}
+
+/// `true` if the lock of the run is held: the local lock of its profile, or the lock of its
+/// destination for a backup given on the command line.
+fn is_running(run: &RunRecord) -> bool {
+    let lock = match &run.profile {
+        Some(name) => crate::cli::profile_lock_path(name),
+        None => run.destination.join(METADATA_DIR).join(LOCK_FILE),
+    };
+    RunLock::is_held(&lock)
+}
+
+/// Prints the last runs of every profile, highlighting the profiles whose last successful
+/// backup is older than `max_age`. With the state of a running `daemon`, it also prints when
+/// the scheduled profiles run next.
+pub fn print_status(
+    history: &History,
+    config: &Config,
+    daemon: Option<&DaemonStatus>,
+    runs: usize,
+    max_age: Duration,
+    output: OutputFormat,
+) -> rackup::Result<()> {
+    let statuses = profile_statuses(history, config, runs)?;
+    let now = SystemTime::now();
+    let scheduled =
+        |name: &str| daemon.and_then(|daemon| daemon.profiles.iter().find(|p| p.name == name));
+
+    if output != OutputFormat::Text {
+        let json: Vec<JsonStatus> = statuses
+            .iter()
+            .map(|status| JsonStatus {
+                schema_version: SCHEMA_VERSION,
+                profile: &status.label,
+                last_success: status.last_success,
+                stale: status.is_stale(max_age, now),
+                last_runs: &status.last_runs,
+                daemon: scheduled(&status.label).map(|scheduled| JsonDaemon {
+                    schedule: scheduled.schedule.as_deref(),
+                    next_run: scheduled.next_run.map(from_unix_seconds),
+                    running_since: scheduled.running_since.map(from_unix_seconds),
+                }),
+            })
+            .collect();
+        // Serializing the records cannot fail
+        let json = serde_json::to_string_pretty(&json).unwrap_or_default();
+        println!("{}", json);
+        return Ok(());
+    }
+
+    if let Some(daemon) = daemon {
+        println!(
+            "Daemon running since {} (pid {})",
+            format_rfc3339_seconds(from_unix_seconds(daemon.started)),
+            daemon.pid
+        );
+    }
+    if statuses.is_empty() {
+        println!("No backups have been run yet.");
+        return Ok(());
+    }
+
+    for status in &statuses {
+        let stale = status.is_stale(max_age, now);
+        let last_success = match status.last_success {
+            Some(time) => format!("last success {} ago", format_age(now, time)),
+            None => "never backed up successfully".to_string(),
+        };
+        println!(
+            "{} {}: {}{}",
+            if stale { "!" } else { " " },
+            status.label,
+            last_success,
+            if stale && status.last_success.is_some() {
+                format!(" (older than {})", format_duration(max_age))
+            } else {
+                String::new()
+            }
+        );
+
+        if let Some(scheduled) = scheduled(&status.label) {
+            let schedule = match &scheduled.schedule {
+                Some(schedule) => format!(" ({})", schedule),
+                None => String::new(),
+            };
+            match (scheduled.running_since, scheduled.next_run) {
+                (Some(since), _) => println!(
+                    "    running since {}",
+                    format_rfc3339_seconds(from_unix_seconds(since))
+                ),
+                (None, Some(next_run)) => println!(
+                    "    next run at {}{}",
+                    format_rfc3339_seconds(from_unix_seconds(next_run)),
+                    schedule
+                ),
+                (None, None) if scheduled.schedule.is_some() => {
+                    println!("    no more runs{}", schedule)
+                }
+                (None, None) => {}
+            }
+        }
+
+        for run in &status.last_runs {
+            println!(
+                "    {}  {:<15}  {} copied ({}), {} unchanged, {} failed",
+                format_rfc3339_seconds(run.finished),
+                match run.status {
+                    RunStatus::Success => "success",
+                    RunStatus::PartialFailure => "partial failure",
+                    RunStatus::Incomplete => "incomplete",
+                    RunStatus::Error => "error",
+                    RunStatus::Running => "running",
+                },
+                run.copied,
+                HumanBytes(run.bytes_copied),
+                run.unchanged,
+                run.failed
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// How long ago `time` was, rounded to minutes.
+fn format_age(now: SystemTime, time: SystemTime) -> String {
+    let age = now.duration_since(time).unwrap_or_default();
+    let minutes = Duration::from_secs(age.as_secs() / 60 * 60);
+    if minutes.is_zero() {
+        "less than a minute".to_string()
+    } else {
+        format_duration(minutes).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_age() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            format_age(now, now - Duration::from_secs(30)),
+            "less than a minute"
+        );
+        assert_eq!(
+            format_age(
+                now,
+                now - Duration::from_secs(2 * 24 * 60 * 60 + 3 * 60 * 60 + 59)
+            ),
+            "2days 3h"
+        );
+    }
+}
@@ -0,0 +1,940 @@
+//! Configuration of the named backup profiles.
+//!
+//! The configuration is a TOML file with a table for every profile:
+//!
+//! ```toml
+//! [profiles.documents]
+//! source = "/home/bob/Documents"
+//! destination = "/media/backup"
+//! ```
+//!
+//! Further directories can be backed up in the same run with `sources`, either as paths or as
+//! tables with rules of their own (see [`SourceConfig`]):
+//!
+//! ```toml
+//! [profiles.projects]
+//! source = "/home/bob/projects"
+//! destination = "/media/backup"
+//! sources = [
+//!     "/home/bob/notes",
+//!     { path = "/home/bob/projects/client-a", rules = [{ action = "exclude", extensions = ["psd"] }] },
+//! ]
+//! ```
+//!
+//! The destination of a profile has to be initialized with `rackup init` (see
+//! [`DestinationMarker`](crate::marker::DestinationMarker)) unless `require_marker = false`.
+//!
+//! Profiles with a [`schedule`](crate::schedule) are backed up by `rackup daemon`, which is
+//! configured in the `[daemon]` table:
+//!
+//! ```toml
+//! [daemon]
+//! max_concurrent = 2
+//! api = "127.0.0.1:8435"
+//! ```
+//!
+use crate::backup::{Backup, BackupBuilder};
+use crate::error::{Error, Result};
+use crate::explain::{Explanation, RuleOutcome};
+use crate::filter::{filter_rule, first_match, include_rule, FilterRule, IncludePattern};
+use crate::marker::DestinationCheck;
+use crate::preset::{cachedir_rule, preset_rule, Preset};
+use crate::rules::{executable_rule, gitignore_rule, marker_rule, ExecutableKind};
+use crate::schedule::Schedule;
+use crate::script::{script_rule, RuleScript, DEFAULT_TIMEOUT};
+use rebackup::WalkerRule;
+use serde::de::{self, MapAccess, Visitor};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+/// The configuration read from the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The profiles by name.
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    #[serde(default)]
+    pub daemon: DaemonConfig,
+}
+
+/// Configuration of `rackup daemon`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// How many profiles are backed up at the same time. Defaults to 1.
+    #[serde(default = "default_max_concurrent")]
+    pub max_concurrent: usize,
+    /// The Unix socket on which the daemon reports its state. Defaults to `rackup.sock` in
+    /// the local data directory.
+    pub socket: Option<PathBuf>,
+    /// The loopback address on which the daemon serves its HTTP API, `None` to not serve it.
+    pub api: Option<SocketAddr>,
+    /// The file holding the token clients of the API have to send. Defaults to `api-token`
+    /// in the local data directory.
+    pub api_token_file: Option<PathBuf>,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            max_concurrent: default_max_concurrent(),
+            socket: None,
+            api: None,
+            api_token_file: None,
+        }
+    }
+}
+
+fn default_max_concurrent() -> usize {
+    1
+}
+
+/// A named backup of a source directory to a destination.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// The directory to be backed up.
+    pub source: PathBuf,
+    /// Further directories backed up in the same run. They may be inside `source` or inside
+    /// each other, see [`BackupBuilder::source`].
+    #[serde(default)]
+    pub sources: Vec<SourceConfig>,
+    /// The backup directory or drive.
+    pub destination: PathBuf,
+    /// Leave the destination out of the backup if it is inside the source, see
+    /// [`BackupBuilder::exclude_destination`].
+    #[serde(default)]
+    pub exclude_destination: bool,
+    /// Only back up to a destination initialized with `rackup init`. Defaults to `true`.
+    #[serde(default = "default_require_marker")]
+    pub require_marker: bool,
+    /// The id of the destination marker, to only back up to one particular drive.
+    pub destination_id: Option<Uuid>,
+    /// Only back up if the destination is a mount point. Defaults to `false`.
+    #[serde(default)]
+    pub require_mount_point: bool,
+    /// The space to keep free on the destination, in bytes or as a size such as `"10GB"`.
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub reserve: u64,
+    /// Leave out files larger than this, in bytes or as a size such as `"2GB"`. The files left
+    /// out are listed after the backup.
+    #[serde(default, deserialize_with = "deserialize_optional_size")]
+    pub max_file_size: Option<u64>,
+    /// Leave out files not modified for longer than this, such as `"5years"`. The files left
+    /// out are listed after the backup.
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub max_file_age: Option<Duration>,
+    /// When `rackup daemon` backs up the profile, `None` if it is only backed up by hand.
+    pub schedule: Option<Schedule>,
+    /// The kinds of executable files that are not backed up. Defaults to programs, but not
+    /// libraries.
+    #[serde(default = "default_exclude_executables")]
+    pub exclude_executables: Vec<ExecutableKind>,
+    /// The paths relative to the source the backup is restricted to, all of them if it is
+    /// empty.
+    #[serde(default)]
+    pub include: Vec<IncludePattern>,
+    /// The include and exclude rules of the profile, see [`filter`](crate::filter).
+    #[serde(default)]
+    pub rules: Vec<FilterRule>,
+    /// The rule scripts of the profile, see [`script`](crate::script). Relative paths are
+    /// relative to the configuration file. They are read by [`Profile::load_scripts`].
+    #[serde(default)]
+    pub scripts: Vec<PathBuf>,
+    /// How long a rule script may take to decide about one item. Defaults to 1 second.
+    #[serde(
+        default = "default_script_timeout",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub script_timeout: Duration,
+    #[serde(skip)]
+    loaded_scripts: Vec<RuleScript>,
+    /// The presets of regenerable items that are not backed up, see [`preset`](crate::preset).
+    #[serde(default)]
+    pub presets: Vec<Preset>,
+    /// Do not back up directories tagged with a `CACHEDIR.TAG` file. Defaults to `false`.
+    #[serde(default)]
+    pub exclude_caches: bool,
+}
+
+/// A further directory backed up by a [`Profile`], given as a path or as a table.
+///
+/// It is backed up with the rules of the profile, after its own `rules`, and with its own
+/// `presets` in addition to the presets of the profile. The `include` paths of the profile
+/// only apply to its `source`.
+// The derived implementation is used for tables by the one accepting paths as well
+#[derive(Debug, Clone, Deserialize)]
+#[serde(remote = "Self", deny_unknown_fields)]
+pub struct SourceConfig {
+    /// The directory to be backed up.
+    pub path: PathBuf,
+    /// The paths relative to this directory the backup of it is restricted to, all of them
+    /// if it is empty.
+    #[serde(default)]
+    pub include: Vec<IncludePattern>,
+    /// The include and exclude rules of this directory, see [`filter`](crate::filter).
+    #[serde(default)]
+    pub rules: Vec<FilterRule>,
+    /// The presets of this directory, see [`preset`](crate::preset).
+    #[serde(default)]
+    pub presets: Vec<Preset>,
+}
+
+impl SourceConfig {
+    /// A source at `path` without rules of its own.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        SourceConfig {
+            path: path.into(),
+            include: Vec::new(),
+            rules: Vec::new(),
+            presets: Vec::new(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SourceConfig {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        struct SourceVisitor;
+
+        impl<'de> Visitor<'de> for SourceVisitor {
+            type Value = SourceConfig;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a path or a table with a path")
+            }
+
+            fn visit_str<E: de::Error>(self, path: &str) -> std::result::Result<SourceConfig, E> {
+                Ok(SourceConfig::new(path))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(
+                self,
+                map: A,
+            ) -> std::result::Result<SourceConfig, A::Error> {
+                SourceConfig::deserialize(de::value::MapAccessDeserializer::new(map))
+            }
+        }
+
+        deserializer.deserialize_any(SourceVisitor)
+    }
+}
+
+fn default_require_marker() -> bool {
+    true
+}
+
+fn default_exclude_executables() -> Vec<ExecutableKind> {
+    ExecutableKind::DEFAULT_EXCLUDED.to_vec()
+}
+
+fn default_script_timeout() -> Duration {
+    DEFAULT_TIMEOUT
+}
+
+impl Config {
+    /// Reads the configuration from `path`. A missing file gives an empty configuration.
+    pub fn load(path: &Path) -> Result<Config> {
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                let base = path.parent().unwrap_or(Path::new(""));
+                Config::parse_in(&content, base)
+                    .map_err(|err| Error::Config(format!("{} in {}", err, path.to_string_lossy())))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(err) => Err(Error::Config(format!(
+                "Cannot read {}: {}",
+                path.to_string_lossy(),
+                err
+            ))),
+        }
+    }
+
+    /// Parses the configuration from the contents of a configuration file. Relative paths
+    /// of rule scripts are relative to the current directory.
+    pub fn parse(content: &str) -> Result<Config> {
+        Config::parse_in(content, Path::new(""))
+    }
+
+    /// Parses the configuration of a configuration file in the `base` directory.
+    fn parse_in(content: &str, base: &Path) -> Result<Config> {
+        let mut config: Config =
+            toml::from_str(content).map_err(|err| Error::Config(err.message().to_string()))?;
+        // The API is only meant for local clients
+        if let Some(api) = config.daemon.api.filter(|api| !api.ip().is_loopback()) {
+            return Err(Error::Config(format!(
+                "The API address {} is not a loopback address",
+                api
+            )));
+        }
+        for (name, profile) in &mut config.profiles {
+            profile
+                .load_scripts(base)
+                .map_err(|err| Error::Config(format!("{} in profile {}", err, name)))?;
+        }
+        Ok(config)
+    }
+
+    /// The profile called `name`.
+    pub fn profile(&self, name: &str) -> Result<&Profile> {
+        self.profiles
+            .get(name)
+            .ok_or_else(|| Error::Config(format!("Unknown profile {}", name)))
+    }
+}
+
+impl Profile {
+    /// A profile backing up `source` to `destination`.
+    pub fn new(source: impl Into<PathBuf>, destination: impl Into<PathBuf>) -> Self {
+        Profile {
+            source: source.into(),
+            sources: Vec::new(),
+            destination: destination.into(),
+            exclude_destination: false,
+            require_marker: true,
+            destination_id: None,
+            require_mount_point: false,
+            reserve: 0,
+            max_file_size: None,
+            max_file_age: None,
+            schedule: None,
+            exclude_executables: default_exclude_executables(),
+            include: Vec::new(),
+            rules: Vec::new(),
+            scripts: Vec::new(),
+            script_timeout: DEFAULT_TIMEOUT,
+            loaded_scripts: Vec::new(),
+            presets: Vec::new(),
+            exclude_caches: false,
+        }
+    }
+
+    /// Reads and compiles the rule scripts of the profile, resolving relative paths against
+    /// `base`. Scripts only take effect once they are loaded.
+    pub fn load_scripts(&mut self, base: &Path) -> Result<()> {
+        self.loaded_scripts = self
+            .scripts
+            .iter()
+            .map(|path| RuleScript::load(&base.join(path)))
+            .collect::<std::result::Result<_, _>>()
+            .map_err(Error::Config)?;
+        Ok(())
+    }
+
+    /// All the directories backed up by this profile, `source` first.
+    pub fn source_paths(&self) -> Vec<&Path> {
+        std::iter::once(self.source.as_path())
+            .chain(self.sources.iter().map(|source| source.path.as_path()))
+            .collect()
+    }
+
+    /// The included paths, rules and presets of `source`, or of the main source for `None`.
+    fn source_rules<'a>(
+        &'a self,
+        source: Option<&'a SourceConfig>,
+    ) -> (&'a [IncludePattern], Vec<FilterRule>, Vec<Preset>) {
+        match source {
+            Some(source) => (
+                &source.include,
+                source.rules.iter().chain(&self.rules).cloned().collect(),
+                self.presets
+                    .iter()
+                    .chain(&source.presets)
+                    .copied()
+                    .collect(),
+            ),
+            None => (&self.include, self.rules.clone(), self.presets.clone()),
+        }
+    }
+
+    /// The walker rules of `source`, or of the main source for `None`.
+    fn walker_rules(&self, source: Option<&SourceConfig>) -> Vec<WalkerRule> {
+        let (include, filter_rules, presets) = self.source_rules(source);
+
+        // The included paths decide what is walked at all. Within them the markers in the
+        // source come first, then the rules of the profile, so that they can override the
+        // built-in ones.
+        let mut rules = Vec::new();
+        if !include.is_empty() {
+            rules.push(include_rule(include.to_vec()));
+        }
+        rules.push(marker_rule());
+        if !filter_rules.is_empty() {
+            rules.push(filter_rule(filter_rules));
+        }
+        for script in &self.loaded_scripts {
+            rules.push(script_rule(script.clone(), self.script_timeout));
+        }
+        if !presets.is_empty() {
+            rules.push(preset_rule(presets));
+        }
+        if self.exclude_caches {
+            rules.push(cachedir_rule());
+        }
+        rules.push(gitignore_rule());
+        rules.push(executable_rule(self.exclude_executables.clone()));
+        rules
+    }
+
+    /// A builder for the backup of this profile.
+    pub fn backup_builder(&self) -> BackupBuilder {
+        let mut builder = Backup::builder()
+            .source(&self.source)
+            .rules(self.walker_rules(None));
+        for source in &self.sources {
+            builder = builder.source_with_rules(&source.path, self.walker_rules(Some(source)));
+        }
+        builder = builder
+            .destination(&self.destination)
+            .exclude_destination(self.exclude_destination)
+            .verify_destination(self.destination_check())
+            .reserve(self.reserve);
+        if let Some(size) = self.max_file_size {
+            builder = builder.max_file_size(size);
+        }
+        if let Some(age) = self.max_file_age {
+            builder = builder.max_file_age(age);
+        }
+        builder
+    }
+
+    /// Explains whether the item at `path` is backed up by this profile, see
+    /// [`Backup::explain`]. For the rules and presets of the profile it names the rule or
+    /// preset that decides.
+    pub fn explain(&self, path: &Path) -> Result<Explanation> {
+        let mut explanation = self.backup_builder().build()?.explain(path)?;
+        // The source the item is in, `None` for the main source, which comes first
+        let is_source = |path: &Path| fs::canonicalize(path).is_ok_and(|p| p == explanation.source);
+        let source = if is_source(&self.source) {
+            None
+        } else {
+            self.sources.iter().find(|source| is_source(&source.path))
+        };
+        let (include, rules, presets) = self.source_rules(source);
+        let source_rule_count = source.map_or(0, |source| source.rules.len());
+        let now = SystemTime::now();
+        for item in &mut explanation.items {
+            for evaluation in &mut item.rules {
+                if !matches!(
+                    evaluation.outcome,
+                    RuleOutcome::IncludedAbsolute | RuleOutcome::Excluded
+                ) {
+                    continue;
+                }
+                evaluation.detail = match evaluation.rule {
+                    "config" => first_match(&rules, &item.path, &explanation.source, now)
+                        .ok()
+                        .flatten()
+                        .map(|(i, rule)| match source {
+                            Some(source) if i < source_rule_count => format!(
+                                "Rule {} of {}: {}",
+                                i + 1,
+                                source.path.to_string_lossy(),
+                                rule
+                            ),
+                            _ => format!("Rule {}: {}", i + 1 - source_rule_count, rule),
+                        }),
+                    "include" => Some(format!(
+                        "Not in the included paths {}",
+                        include
+                            .iter()
+                            .map(IncludePattern::as_str)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )),
+                    "preset" => presets.iter().find_map(|preset| {
+                        let entry = preset.matching(&item.path)?;
+                        Some(format!("{}: {}", preset, entry))
+                    }),
+                    _ => continue,
+                };
+            }
+        }
+        Ok(explanation)
+    }
+
+    /// What is checked about the destination before a backup of this profile.
+    ///
+    /// The name of the profile is not known to the profile itself, so it has to be set on
+    /// the returned check to refuse destinations that belong to other profiles.
+    pub fn destination_check(&self) -> DestinationCheck {
+        DestinationCheck {
+            require_marker: self.require_marker,
+            id: self.destination_id,
+            profile: None,
+            require_mount_point: self.require_mount_point,
+        }
+    }
+}
+
+/// Parses a size such as `512`, `10MB` or `2 GiB` into bytes.
+///
+/// Both decimal (`kB`, `MB`, `GB`, `TB`) and binary (`KiB`, `MiB`, `GiB`, `TiB`) units are
+/// accepted, the case of the unit is ignored.
+pub fn parse_size(size: &str) -> std::result::Result<u64, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size {}", size))?;
+    let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "t" | "tb" => 1_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => return Err(format!("Invalid unit in size {}", size)),
+    };
+
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// A size given either in bytes or as a string parsed by [`parse_size`].
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Size {
+    Bytes(u64),
+    Text(String),
+}
+
+impl Size {
+    pub(crate) fn bytes(self) -> std::result::Result<u64, String> {
+        match self {
+            Size::Bytes(bytes) => Ok(bytes),
+            Size::Text(text) => parse_size(&text),
+        }
+    }
+}
+
+/// Deserializes a duration such as `"500ms"` or `"2s"`.
+fn deserialize_duration<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Duration, D::Error> {
+    humantime::parse_duration(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+/// Deserializes an optional duration, see [`deserialize_duration`].
+fn deserialize_optional_duration<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<Duration>, D::Error> {
+    deserialize_duration(deserializer).map(Some)
+}
+
+/// Deserializes an optional [`Size`].
+fn deserialize_optional_size<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<u64>, D::Error> {
+    deserialize_size(deserializer).map(Some)
+}
+
+/// Deserializes a [`Size`].
+fn deserialize_size<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<u64, D::Error> {
+    Size::deserialize(deserializer)?
+        .bytes()
+        .map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_profiles() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [profiles.documents]
+            source = "/home/bob/Documents"
+            destination = "/media/backup"
+            "#,
+        )?;
+
+        let profile = config.profile("documents")?;
+        assert_eq!(profile.source, PathBuf::from("/home/bob/Documents"));
+        assert_eq!(profile.destination, PathBuf::from("/media/backup"));
+        assert!(profile.require_marker);
+        assert_eq!(profile.destination_id, None);
+        assert_eq!(profile.reserve, 0);
+        assert_eq!(
+            profile.exclude_executables,
+            ExecutableKind::DEFAULT_EXCLUDED
+        );
+
+        assert!(matches!(config.profile("music"), Err(Error::Config(_))));
+
+        let config = Config::parse(
+            r#"
+            [profiles.documents]
+            source = "/home/bob/Documents"
+            destination = "/media/backup"
+            reserve = "2 GB"
+            max_file_size = "2 GB"
+            max_file_age = "5 years"
+
+            [profiles.music]
+            source = "/home/bob/Music"
+            destination = "/media/backup"
+            reserve = 1024
+            exclude_executables = ["pe", "shared_object"]
+            "#,
+        )?;
+        assert_eq!(config.profile("documents")?.reserve, 2_000_000_000);
+        assert_eq!(config.profile("music")?.reserve, 1024);
+        assert_eq!(
+            config.profile("documents")?.max_file_size,
+            Some(2_000_000_000)
+        );
+        assert!(config.profile("documents")?.max_file_age.is_some());
+        assert_eq!(config.profile("music")?.max_file_size, None);
+        assert_eq!(
+            config.profile("music")?.exclude_executables,
+            vec![ExecutableKind::Pe, ExecutableKind::SharedObject]
+        );
+        assert_eq!(config.daemon, DaemonConfig::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_schedules() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [daemon]
+            max_concurrent = 2
+
+            [profiles.documents]
+            source = "/home/bob/Documents"
+            destination = "/media/backup"
+            schedule = "0 3 * * *"
+
+            [profiles.music]
+            source = "/home/bob/Music"
+            destination = "/media/backup"
+            schedule = "every 12h"
+            "#,
+        )?;
+        assert_eq!(config.daemon.max_concurrent, 2);
+        assert!(matches!(
+            config.profile("documents")?.schedule,
+            Some(Schedule::Cron { .. })
+        ));
+        assert!(matches!(
+            config.profile("music")?.schedule,
+            Some(Schedule::Interval(_))
+        ));
+
+        let config = Config::parse(
+            r#"
+            [profiles.documents]
+            source = "/home/bob/Documents"
+            destination = "/media/backup"
+            schedule = "at 3 o'clock"
+            "#,
+        );
+        assert!(matches!(config, Err(Error::Config(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_profile_rules() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let (_test_dir, _, copied) = backup_with_config(
+            r#"
+            [profiles.documents]
+            source = "{dir}/source"
+            destination = "{dir}/backup"
+            require_marker = false
+            presets = ["rust"]
+            exclude_caches = true
+
+            [[profiles.documents.rules]]
+            action = "include"
+            glob = "tools/*.exe"
+
+            [[profiles.documents.rules]]
+            action = "exclude"
+            extensions = ["tmp"]
+            "#,
+            &[
+                ("source/notes.txt", "notes"),
+                ("source/scratch.tmp", "scratch"),
+                ("source/tools/setup.exe", "MZ"),
+                ("source/other.exe", "MZ"),
+                ("source/app/Cargo.toml", "[package]"),
+                ("source/app/target/app.d", "deps"),
+                (
+                    "source/thumbnails/CACHEDIR.TAG",
+                    "Signature: 8a477f597d28d172789f06886806bc55",
+                ),
+            ],
+        )?;
+
+        // An include rule overrides the built-in rule excluding programs, the build output
+        // and the cache are left out
+        assert_eq!(
+            copied,
+            vec![
+                "source/app/Cargo.toml",
+                "source/notes.txt",
+                "source/tools/setup.exe"
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_profile_include() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let (_test_dir, _, copied) = backup_with_config(
+            r#"
+            [profiles.home]
+            source = "{dir}/home"
+            destination = "{dir}/backup"
+            require_marker = false
+            include = ["Documents", ".ssh", ".bashrc", "projects/*/notes/*.md"]
+
+            [[profiles.home.rules]]
+            action = "exclude"
+            extensions = ["tmp"]
+            "#,
+            &[
+                ("home/Documents/taxes.pdf", "data"),
+                ("home/Documents/draft.tmp", "data"),
+                ("home/Documents/cache/.nobackup", ""),
+                ("home/Documents/cache/thumbs.db", "data"),
+                ("home/.ssh/config", "data"),
+                ("home/.bashrc", "data"),
+                ("home/.profile", "data"),
+                ("home/Downloads/movie.mkv", "data"),
+                ("home/projects/app/notes/todo.md", "data"),
+                ("home/projects/app/main.rs", "data"),
+            ],
+        )?;
+
+        // Only the included paths are backed up, without the excluded items in them
+        assert_eq!(
+            copied,
+            vec![
+                "home/.bashrc",
+                "home/.ssh/config",
+                "home/Documents/taxes.pdf",
+                "home/projects/app/notes/todo.md"
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_profile_sources() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let (test_dir, config, copied) = backup_with_config(
+            r#"
+            [profiles.projects]
+            source = "{dir}/projects"
+            destination = "{dir}/backup"
+            require_marker = false
+            sources = [
+                "{dir}/notes",
+                { path = "{dir}/projects/client-a", presets = ["rust"], rules = [
+                    { action = "exclude", extensions = ["psd"] },
+                ] },
+            ]
+
+            [[profiles.projects.rules]]
+            action = "exclude"
+            extensions = ["log"]
+            "#,
+            &[
+                ("projects/app/Cargo.toml", "data"),
+                ("projects/app/debug.log", "data"),
+                ("projects/app/target/", ""),
+                ("projects/client-a/Cargo.toml", "data"),
+                ("projects/client-a/art/logo.psd", "data"),
+                ("projects/client-a/target/app", "data"),
+                ("notes/todo.md", "data"),
+            ],
+        )?;
+
+        // The rules of the profile apply to all sources, those of a source only to it
+        assert_eq!(
+            copied,
+            vec![
+                "notes/todo.md",
+                "projects/app/Cargo.toml",
+                "projects/app/target",
+                "projects/client-a/Cargo.toml",
+            ]
+        );
+
+        let projects = fs::canonicalize(test_dir.path())?.join("projects");
+        let profile = config.profile("projects")?;
+        assert_eq!(
+            profile.source_paths(),
+            vec![
+                projects.as_path(),
+                projects.with_file_name("notes").as_path(),
+                projects.join("client-a").as_path()
+            ]
+        );
+
+        let explanation = profile.explain(&projects.join("client-a/art/logo.psd"))?;
+        assert_eq!(explanation.source, projects.join("client-a"));
+        let config_rule = explanation.items[1]
+            .rules
+            .iter()
+            .find(|rule| rule.rule == "config")
+            .unwrap();
+        assert_eq!(
+            config_rule.detail,
+            Some(format!(
+                "Rule 1 of {}/client-a: action = \"exclude\", extensions = [\"psd\"]",
+                projects.to_string_lossy()
+            ))
+        );
+
+        assert!(Config::parse(
+            r#"
+            [profiles.projects]
+            source = "/home/bob/projects"
+            destination = "/media/backup"
+            sources = [{ pth = "/home/bob/notes" }]
+            "#,
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_scripts() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let test_dir = tempfile::tempdir()?;
+        fs::create_dir(test_dir.path().join("rules"))?;
+        fs::write(
+            test_dir.path().join("rules/skip.rhai"),
+            "if item.name == \"target\" { \"exclude\" }",
+        )?;
+        fs::write(test_dir.path().join("rules/broken.rhai"), "if {")?;
+
+        // Scripts are relative to the configuration file
+        let config_path = test_dir.path().join("config.toml");
+        let profile = |scripts: &str| {
+            format!(
+                r#"
+                [profiles.documents]
+                source = "/home/bob/Documents"
+                destination = "/media/backup"
+                scripts = [{}]
+                script_timeout = "200ms"
+                "#,
+                scripts
+            )
+        };
+        fs::write(&config_path, profile("\"rules/skip.rhai\""))?;
+        let config = Config::load(&config_path)?;
+        let documents = config.profile("documents")?;
+        assert_eq!(documents.loaded_scripts.len(), 1);
+        assert_eq!(documents.script_timeout, Duration::from_millis(200));
+
+        // Errors name the script and the line
+        fs::write(&config_path, profile("\"rules/broken.rhai\""))?;
+        match Config::load(&config_path) {
+            Err(Error::Config(err)) => assert!(err.contains("broken.rhai line 1: "), "{}", err),
+            other => panic!("Unexpected result {:?}", other),
+        }
+        fs::write(&config_path, profile("\"rules/missing.rhai\""))?;
+        assert!(matches!(Config::load(&config_path), Err(Error::Config(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_api() -> Result<()> {
+        let config = Config::parse("[daemon]\napi = \"127.0.0.1:8435\"")?;
+        assert_eq!(config.daemon.api, Some("127.0.0.1:8435".parse().unwrap()));
+        assert_eq!(config.daemon.api_token_file, None);
+
+        assert!(Config::parse("[daemon]\napi = \"[::1]:8435\"").is_ok());
+        assert!(matches!(
+            Config::parse("[daemon]\napi = \"0.0.0.0:8435\""),
+            Err(Error::Config(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("10MB"), Ok(10_000_000));
+        assert_eq!(parse_size("2 GiB"), Ok(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("1.5kb"), Ok(1500));
+        assert!(parse_size("10 apples").is_err());
+        assert!(parse_size("GB").is_err());
+    }
+
+    #[test]
+    fn test_parse_unknown_field() {
+        let config = Config::parse(
+            r#"
+            [profiles.documents]
+            source = "/home/bob/Documents"
+            destnation = "/media/backup"
+            "#,
+        );
+        assert!(matches!(config, Err(Error::Config(_))));
+    }
+
+    /// Creates the `files` with their content in a temporary directory, a path ending in `/`
+    /// as an empty directory, and runs the single profile of `config`, in which `{dir}` stands
+    /// for the directory. Returns the directory, the configuration and the items backed up,
+    /// relative to the directory and sorted.
+    fn backup_with_config(
+        config: &str,
+        files: &[(&str, &str)],
+    ) -> std::result::Result<(tempfile::TempDir, Config, Vec<String>), Box<dyn std::error::Error>>
+    {
+        let test_dir = tempfile::tempdir()?;
+        let root = fs::canonicalize(test_dir.path())?;
+        for (file, content) in files {
+            let path = root.join(file);
+            if file.ends_with('/') {
+                fs::create_dir_all(path)?;
+            } else {
+                fs::create_dir_all(path.parent().unwrap())?;
+                fs::write(path, content)?;
+            }
+        }
+
+        let config = Config::parse(&config.replace("{dir}", &root.to_string_lossy()))?;
+        let profile = config.profiles.values().next().unwrap();
+        let report = profile.backup_builder().run()?;
+
+        let mut copied: Vec<String> = report
+            .files
+            .iter()
+            .filter_map(|file| file.source.strip_prefix(&root).ok())
+            .map(|path| path.to_string_lossy().to_string())
+            .collect();
+        copied.sort();
+
+        Ok((test_dir, config, copied))
+    }
+}
@@ -0,0 +1,138 @@
+//! The errors returned by rackup.
+//!
+use indicatif::HumanBytes;
+use rebackup::WalkerErr;
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Result type of the fallible rackup operations.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Error that stops a backup from running.
+///
+/// Errors for single files do not stop a backup. They are recorded in the
+/// [report](crate::BackupReport) as a [`FileError`] instead.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// The backup has not been configured correctly
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+
+    /// The list of files to back up could not be built
+    #[error("Failed to build the files list for {}: {source}", .path.display())]
+    Walk {
+        path: PathBuf,
+        #[source]
+        source: WalkerErr,
+    },
+
+    /// The backup directory or drive cannot be written to
+    #[error("Cannot use the backup directory {}: {source}", .path.display())]
+    Destination {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// The backup directory is inside a source directory, so that every backup would also
+    /// copy the previous backups
+    #[error(
+        "Cannot back up {} into {}: the backup directory is inside the source directory",
+        .path.display(),
+        .destination.display()
+    )]
+    Overlap {
+        /// The source directory
+        path: PathBuf,
+        destination: PathBuf,
+    },
+
+    /// The backup directory is not the one expected, see
+    /// [`DestinationCheck`](crate::marker::DestinationCheck)
+    #[error("Refusing to back up into {}: {reason}", .path.display())]
+    UnverifiedDestination { path: PathBuf, reason: String },
+
+    /// There is not enough free space on the destination for the files that have to be copied
+    #[error(
+        "Not enough space in {}: {} needed to back up and keep {} free, but only {} available",
+        .path.display(),
+        HumanBytes(*.required),
+        HumanBytes(*.reserve),
+        HumanBytes(*.available)
+    )]
+    InsufficientSpace {
+        path: PathBuf,
+        /// The bytes the backup will add to the destination
+        required: u64,
+        /// The bytes to be kept free
+        reserve: u64,
+        available: u64,
+    },
+
+    /// Another backup holds the lock
+    #[error("Another backup is running: {} is locked by {holder}", .path.display())]
+    Locked {
+        /// The lock file
+        path: PathBuf,
+        /// The process holding the lock
+        holder: String,
+    },
+
+    /// The lock file cannot be created or removed
+    #[error("Cannot lock {}: {source}", .path.display())]
+    Lock {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// The changes to a source directory cannot be watched, e.g. because the limit of
+    /// inotify watches has been reached
+    #[error("Cannot watch {} for changes: {source}", .path.display())]
+    Watch {
+        path: PathBuf,
+        #[source]
+        source: notify::Error,
+    },
+
+    /// The history of the runs cannot be read
+    #[error("Cannot read the history {}: {source}", .path.display())]
+    History {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+/// Error copying a single file into the backup.
+#[derive(Error, Debug)]
+#[error("Error copying {}: {source}", .path.display())]
+pub struct FileError {
+    /// The path of the file in the source directory.
+    pub path: PathBuf,
+    #[source]
+    pub source: io::Error,
+}
+
+impl FileError {
+    /// The kind of I/O error that occurred.
+    pub fn kind(&self) -> io::ErrorKind {
+        self.source.kind()
+    }
+}
+
+/// Error of a rule that cannot decide about a single item, returned from the action of a
+/// [`WalkerRule`](rebackup::WalkerRule) as an [`io::Error`].
+///
+/// Other errors of a rule stop the backup. With this error only the item fails: it is left
+/// out and recorded in the [report](crate::BackupReport) as a [`FileError`].
+#[derive(Error, Debug)]
+#[error("{0}")]
+pub struct RuleItemError(pub String);
+
+impl From<RuleItemError> for io::Error {
+    fn from(err: RuleItemError) -> io::Error {
+        io::Error::other(err)
+    }
+}
@@ -0,0 +1,365 @@
+//! Explaining why an item is or is not backed up.
+//!
+//! The rules are evaluated on the item and on each of its ancestors within the source, the
+//! same way the walk evaluates them (see [`Backup::explain`](crate::Backup::explain)). For an
+//! included file it is also worked out whether it would be copied, by comparing it with its
+//! copy in the backup.
+//!
+use crate::error::{Error, Result};
+use crate::files::{create_backup_file_path, is_newer};
+use crate::rules::{gitignore_match, marker_decision, ExecutableKind, MarkerDecision};
+use crate::scan::{evaluate_rules, walked_item_type, RuleCheck};
+use humantime::format_rfc3339_seconds;
+use rebackup::{WalkerConfig, WalkerItemType, WalkerRuleErr, WalkerRuleResult};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Why an item is or is not backed up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    /// The item, with its ancestors resolved.
+    pub path: PathBuf,
+    /// The resolved source directory containing the item.
+    pub source: PathBuf,
+    /// The evaluation of the rules on the ancestors of the item below the source and on the
+    /// item itself, up to the first one that is not included.
+    pub items: Vec<ItemExplanation>,
+    /// Whether the item is backed up.
+    pub included: bool,
+    /// Whether the item would be copied, only for included items.
+    pub change: Option<ChangeDecision>,
+}
+
+/// The evaluation of the rules on one item.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ItemExplanation {
+    pub path: PathBuf,
+    /// The rules in the order they have been checked.
+    pub rules: Vec<RuleEvaluation>,
+    pub included: bool,
+    /// Why the item is not walked or the walk fails on it.
+    pub note: Option<String>,
+}
+
+/// How one rule has been checked on an item.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleEvaluation {
+    pub rule: &'static str,
+    pub description: Option<String>,
+    pub outcome: RuleOutcome,
+    /// What made the rule decide, e.g. the matching pattern of an ignore file.
+    pub detail: Option<String>,
+}
+
+/// The outcome of a rule on an item.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleOutcome {
+    /// The rule is only for other types of items.
+    NotApplicable,
+    /// The item does not match the rule.
+    NotMatching,
+    /// The rule leaves the item to the next rules.
+    Skipped,
+    /// The rule includes the item, the next rules can still exclude it.
+    Included,
+    /// The rule includes the item, no more rules are checked.
+    IncludedAbsolute,
+    /// The rule excludes the item.
+    Excluded,
+    /// The rule replaces the item with other items.
+    Mapped,
+    /// The rule has failed, which fails the walk.
+    Failed(String),
+}
+
+impl RuleOutcome {
+    /// A short name of the outcome in snake case, e.g. `not_matching`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RuleOutcome::NotApplicable => "not_applicable",
+            RuleOutcome::NotMatching => "not_matching",
+            RuleOutcome::Skipped => "skipped",
+            RuleOutcome::Included => "included",
+            RuleOutcome::IncludedAbsolute => "included_absolute",
+            RuleOutcome::Excluded => "excluded",
+            RuleOutcome::Mapped => "mapped",
+            RuleOutcome::Failed(_) => "failed",
+        }
+    }
+}
+
+/// Whether an included item would be copied to the backup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeDecision {
+    /// Where the item is copied to.
+    pub backup_path: PathBuf,
+    /// Whether the item would be copied by the next backup.
+    pub copy: bool,
+    pub reason: String,
+}
+
+/// Explains whether the item at `path` is backed up from one of the `sources` to
+/// `destination` with the rules of `config`.
+pub(crate) fn explain(
+    sources: &[(&Path, &WalkerConfig)],
+    destination: &Path,
+    path: &Path,
+) -> Result<Explanation> {
+    // The item itself is not resolved, as symbolic links may not be followed
+    let resolved = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => fs::canonicalize(parent).map(|parent| parent.join(name)),
+        _ => fs::canonicalize(path),
+    };
+    let path = resolved
+        .and_then(|path| path.symlink_metadata().map(|_| path))
+        .map_err(|err| Error::Config(format!("Cannot find {}: {}", path.to_string_lossy(), err)))?;
+
+    // The innermost source walks the item, the first one if a source is given twice
+    let (source, config) = sources
+        .iter()
+        .filter_map(|(source, config)| Some((fs::canonicalize(source).ok()?, *config)))
+        .filter(|(source, _)| path.starts_with(source))
+        .rev()
+        .max_by_key(|(source, _)| source.components().count())
+        .ok_or_else(|| {
+            Error::Config(format!(
+                "{} is not in a source of the backup",
+                path.to_string_lossy()
+            ))
+        })?;
+
+    let mut items = Vec::new();
+    let mut included = true;
+    let mut ancestor = source.clone();
+    for component in path.strip_prefix(&source).unwrap_or(&path).components() {
+        ancestor.push(component);
+        let item = explain_item(config, &source, &ancestor);
+        included = item.included;
+        items.push(item);
+        if !included {
+            break;
+        }
+    }
+
+    let change = included.then(|| change_decision(&path, destination));
+    Ok(Explanation {
+        path,
+        source,
+        items,
+        included,
+        change,
+    })
+}
+
+/// Evaluates the rules on the item at `path`, without its ancestors.
+fn explain_item(config: &WalkerConfig, source: &Path, path: &Path) -> ItemExplanation {
+    let mut explanation = ItemExplanation {
+        path: path.to_path_buf(),
+        rules: Vec::new(),
+        included: false,
+        note: None,
+    };
+    let Some(item_type) = walked_item_type(config, path) else {
+        explanation.note = Some(if path.is_symlink() {
+            "Symbolic links are not followed".to_string()
+        } else {
+            "Only files, directories and symbolic links are backed up".to_string()
+        });
+        return explanation;
+    };
+
+    let result = evaluate_rules(config, source, path, item_type, |rule, check| {
+        // Cancelling the backup is not a decision about the item, and no source is nested in
+        // the innermost source of the item
+        if rule.name == "cancel" || rule.name == "sources" {
+            return;
+        }
+        let outcome = match check {
+            RuleCheck::NotFor => RuleOutcome::NotApplicable,
+            RuleCheck::NotMatching => RuleOutcome::NotMatching,
+            RuleCheck::Result(WalkerRuleResult::SkipRule) => RuleOutcome::Skipped,
+            RuleCheck::Result(WalkerRuleResult::IncludeItem) => RuleOutcome::Included,
+            RuleCheck::Result(WalkerRuleResult::IncludeItemAbsolute) => {
+                RuleOutcome::IncludedAbsolute
+            }
+            RuleCheck::Result(WalkerRuleResult::ExcludeItem) => RuleOutcome::Excluded,
+            RuleCheck::Result(WalkerRuleResult::MapAsList(..)) => RuleOutcome::Mapped,
+            RuleCheck::Result(WalkerRuleResult::StrError(err))
+            | RuleCheck::Failed(WalkerRuleErr::Str(err)) => RuleOutcome::Failed(err.clone()),
+            RuleCheck::Failed(WalkerRuleErr::Io(err)) => RuleOutcome::Failed(err.to_string()),
+        };
+        let detail = rule_detail(rule.name, &outcome, path, source, item_type);
+        explanation.rules.push(RuleEvaluation {
+            rule: rule.name,
+            description: rule.description.clone(),
+            outcome,
+            detail,
+        });
+    });
+
+    match result {
+        Ok(included) => explanation.included = included,
+        Err(err) => explanation.note = Some(format!("The backup would fail: {}", err)),
+    }
+    explanation
+}
+
+/// What made a built-in rule decide on the item at `path`.
+fn rule_detail(
+    rule: &str,
+    outcome: &RuleOutcome,
+    path: &Path,
+    source: &Path,
+    item_type: WalkerItemType,
+) -> Option<String> {
+    match (rule, outcome) {
+        ("nobackup", RuleOutcome::Excluded | RuleOutcome::IncludedAbsolute) => {
+            match marker_decision(path, source) {
+                Ok(Some(MarkerDecision::Exclude(marker) | MarkerDecision::Include(marker))) => {
+                    Some(marker.to_string())
+                }
+                _ => None,
+            }
+        }
+        ("gitignore", RuleOutcome::Excluded) => match gitignore_match(path) {
+            Ok(Some(ignore)) => Some(format!(
+                "{} line {}: {}",
+                ignore.file.to_string_lossy(),
+                ignore.line,
+                ignore.pattern
+            )),
+            _ => None,
+        },
+        ("noexe", RuleOutcome::Included | RuleOutcome::Excluded)
+            if item_type == WalkerItemType::File =>
+        {
+            Some(match ExecutableKind::detect(path) {
+                Some(kind) => kind.to_string(),
+                None => "not an executable".to_string(),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Whether the included item at `path` would be copied to `destination`, like the backup
+/// decides it.
+fn change_decision(path: &Path, destination: &Path) -> ChangeDecision {
+    let backup_path = create_backup_file_path(path, destination);
+    let copy = is_newer(&path.to_path_buf(), &backup_path);
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    let reason = if path.is_dir() {
+        "Directories are created in the backup if they are missing".to_string()
+    } else if !backup_path.is_file() {
+        "The file is not in the backup yet".to_string()
+    } else {
+        match (modified(path), modified(&backup_path)) {
+            (Some(source), Some(backup)) if copy => format!(
+                "The file has been modified at {}, after its copy in the backup ({})",
+                format_rfc3339_seconds(source),
+                format_rfc3339_seconds(backup)
+            ),
+            (Some(source), Some(backup)) => format!(
+                "The file has not been modified since its copy in the backup ({}, copied {})",
+                format_rfc3339_seconds(source),
+                format_rfc3339_seconds(backup)
+            ),
+            _ => "The modification times cannot be compared".to_string(),
+        }
+    };
+
+    ChangeDecision {
+        backup_path,
+        copy,
+        reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{filter_rule, FilterAction, FilterRule};
+    use crate::rules::exe_rule;
+    use crate::Backup;
+    use rebackup::glob::Pattern;
+
+    #[test]
+    fn test_explain() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let test_dir = tempfile::tempdir()?;
+        let source = fs::canonicalize(test_dir.path())?.join("source");
+        fs::create_dir_all(source.join("docs"))?;
+        fs::create_dir_all(source.join("cache"))?;
+        fs::write(source.join("docs/notes.txt"), b"notes")?;
+        fs::write(source.join("docs/setup.exe"), b"MZ")?;
+        fs::write(source.join("cache/data.bin"), b"data")?;
+
+        let mut exclude_cache = FilterRule::new(FilterAction::Exclude);
+        exclude_cache.glob = Some(Pattern::new("cache").unwrap());
+        let backup = Backup::builder()
+            .source(&source)
+            .destination(test_dir.path().join("backup"))
+            .rules([filter_rule(vec![exclude_cache]), exe_rule()])
+            .build()?;
+        let outcomes = |explanation: &Explanation| -> Vec<Vec<(&str, &str)>> {
+            explanation
+                .items
+                .iter()
+                .map(|item| {
+                    item.rules
+                        .iter()
+                        .map(|rule| (rule.rule, rule.outcome.name()))
+                        .collect()
+                })
+                .collect()
+        };
+
+        // Excluded by the rule on its directory
+        let explanation = backup.explain(&source.join("cache/data.bin"))?;
+        assert!(!explanation.included);
+        assert_eq!(
+            outcomes(&explanation),
+            vec![vec![
+                ("destination", "not_matching"),
+                ("config", "excluded")
+            ]]
+        );
+        assert_eq!(explanation.change, None);
+
+        // Excluded by a built-in rule, naming the kind of executable
+        let explanation = backup.explain(&source.join("docs/setup.exe"))?;
+        assert!(!explanation.included);
+        let noexe = &explanation.items[1].rules[2];
+        assert_eq!(noexe.outcome, RuleOutcome::Excluded);
+        assert_eq!(noexe.detail.as_deref(), Some("Windows program"));
+
+        // Included, and copied until it is in the backup
+        let notes = source.join("docs/notes.txt");
+        let explanation = backup.explain(&notes)?;
+        assert!(explanation.included);
+        assert_eq!(
+            outcomes(&explanation)[1],
+            vec![
+                ("destination", "not_matching"),
+                ("config", "skipped"),
+                ("noexe", "included")
+            ]
+        );
+        let change = explanation.change.expect("No change decision");
+        assert!(change.copy);
+        assert_eq!(change.reason, "The file is not in the backup yet");
+
+        backup.run()?;
+        let change = backup.explain(&notes)?.change.expect("No change decision");
+        assert!(!change.copy);
+        assert!(change.backup_path.is_file());
+
+        // Paths outside the sources
+        assert!(matches!(
+            backup.explain(test_dir.path()),
+            Err(Error::Config(_))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+//! Export of a backup as a tar stream.
+//!
+//! The entries are written in sorted order so that exporting the same backup twice
+//! gives the same stream. With `normalize` the ownership, timestamps and permissions
+//! are also normalized so that the stream only depends on the file names and contents.
+//! Partial copies left behind by a backup that was killed are not exported.
+//!
+use crate::files::is_temp_file;
+use crate::history::METADATA_DIR;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use tar::{Builder, HeaderMode};
+
+/// Writes the contents of `snapshot_path` (or of `sub_path` within it) as a tar
+/// stream to `out`.
+///
+/// The entry names are relative to the exported directory. `sub_path` has to be a path
+/// within the backup, made of names only.
+pub fn export_tar<W: Write>(
+    snapshot_path: &Path,
+    sub_path: Option<&Path>,
+    normalize: bool,
+    out: W,
+) -> io::Result<W> {
+    let mut export_root = PathBuf::from(snapshot_path);
+    if let Some(sub_path) = sub_path {
+        let outside = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "The sub path {} has to be a path within the backup",
+                    sub_path.to_string_lossy()
+                ),
+            )
+        };
+        if !sub_path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(outside());
+        }
+        export_root.push(sub_path);
+
+        // Symbolic links in the backup cannot lead out of it either
+        if export_root.exists()
+            && !fs::canonicalize(&export_root)?.starts_with(fs::canonicalize(snapshot_path)?)
+        {
+            return Err(outside());
+        }
+    }
+
+    if !export_root.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} does not exist", export_root.to_string_lossy()),
+        ));
+    }
+
+    let mut builder = Builder::new(out);
+    builder.follow_symlinks(false);
+    if normalize {
+        builder.mode(HeaderMode::Deterministic);
+    } else {
+        builder.mode(HeaderMode::Complete);
+    }
+
+    if export_root.is_file() {
+        let name = export_root.file_name().unwrap_or_default().to_owned();
+        builder.append_path_with_name(&export_root, name)?;
+    } else {
+        // The files rackup keeps on the destination are not part of the backup
+        let metadata_dir = snapshot_path.join(METADATA_DIR);
+
+        for entry_path in sorted_entries(&export_root)? {
+            if entry_path.starts_with(&metadata_dir) || is_temp_file(&entry_path) {
+                continue;
+            }
+            let name = entry_path.strip_prefix(&export_root).unwrap_or(&entry_path);
+            builder.append_path_with_name(&entry_path, name)?;
+        }
+    }
+
+    builder.into_inner()
+}
+
+/// Recursively lists all the entries below `dir`, with each directory listed
+/// before its contents and the entries of a directory sorted by name.
+fn sorted_entries(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut entries = Vec::new();
+
+    let mut dir_entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    dir_entries.sort();
+
+    for path in dir_entries {
+        // Do not follow symbolic links into other directories
+        let is_dir = fs::symlink_metadata(&path)?.is_dir();
+        entries.push(path.clone());
+        if is_dir {
+            entries.append(&mut sorted_entries(&path)?);
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::Write;
+
+    #[test]
+    fn test_export_is_sorted() -> Result<(), io::Error> {
+        let test_dir = tempfile::tempdir()?;
+        fs::create_dir(test_dir.path().join("b"))?;
+        fs::create_dir(test_dir.path().join("a"))?;
+        fs::create_dir(test_dir.path().join(".rackup"))?;
+        File::create(test_dir.path().join(".rackup/history.jsonl"))?;
+        // A partial copy left by a killed backup
+        File::create(test_dir.path().join("a/.y.txt.rackup-tmp"))?;
+        let mut f = File::create(test_dir.path().join("b/file.txt"))?;
+        write!(f, "file.txt")?;
+        f = File::create(test_dir.path().join("a/z.txt"))?;
+        write!(f, "z.txt")?;
+
+        let stream = export_tar(test_dir.path(), None, false, Vec::new())?;
+
+        let mut archive = tar::Archive::new(stream.as_slice());
+        let names = archive
+            .entries()?
+            .map(|e| e.and_then(|e| e.path().map(|p| p.to_string_lossy().to_string())))
+            .collect::<io::Result<Vec<String>>>()?;
+        assert_eq!(names, vec!["a", "a/z.txt", "b", "b/file.txt"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_normalized_is_reproducible() -> Result<(), io::Error> {
+        let test_dir = tempfile::tempdir()?;
+        fs::create_dir(test_dir.path().join("Documents"))?;
+        let mut f = File::create(test_dir.path().join("Documents/file.txt"))?;
+        write!(f, "file.txt")?;
+
+        let first = export_tar(test_dir.path(), None, true, Vec::new())?;
+
+        // Touching the file changes the modification time but not the normalized stream
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .open(test_dir.path().join("Documents/file.txt"))?;
+        write!(f, "file.txt")?;
+
+        let second = export_tar(test_dir.path(), None, true, Vec::new())?;
+        assert_eq!(first, second);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_sub_path() -> Result<(), io::Error> {
+        let test_dir = tempfile::tempdir()?;
+        fs::create_dir(test_dir.path().join("DocumentsA"))?;
+        fs::create_dir(test_dir.path().join("DocumentsB"))?;
+        let mut f = File::create(test_dir.path().join("DocumentsA/fileAA.txt"))?;
+        write!(f, "fileAA.txt")?;
+        f = File::create(test_dir.path().join("DocumentsB/fileBA.txt"))?;
+        write!(f, "fileBA.txt")?;
+
+        let stream = export_tar(
+            test_dir.path(),
+            Some(Path::new("DocumentsB")),
+            true,
+            Vec::new(),
+        )?;
+
+        let mut archive = tar::Archive::new(stream.as_slice());
+        let names = archive
+            .entries()?
+            .map(|e| e.and_then(|e| e.path().map(|p| p.to_string_lossy().to_string())))
+            .collect::<io::Result<Vec<String>>>()?;
+        assert_eq!(names, vec!["fileBA.txt"]);
+
+        assert!(export_tar(
+            test_dir.path(),
+            Some(Path::new("Missing")),
+            true,
+            Vec::new()
+        )
+        .is_err());
+
+        // Nothing outside the backup is exported
+        let backup = test_dir.path().join("DocumentsA");
+        for sub_path in ["../DocumentsB", "fileAA.txt/../../DocumentsB", "/etc"] {
+            let result = export_tar(&backup, Some(Path::new(sub_path)), true, Vec::new());
+            assert_eq!(
+                result.err().map(|err| err.kind()),
+                Some(io::ErrorKind::InvalidInput),
+                "{}",
+                sub_path
+            );
+        }
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("../DocumentsB", backup.join("link"))?;
+            let result = export_tar(&backup, Some(Path::new("link")), true, Vec::new());
+            assert!(result.is_err());
+        }
+
+        Ok(())
+    }
+}
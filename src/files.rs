@@ -0,0 +1,501 @@
+//! Helpers for copying the individual files into the backup.
+//!
+use crate::cancel::CancelToken;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf, Prefix};
+
+/// Checks if the `source_file`is newer then the `backup_file`.
+/// If the `backup_file`does not exist then this always returns `true`.
+///
+pub(crate) fn is_newer(source_file: &PathBuf, backup_file: &std::path::PathBuf) -> bool {
+    // Check if the backup file exists. If it does not return true as the source file
+    // is "newer"
+    if !backup_file.exists() || !backup_file.is_file() {
+        return true;
+    }
+
+    // Check the modifed times of the files to find the newest
+    if let (Ok(source_metadata), Ok(backup_metadata)) =
+        (fs::metadata(source_file), fs::metadata(backup_file))
+    {
+        if let (Ok(source_modified), Ok(existing_modified)) =
+            (source_metadata.modified(), backup_metadata.modified())
+        {
+            return source_modified > existing_modified;
+        }
+    }
+    false
+}
+
+/// Size of the chunks in which files are copied.
+const COPY_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Copies over the backup file, returning the number of bytes written.
+///
+/// The file is copied to a temporary file next to the backup file first, which then replaces
+/// the backup file. If the copy fails part way, or `cancel` is cancelled, the temporary file
+/// is removed and the previous backup of the file is kept. A cancelled copy fails with an
+/// error of kind [`io::ErrorKind::Interrupted`].
+///
+/// `progress` is called with the number of bytes copied so far after each chunk.
+pub(crate) fn copy_file(
+    source_file_path: &PathBuf,
+    backup_file_path: &PathBuf,
+    cancel: &CancelToken,
+    mut progress: impl FnMut(u64),
+) -> io::Result<u64> {
+    // Create the directory/directories the file is in if they have not already been created.
+    let mut dir = backup_file_path.clone();
+    dir.pop();
+    fs::create_dir_all(dir)?;
+
+    // Open the source file for reading, but only if it is a file
+    // (directories hve been created before).
+    if source_file_path.is_file() {
+        let mut source_file = fs::File::open(source_file_path)?;
+
+        let temp_file_path = temp_file_path(backup_file_path);
+        let mut backup_file = fs::File::create(&temp_file_path)?;
+
+        // Write the contents of the checked file to the existing file
+        let mut buf = vec![0; COPY_CHUNK_SIZE];
+        let mut bytes_copied = 0;
+        let copied = loop {
+            if cancel.is_cancelled() {
+                break Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "the backup has been interrupted",
+                ));
+            }
+            let n = match source_file.read(&mut buf) {
+                Ok(0) => break Ok(bytes_copied),
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => break Err(err),
+            };
+            if let Err(err) = backup_file.write_all(&buf[..n]) {
+                break Err(err);
+            }
+            bytes_copied += n as u64;
+            progress(bytes_copied);
+        };
+
+        drop(backup_file);
+        let renamed = copied.and_then(|bytes| {
+            fs::rename(&temp_file_path, backup_file_path)?;
+            Ok(bytes)
+        });
+        if renamed.is_err() {
+            let _ = fs::remove_file(&temp_file_path);
+        }
+
+        renamed
+    } else {
+        // Is just a directory so create it
+        fs::create_dir_all(backup_file_path)?;
+
+        Ok(0)
+    }
+}
+
+/// The end of the name of the temporary files that files are copied to.
+const TEMP_FILE_SUFFIX: &str = ".rackup-tmp";
+
+/// The temporary file a file is copied to before it replaces `backup_file_path`.
+fn temp_file_path(backup_file_path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(backup_file_path.file_name().unwrap_or_default());
+    name.push(TEMP_FILE_SUFFIX);
+    backup_file_path.with_file_name(name)
+}
+
+/// `true` if `path` is a temporary file that a backup copies a file to, which a backup that
+/// was killed may have left behind.
+pub(crate) fn is_temp_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('.') && name.ends_with(TEMP_FILE_SUFFIX))
+}
+
+/// `true` if an error of `kind` means that there is no space left on the backup drive.
+pub(crate) fn is_storage_full(kind: io::ErrorKind) -> bool {
+    matches!(
+        kind,
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded
+    )
+}
+
+/// Create the path of the file being backed up, i.e.:
+/// with source file: C:/Users/bob/Documents/test.txt
+/// and backup directory C:/Users/bob/Backup it will create a PathBuf of
+///      C:/Users/bob/Backup/C/Users/bob/Documents/test.txt
+pub fn create_backup_file_path(source_file_path: &Path, backup_dir_path: &Path) -> PathBuf {
+    let components = source_file_path.components();
+
+    let mut backup_file_path = PathBuf::from(backup_dir_path);
+
+    // Built from the components themselves so that names which are not valid UTF-8 are kept
+    let mut sub_path = PathBuf::new();
+
+    for component in components {
+        match component {
+            Component::Prefix(p) => match p.kind() {
+                Prefix::Verbatim(_osstr) | Prefix::DeviceNS(_osstr) => {
+                    // Ignored
+                }
+                Prefix::VerbatimUNC(hostname, sharename) | Prefix::UNC(hostname, sharename) => {
+                    sub_path.push(hostname);
+                    sub_path.push(sharename);
+                }
+                Prefix::Disk(disk_chr) | Prefix::VerbatimDisk(disk_chr) => {
+                    sub_path.push(String::from(disk_chr as char));
+                }
+            },
+            // The root is given by the backup directory, so that the sub path stays relative to it
+            Component::RootDir => {}
+            Component::Normal(c) => sub_path.push(c),
+            _ => sub_path.push("unknown"),
+        };
+    }
+
+    backup_file_path.push(sub_path);
+
+    backup_file_path
+}
+
+/// Resolves `path` to an absolute path without symbolic links, like [`fs::canonicalize`],
+/// but `path` does not need to exist yet.
+///
+/// The longest existing ancestor of `path` is canonicalized and the missing components are
+/// appended to it.
+pub(crate) fn canonicalize_missing(path: &Path) -> io::Result<PathBuf> {
+    let path = std::path::absolute(path)?;
+
+    let mut existing = path.as_path();
+    let mut missing = Vec::new();
+    let mut canonical = loop {
+        match fs::canonicalize(existing) {
+            Ok(canonical) => break canonical,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let mut components = existing.components();
+                match components.next_back() {
+                    Some(component) => missing.push(component),
+                    None => return Err(err),
+                }
+                existing = components.as_path();
+            }
+            Err(err) => return Err(err),
+        }
+    };
+
+    // The missing directories cannot be symbolic links, so `..` just removes the parent
+    for component in missing.into_iter().rev() {
+        match component {
+            Component::ParentDir => {
+                canonical.pop();
+            }
+            Component::CurDir => {}
+            component => canonical.push(component),
+        }
+    }
+
+    Ok(canonical)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::time;
+    use std::fs::File;
+    use std::io::{Read, Write};
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_failed_copy_removes_partial_file() -> Result<(), std::io::Error> {
+        let test_dir = tempfile::tempdir()?;
+        let backup_path = test_dir.path().join("backup/mem");
+
+        // Reading the start of the memory of the process fails
+        let source_path = PathBuf::from("/proc/self/mem");
+        assert!(copy_file(&source_path, &backup_path, &CancelToken::new(), |_| {}).is_err());
+        assert!(!backup_path.exists());
+        assert_eq!(fs::read_dir(test_dir.path().join("backup"))?.count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancelled_copy_keeps_previous_backup() -> Result<(), std::io::Error> {
+        let test_dir = tempfile::tempdir()?;
+        let source_path = test_dir.path().join("source.txt");
+        let backup_path = test_dir.path().join("backup/source.txt");
+        fs::write(&source_path, "new contents")?;
+        fs::create_dir(test_dir.path().join("backup"))?;
+        fs::write(&backup_path, "old contents")?;
+
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let err = copy_file(&source_path, &backup_path, &cancel, |_| {}).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert_eq!(fs::read_to_string(&backup_path)?, "old contents");
+        assert_eq!(fs::read_dir(test_dir.path().join("backup"))?.count(), 1);
+
+        copy_file(&source_path, &backup_path, &CancelToken::new(), |_| {})?;
+        assert_eq!(fs::read_to_string(&backup_path)?, "new contents");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_canonicalize_missing() -> Result<(), std::io::Error> {
+        let test_dir = tempfile::tempdir()?;
+        let root = fs::canonicalize(test_dir.path())?;
+        fs::create_dir(root.join("existing"))?;
+
+        assert_eq!(
+            canonicalize_missing(&test_dir.path().join("existing/./missing/../other"))?,
+            root.join("existing/other")
+        );
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join("existing"), root.join("link"))?;
+            assert_eq!(
+                canonicalize_missing(&test_dir.path().join("link/missing"))?,
+                root.join("existing/missing")
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_newer_where_backup_file_does_not_exist() -> Result<(), std::io::Error> {
+        // Set up test data
+        let test_dir = tempfile::tempdir()?;
+
+        let source_path = test_dir.path().join("source_test_data");
+        let mut source_file = File::create(source_path)?;
+        writeln!(source_file, "Some test data")?;
+
+        // test the is_newer function
+        let backup_path = test_dir.path().join("backup");
+        let source_path_created = test_dir.path().join("source_test_data");
+        let newer = is_newer(&source_path_created, &backup_path);
+        assert!(newer);
+
+        //Cleanup
+        drop(source_file);
+        test_dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_newer_where_source_older() -> Result<(), std::io::Error> {
+        // Set up test data
+        let test_dir = tempfile::tempdir()?;
+
+        // Create backup file after source file
+        let source_path = test_dir.path().join("source_test_data");
+        let mut source_file = File::create(source_path)?;
+        writeln!(source_file, "Some test data")?;
+
+        let backup_path = test_dir.path().join("backup");
+        let mut backup_file = File::create(backup_path)?;
+        writeln!(backup_file, "Some test data")?;
+
+        // Test the is_newer function
+        let source_path_created = test_dir.path().join("source_test_data");
+        let backup_path_created = test_dir.path().join("backup");
+        let newer = is_newer(&source_path_created, &backup_path_created);
+        assert!(!newer); // Backup file is younger than source file
+
+        //Cleanup
+        drop(source_file);
+        drop(backup_file);
+        test_dir.close()?;
+
+        Ok(())
+    }
+    #[test]
+    fn test_is_newer_where_source_younger() -> Result<(), std::io::Error> {
+        // Set up test data
+        let test_dir = tempfile::tempdir()?;
+
+        // Crate backup file before source file
+        let backup_path = test_dir.path().join("backup");
+        let mut backup_file = File::create(backup_path)?;
+        writeln!(backup_file, "Some test data")?;
+
+        std::thread::sleep(time::Duration::from_millis(250));
+
+        let source_path = test_dir.path().join("source_test_data");
+        let mut source_file = File::create(source_path)?;
+        writeln!(source_file, "Some test data")?;
+
+        // Test the is_newer function
+        let source_path_created = test_dir.path().join("source_test_data");
+        let backup_path_created = test_dir.path().join("backup");
+        let newer = is_newer(&source_path_created, &backup_path_created);
+        assert!(newer); // Backup file is older then source file
+
+        //Cleanup
+        drop(source_file);
+        drop(backup_file);
+        test_dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_first_backup() -> Result<(), std::io::Error> {
+        // Set up test data
+        let test_content = "Some test content".to_string();
+
+        let test_dir = tempfile::tempdir()?;
+
+        let source_path = test_dir.path().join("source_test_data");
+        let mut source_file = File::create(source_path)?;
+        write!(source_file, "{}", test_content)?;
+
+        // Test the back_file function
+        let backup_path = test_dir.path().join("backup");
+        let source_path_created = test_dir.path().join("source_test_data");
+        copy_file(
+            &source_path_created,
+            &backup_path,
+            &CancelToken::new(),
+            |_| {},
+        )?;
+
+        let mut created_backup_file = File::open(backup_path)?;
+        let mut buf = String::new();
+        created_backup_file.read_to_string(&mut buf)?;
+        assert_eq!(buf, test_content);
+
+        //Cleanup
+        drop(source_file);
+        test_dir.close()?;
+
+        Ok(())
+    }
+    #[test]
+    fn test_subsequent_backup() -> Result<(), std::io::Error> {
+        // Set up test data
+
+        let test_dir = tempfile::tempdir()?;
+
+        let backup_path = test_dir.path().join("backup");
+        let mut backup_file = File::create(backup_path)?;
+        write!(
+            backup_file,
+            "Some really old data that should be overwritten."
+        )?;
+        assert!(test_dir.path().join("backup").exists());
+
+        let test_content = "Some test content".to_string();
+        let source_path = test_dir.path().join("source_test_data");
+        let mut source_file = File::create(source_path)?;
+        write!(source_file, "{}", test_content)?;
+
+        // Test the copy_file function
+        let backup_path = test_dir.path().join("backup");
+        let source_path_created = test_dir.path().join("source_test_data");
+        copy_file(
+            &source_path_created,
+            &backup_path,
+            &CancelToken::new(),
+            |_| {},
+        )?;
+
+        let mut created_backup_file = File::open(backup_path)?;
+        let mut buf = String::new();
+        created_backup_file.read_to_string(&mut buf)?;
+        assert_eq!(buf, test_content);
+
+        //Cleanup
+        drop(source_file);
+        test_dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_create_backup_path_not_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let name = OsStr::from_bytes(b"caf\xe9.txt");
+        let source_file_path = Path::new("/home/bob").join(name);
+        let backup_dir_path = PathBuf::from("/media/backup");
+
+        let backup_path = create_backup_file_path(&source_file_path, &backup_dir_path);
+
+        assert_eq!(
+            PathBuf::from("/media/backup/home/bob").join(name),
+            backup_path
+        );
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_create_backup_path() {
+        // With source file: C:/Users/bob/Documents/test.txt
+        // and backup directory C:/Users/bob/Backup it will create a path of
+        //  C:/Users/bob/Backup/c/Users/bob/Documents/test.txt
+
+        let mut source_file_path = PathBuf::from("C:/Users/bob/Documents/test.txt");
+        let mut backup_dir_path = PathBuf::from("C:/Users/bob/Backup");
+
+        let mut backup_path = create_backup_file_path(&source_file_path, &backup_dir_path);
+
+        assert_eq!(
+            PathBuf::from("C:/Users/bob/Backup/C/Users/bob/Documents/test.txt"),
+            backup_path
+        );
+
+        // With other drives
+        // With source file: D:/Users/bob/Documents/test.txt
+        // and backup directory G:/Users/bob/Backup it will create a path of
+        //  G:/Users/bob/Backup/D/Users/bob/Documents/test.txt
+        source_file_path = PathBuf::from("D:/Users/bob/Documents/test.txt");
+        backup_dir_path = PathBuf::from("G:/Users/bob/Backup");
+
+        backup_path = create_backup_file_path(&source_file_path, &backup_dir_path);
+
+        assert_eq!(
+            PathBuf::from("G:/Users/bob/Backup/D/Users/bob/Documents/test.txt"),
+            backup_path
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_create_backup_path() {
+        // With source file: /home/bob/Documents/test.txt
+        // and backup directory /media/backup it will create a path of
+        //  /media/backup/home/bob/Documents/test.txt
+        let mut source_file_path = PathBuf::from("/home/bob/Documents/test.txt");
+        let mut backup_dir_path = PathBuf::from("/media/backup");
+
+        let mut backup_path = create_backup_file_path(&source_file_path, &backup_dir_path);
+
+        assert_eq!(
+            PathBuf::from("/media/backup/home/bob/Documents/test.txt"),
+            backup_path
+        );
+
+        // A file at the root stays in the backup directory
+        source_file_path = PathBuf::from("/test.txt");
+        backup_dir_path = PathBuf::from("/home/bob/Backup");
+
+        backup_path = create_backup_file_path(&source_file_path, &backup_dir_path);
+
+        assert_eq!(PathBuf::from("/home/bob/Backup/test.txt"), backup_path);
+    }
+}
@@ -0,0 +1,787 @@
+//! Include and exclude rules of a profile, given in the configuration.
+//!
+//! Every rule has an `action` and any number of conditions, which all have to match. The rules
+//! are evaluated in order and the first matching rule decides: `include` backs the item up
+//! regardless of the [built-in rules](crate::rules), `exclude` leaves it out. Items that no
+//! rule matches are left to the built-in rules.
+//!
+//! ```toml
+//! [[profiles.documents.rules]]
+//! action = "include"
+//! glob = "*.log"
+//! regex = "^projects/[^/]+/logs/"
+//!
+//! [[profiles.documents.rules]]
+//! action = "exclude"
+//! extensions = ["log", "tmp"]
+//!
+//! [[profiles.documents.rules]]
+//! action = "exclude"
+//! type = "file"
+//! min_size = "1GB"
+//! older_than = "1year"
+//! ```
+//!
+//! The conditions are:
+//!
+//! * `glob`: a glob pattern matched against the name of the item, or against its path relative
+//!   to the source if the pattern contains a `/`. `*` does not match `/`, `**` does.
+//! * `regex`: a regular expression searched in the path relative to the source, with `/`
+//!   separators.
+//! * `extensions`: a list of extensions, ignoring their case.
+//! * `min_size` and `max_size`: sizes of files, in bytes or as a size such as `"10MB"`.
+//! * `older_than` and `newer_than`: the time since the item was modified, such as `"30days"`.
+//! * `hidden`: whether the name of the item starts with a `.` (or it is hidden on Windows).
+//! * `owner` and `group`: the user or group owning the item, by name or id (only on Unix).
+//! * `type`: `file`, `directory` or `symlink`.
+//!
+//! A profile can also be restricted to some paths with [`IncludePattern`]s, in which case
+//! only these paths and the directories leading to them are walked:
+//!
+//! ```toml
+//! [profiles.home]
+//! include = ["Documents", ".ssh", ".bashrc", "projects/*/notes/**/*.md"]
+//! ```
+//!
+use crate::config::Size;
+use rebackup::glob::{MatchOptions, Pattern};
+use rebackup::{WalkerRule, WalkerRuleResult};
+use regex::Regex;
+use serde::Deserialize;
+use std::fmt;
+use std::fs::Metadata;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// What a [`FilterRule`] does with the items it matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    Include,
+    Exclude,
+}
+
+/// The type of an item matched by a [`FilterRule`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemType {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// A rule of a profile, see the [module documentation](self).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RawFilterRule")]
+pub struct FilterRule {
+    pub action: FilterAction,
+    pub glob: Option<Pattern>,
+    pub regex: Option<Regex>,
+    /// Lowercase extensions without the leading dot.
+    pub extensions: Vec<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub older_than: Option<Duration>,
+    pub newer_than: Option<Duration>,
+    pub hidden: Option<bool>,
+    pub owner: Option<u32>,
+    pub group: Option<u32>,
+    pub item_type: Option<ItemType>,
+}
+
+impl FilterRule {
+    /// A rule without conditions, matching every item.
+    pub fn new(action: FilterAction) -> Self {
+        FilterRule {
+            action,
+            glob: None,
+            regex: None,
+            extensions: Vec::new(),
+            min_size: None,
+            max_size: None,
+            older_than: None,
+            newer_than: None,
+            hidden: None,
+            owner: None,
+            group: None,
+            item_type: None,
+        }
+    }
+
+    /// `true` if all conditions match the item at `path` in the `source` directory.
+    pub fn matches(&self, path: &Path, source: &Path, now: SystemTime) -> io::Result<bool> {
+        let relative = path.strip_prefix(source).unwrap_or(path);
+        let relative_str = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+
+        if let Some(glob) = &self.glob {
+            let options = MatchOptions {
+                case_sensitive: true,
+                require_literal_separator: true,
+                require_literal_leading_dot: false,
+            };
+            let subject = if glob.as_str().contains('/') {
+                &relative_str
+            } else {
+                name.as_ref()
+            };
+            if !glob.matches_with(subject, options) {
+                return Ok(false);
+            }
+        }
+        if self
+            .regex
+            .as_ref()
+            .is_some_and(|regex| !regex.is_match(&relative_str))
+        {
+            return Ok(false);
+        }
+        if !self.extensions.is_empty() {
+            let extension = path
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase());
+            if !extension.is_some_and(|ext| self.extensions.contains(&ext)) {
+                return Ok(false);
+            }
+        }
+
+        // The remaining conditions need the metadata, which is only read if they are used
+        if self.min_size.is_none()
+            && self.max_size.is_none()
+            && self.older_than.is_none()
+            && self.newer_than.is_none()
+            && self.hidden.is_none()
+            && self.owner.is_none()
+            && self.group.is_none()
+            && self.item_type.is_none()
+        {
+            return Ok(true);
+        }
+        let metadata = path.symlink_metadata()?;
+
+        if let Some(item_type) = self.item_type {
+            let file_type = metadata.file_type();
+            let matches = match item_type {
+                ItemType::File => file_type.is_file(),
+                ItemType::Directory => file_type.is_dir(),
+                ItemType::Symlink => file_type.is_symlink(),
+            };
+            if !matches {
+                return Ok(false);
+            }
+        }
+        if self.min_size.is_some() || self.max_size.is_some() {
+            // Only files have a size that means something
+            if !metadata.is_file()
+                || self.min_size.is_some_and(|min| metadata.len() < min)
+                || self.max_size.is_some_and(|max| metadata.len() > max)
+            {
+                return Ok(false);
+            }
+        }
+        if self.older_than.is_some() || self.newer_than.is_some() {
+            // A modification time in the future has an age of 0
+            let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+            if self.older_than.is_some_and(|older_than| age <= older_than)
+                || self.newer_than.is_some_and(|newer_than| age >= newer_than)
+            {
+                return Ok(false);
+            }
+        }
+        if self
+            .hidden
+            .is_some_and(|hidden| is_hidden(&name, &metadata) != hidden)
+        {
+            return Ok(false);
+        }
+        if self.owner.is_some() || self.group.is_some() {
+            let (uid, gid) = ownership(&metadata);
+            if self.owner.is_some_and(|owner| Some(owner) != uid)
+                || self.group.is_some_and(|group| Some(group) != gid)
+            {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// The first of `rules` matching the item at `path` in the `source` directory, with its index.
+pub fn first_match<'a>(
+    rules: &'a [FilterRule],
+    path: &Path,
+    source: &Path,
+    now: SystemTime,
+) -> io::Result<Option<(usize, &'a FilterRule)>> {
+    for (i, rule) in rules.iter().enumerate() {
+        if rule.matches(path, source, now)? {
+            return Ok(Some((i, rule)));
+        }
+    }
+    Ok(None)
+}
+
+impl fmt::Display for FilterRule {
+    /// The rule as it is written in the configuration, on one line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut conditions = Vec::new();
+        if let Some(glob) = &self.glob {
+            conditions.push(format!("glob = {:?}", glob.as_str()));
+        }
+        if let Some(regex) = &self.regex {
+            conditions.push(format!("regex = {:?}", regex.as_str()));
+        }
+        if !self.extensions.is_empty() {
+            conditions.push(format!("extensions = {:?}", self.extensions));
+        }
+        if let Some(size) = self.min_size {
+            conditions.push(format!("min_size = {}", size));
+        }
+        if let Some(size) = self.max_size {
+            conditions.push(format!("max_size = {}", size));
+        }
+        if let Some(age) = self.older_than {
+            conditions.push(format!(
+                "older_than = \"{}\"",
+                humantime::format_duration(age)
+            ));
+        }
+        if let Some(age) = self.newer_than {
+            conditions.push(format!(
+                "newer_than = \"{}\"",
+                humantime::format_duration(age)
+            ));
+        }
+        if let Some(hidden) = self.hidden {
+            conditions.push(format!("hidden = {}", hidden));
+        }
+        if let Some(owner) = self.owner {
+            conditions.push(format!("owner = {}", owner));
+        }
+        if let Some(group) = self.group {
+            conditions.push(format!("group = {}", group));
+        }
+        if let Some(item_type) = self.item_type {
+            conditions.push(format!("type = \"{:?}\"", item_type).to_lowercase());
+        }
+
+        let action = match self.action {
+            FilterAction::Include => "include",
+            FilterAction::Exclude => "exclude",
+        };
+        write!(f, "action = \"{}\"", action)?;
+        for condition in conditions {
+            write!(f, ", {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+/// Rule applying the `rules` of a profile, the first matching rule decides.
+pub fn filter_rule(rules: Vec<FilterRule>) -> WalkerRule {
+    WalkerRule {
+        name: "config",
+        description: Some("Rules of the profile".to_string()),
+        only_for: None,
+        matches: Box::new(|_, _, _| true),
+        action: Box::new(move |path, _, source| {
+            match first_match(&rules, path, source, SystemTime::now())? {
+                Some((_, rule)) if rule.action == FilterAction::Include => {
+                    Ok(WalkerRuleResult::IncludeItemAbsolute)
+                }
+                Some(_) => Ok(WalkerRuleResult::ExcludeItem),
+                None => Ok(WalkerRuleResult::SkipRule),
+            }
+        }),
+    }
+}
+
+/// A path a profile is restricted to with `include`, relative to the source. `*` does not
+/// match `/`, a `**` component matches any number of directories.
+#[derive(Debug, Clone)]
+pub struct IncludePattern {
+    pattern: String,
+    components: Vec<Component>,
+}
+
+/// A component of an [`IncludePattern`].
+#[derive(Debug, Clone)]
+enum Component {
+    /// `**`, any number of components.
+    Any,
+    Glob(Pattern),
+}
+
+impl IncludePattern {
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        let trimmed = pattern.trim_matches('/');
+        if trimmed.is_empty() {
+            return Err(format!("Invalid include pattern {:?}", pattern));
+        }
+        let components = trimmed
+            .split('/')
+            .filter(|component| !component.is_empty() && *component != ".")
+            .map(|component| match component {
+                "**" => Ok(Component::Any),
+                ".." => Err(format!("Include pattern {} leaves the source", pattern)),
+                glob => Pattern::new(glob)
+                    .map(Component::Glob)
+                    .map_err(|err| format!("Invalid include pattern {}: {}", pattern, err)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(IncludePattern {
+            pattern: pattern.to_string(),
+            components,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// `true` if the path with the `relative` components matches the pattern.
+    pub fn matches(&self, relative: &[&str]) -> bool {
+        match_components(&self.components, relative, false)
+    }
+
+    /// `true` if the directory with the `relative` components may contain items matching the
+    /// pattern, so that it has to be walked.
+    pub fn may_contain(&self, relative: &[&str]) -> bool {
+        match_components(&self.components, relative, true)
+    }
+}
+
+impl<'de> Deserialize<'de> for IncludePattern {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        IncludePattern::new(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Matches the `path` components against the `pattern` components. With `prefix` the path
+/// only has to match the start of the pattern.
+fn match_components(pattern: &[Component], path: &[&str], prefix: bool) -> bool {
+    let options = MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    };
+    match (pattern.first(), path.first()) {
+        (None, _) => path.is_empty(),
+        (Some(_), None) => prefix || pattern.iter().all(|c| matches!(c, Component::Any)),
+        (Some(Component::Any), Some(_)) => {
+            match_components(&pattern[1..], path, prefix)
+                || match_components(pattern, &path[1..], prefix)
+        }
+        (Some(Component::Glob(glob)), Some(name)) => {
+            glob.matches_with(name, options) && match_components(&pattern[1..], &path[1..], prefix)
+        }
+    }
+}
+
+/// Whether an item is in the paths the `patterns` restrict a backup to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inclusion {
+    /// The item or one of its ancestors matches a pattern.
+    Matching,
+    /// The item is a directory that may contain matching items.
+    Ancestor,
+    /// The item is not in the paths.
+    Outside,
+}
+
+/// Whether the item at `path` in the `source` directory is in the paths of the `patterns`.
+pub fn inclusion(patterns: &[IncludePattern], path: &Path, source: &Path) -> Inclusion {
+    let relative = path.strip_prefix(source).unwrap_or(path);
+    let components: Vec<_> = relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect();
+    let components: Vec<&str> = components.iter().map(|c| c.as_ref()).collect();
+
+    // Everything below a matching directory is included
+    let matching =
+        (1..=components.len()).any(|len| patterns.iter().any(|p| p.matches(&components[..len])));
+    if matching {
+        Inclusion::Matching
+    } else if path.is_dir() && patterns.iter().any(|p| p.may_contain(&components)) {
+        Inclusion::Ancestor
+    } else {
+        Inclusion::Outside
+    }
+}
+
+/// Rule restricting a backup to the paths matching the `patterns` and their ancestors.
+/// Directories that cannot contain matching items are not walked. The items in the paths
+/// are left to the following rules, so that they can still be excluded.
+pub fn include_rule(patterns: Vec<IncludePattern>) -> WalkerRule {
+    WalkerRule {
+        name: "include",
+        description: Some("Only back up the included paths".to_string()),
+        only_for: None,
+        matches: Box::new(|_, _, _| true),
+        action: Box::new(
+            move |path, _, source| match inclusion(&patterns, path, source) {
+                Inclusion::Matching | Inclusion::Ancestor => Ok(WalkerRuleResult::SkipRule),
+                Inclusion::Outside => Ok(WalkerRuleResult::ExcludeItem),
+            },
+        ),
+    }
+}
+
+#[cfg(windows)]
+fn is_hidden(name: &str, metadata: &Metadata) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    name.starts_with('.') || metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0
+}
+
+#[cfg(not(windows))]
+fn is_hidden(name: &str, _metadata: &Metadata) -> bool {
+    name.starts_with('.')
+}
+
+/// The user and group ids owning an item.
+#[cfg(unix)]
+fn ownership(metadata: &Metadata) -> (Option<u32>, Option<u32>) {
+    use std::os::unix::fs::MetadataExt;
+    (Some(metadata.uid()), Some(metadata.gid()))
+}
+
+/// Items have no user and group ids on this platform, so no owner matches.
+#[cfg(not(unix))]
+fn ownership(_metadata: &Metadata) -> (Option<u32>, Option<u32>) {
+    (None, None)
+}
+
+/// A user or group given by id or name.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Id {
+    Number(u32),
+    Name(String),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawFilterRule {
+    action: FilterAction,
+    glob: Option<String>,
+    regex: Option<String>,
+    #[serde(default)]
+    extensions: Vec<String>,
+    min_size: Option<Size>,
+    max_size: Option<Size>,
+    older_than: Option<String>,
+    newer_than: Option<String>,
+    hidden: Option<bool>,
+    owner: Option<Id>,
+    group: Option<Id>,
+    #[serde(rename = "type")]
+    item_type: Option<ItemType>,
+}
+
+impl TryFrom<RawFilterRule> for FilterRule {
+    type Error = String;
+
+    fn try_from(raw: RawFilterRule) -> Result<Self, Self::Error> {
+        let duration = |duration: Option<String>| {
+            duration
+                .map(|duration| {
+                    humantime::parse_duration(&duration)
+                        .map_err(|err| format!("Invalid age {}: {}", duration, err))
+                })
+                .transpose()
+        };
+
+        Ok(FilterRule {
+            action: raw.action,
+            glob: raw
+                .glob
+                .map(|glob| {
+                    // A pattern relative to the source may start with a `/`
+                    Pattern::new(glob.strip_prefix('/').unwrap_or(&glob))
+                        .map_err(|err| format!("Invalid glob pattern {}: {}", glob, err))
+                })
+                .transpose()?,
+            regex: raw
+                .regex
+                .map(|regex| {
+                    Regex::new(&regex)
+                        .map_err(|err| format!("Invalid regular expression {}: {}", regex, err))
+                })
+                .transpose()?,
+            extensions: raw
+                .extensions
+                .iter()
+                .map(|ext| ext.trim_start_matches('.').to_lowercase())
+                .collect(),
+            min_size: raw.min_size.map(Size::bytes).transpose()?,
+            max_size: raw.max_size.map(Size::bytes).transpose()?,
+            older_than: duration(raw.older_than)?,
+            newer_than: duration(raw.newer_than)?,
+            hidden: raw.hidden,
+            owner: raw.owner.map(|id| resolve_id(id, user_id)).transpose()?,
+            group: raw.group.map(|id| resolve_id(id, group_id)).transpose()?,
+            item_type: raw.item_type,
+        })
+    }
+}
+
+fn resolve_id(id: Id, by_name: fn(&str) -> Result<u32, String>) -> Result<u32, String> {
+    match id {
+        Id::Number(id) => Ok(id),
+        Id::Name(name) => by_name(&name),
+    }
+}
+
+/// The id of the user called `name`.
+#[cfg(unix)]
+fn user_id(name: &str) -> Result<u32, String> {
+    let c_name = std::ffi::CString::new(name).map_err(|_| format!("Unknown user {}", name))?;
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    // SAFETY: an all-zero passwd is valid, it only contains integers and null pointers.
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    // SAFETY: the name is a valid C string, and the buffer is valid for its length. The
+    // strings in the result point into the buffer, but only the id is read.
+    let ret = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret == 0 && !result.is_null() {
+        Ok(passwd.pw_uid)
+    } else {
+        Err(format!("Unknown user {}", name))
+    }
+}
+
+/// The id of the group called `name`.
+#[cfg(unix)]
+fn group_id(name: &str) -> Result<u32, String> {
+    let c_name = std::ffi::CString::new(name).map_err(|_| format!("Unknown group {}", name))?;
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    // SAFETY: an all-zero group is valid, it only contains integers and null pointers.
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    // SAFETY: as for `getpwnam_r` above.
+    let ret = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut group,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret == 0 && !result.is_null() {
+        Ok(group.gr_gid)
+    } else {
+        Err(format!("Unknown group {}", name))
+    }
+}
+
+#[cfg(not(unix))]
+fn user_id(name: &str) -> Result<u32, String> {
+    Err(format!(
+        "Cannot look up the user {}, owners are only supported on Unix",
+        name
+    ))
+}
+
+#[cfg(not(unix))]
+fn group_id(name: &str) -> Result<u32, String> {
+    Err(format!(
+        "Cannot look up the group {}, groups are only supported on Unix",
+        name
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+
+    fn rules(toml: &str) -> Vec<FilterRule> {
+        #[derive(Deserialize)]
+        struct Rules {
+            rules: Vec<FilterRule>,
+        }
+        toml::from_str::<Rules>(toml)
+            .expect("Failed to parse the rules")
+            .rules
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let parsed = rules(
+            r#"
+            [[rules]]
+            action = "exclude"
+            extensions = [".LOG", "tmp"]
+            min_size = "1kB"
+            older_than = "30days"
+            type = "file"
+
+            [[rules]]
+            action = "include"
+            owner = 0
+            group = 0
+            hidden = true
+            "#,
+        );
+        assert_eq!(parsed[0].action, FilterAction::Exclude);
+        assert_eq!(parsed[0].extensions, vec!["log", "tmp"]);
+        assert_eq!(parsed[0].min_size, Some(1000));
+        assert_eq!(
+            parsed[0].older_than,
+            Some(Duration::from_secs(30 * 24 * 60 * 60))
+        );
+        assert_eq!(parsed[0].item_type, Some(ItemType::File));
+        assert_eq!(parsed[1].action, FilterAction::Include);
+        assert_eq!(parsed[1].owner, Some(0));
+        assert_eq!(
+            parsed[0].to_string(),
+            "action = \"exclude\", extensions = [\"log\", \"tmp\"], min_size = 1000, \
+             older_than = \"30days\", type = \"file\""
+        );
+
+        #[cfg(unix)]
+        assert_eq!(
+            rules("[[rules]]\naction = \"include\"\nowner = \"root\"")[0].owner,
+            Some(0)
+        );
+
+        for invalid in [
+            "action = \"ignore\"",
+            "action = \"exclude\"\nglob = \"[\"",
+            "action = \"exclude\"\nregex = \"(\"",
+            "action = \"exclude\"\nolder_than = \"old\"",
+            "action = \"exclude\"\nmax_size = \"big\"",
+            "action = \"exclude\"\nowner = \"no such user\"",
+            "action = \"exclude\"\npath = \"/tmp\"",
+        ] {
+            #[derive(Debug, Deserialize)]
+            #[allow(dead_code)]
+            struct Rules {
+                rules: Vec<FilterRule>,
+            }
+            let toml = format!("[[rules]]\n{}", invalid);
+            assert!(toml::from_str::<Rules>(&toml).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_first_match() -> io::Result<()> {
+        let test_dir = tempfile::tempdir()?;
+        let source = test_dir.path();
+        fs::create_dir_all(source.join("projects/app/logs"))?;
+        fs::create_dir_all(source.join(".cache"))?;
+        fs::write(source.join("projects/app/logs/today.log"), b"log")?;
+        fs::write(source.join("projects/app/debug.LOG"), b"debug")?;
+        fs::write(source.join("projects/app/video.mp4"), vec![0; 2000])?;
+        File::create(source.join("notes.txt"))?;
+
+        let rules = rules(
+            r#"
+            [[rules]]
+            action = "include"
+            regex = "^projects/[^/]+/logs/"
+
+            [[rules]]
+            action = "exclude"
+            extensions = ["log"]
+
+            [[rules]]
+            action = "exclude"
+            glob = "projects/*/*.mp4"
+            min_size = "1kB"
+
+            [[rules]]
+            action = "exclude"
+            hidden = true
+            type = "directory"
+
+            [[rules]]
+            action = "exclude"
+            glob = "*.txt"
+            older_than = "1h"
+            "#,
+        );
+        let now = SystemTime::now();
+        let first = |path: &str| {
+            first_match(&rules, &source.join(path), source, now)
+                .expect("Failed to match")
+                .map(|(i, _)| i)
+        };
+
+        assert_eq!(first("projects/app/logs/today.log"), Some(0));
+        assert_eq!(first("projects/app/debug.LOG"), Some(1));
+        assert_eq!(first("projects/app/video.mp4"), Some(2));
+        assert_eq!(first(".cache"), Some(3));
+        assert_eq!(first("projects"), None);
+        // The notes have just been written
+        assert_eq!(first("notes.txt"), None);
+        assert_eq!(
+            first_match(
+                &rules,
+                &source.join("notes.txt"),
+                source,
+                now + Duration::from_secs(2 * 60 * 60)
+            )?
+            .map(|(i, _)| i),
+            Some(4)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_include_patterns() -> io::Result<()> {
+        let test_dir = tempfile::tempdir()?;
+        let source = test_dir.path();
+        fs::create_dir_all(source.join("projects/app/notes/2024"))?;
+        fs::create_dir_all(source.join("projects/app/src"))?;
+        fs::create_dir_all(source.join("Downloads"))?;
+        File::create(source.join(".bashrc"))?;
+
+        let patterns: Vec<IncludePattern> = ["/Documents/", ".bashrc", "projects/*/notes/**/*.md"]
+            .into_iter()
+            .map(|pattern| IncludePattern::new(pattern).unwrap())
+            .collect();
+        let inclusion = |path: &str| inclusion(&patterns, &source.join(path), source);
+
+        assert_eq!(inclusion(".bashrc"), Inclusion::Matching);
+        assert_eq!(inclusion(".profile"), Inclusion::Outside);
+        assert_eq!(inclusion("Documents/taxes/2024.pdf"), Inclusion::Matching);
+        assert_eq!(inclusion("Downloads"), Inclusion::Outside);
+        assert_eq!(inclusion("projects"), Inclusion::Ancestor);
+        assert_eq!(inclusion("projects/app/notes/2024"), Inclusion::Ancestor);
+        assert_eq!(inclusion("projects/app/notes/todo.md"), Inclusion::Matching);
+        assert_eq!(
+            inclusion("projects/app/notes/2024/june.md"),
+            Inclusion::Matching
+        );
+        assert_eq!(inclusion("projects/app/notes/todo.txt"), Inclusion::Outside);
+        assert_eq!(inclusion("projects/app/src"), Inclusion::Outside);
+
+        assert!(IncludePattern::new("../etc").is_err());
+        assert!(IncludePattern::new("/").is_err());
+        assert!(IncludePattern::new("[").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,454 @@
+//! History of the backup runs.
+//!
+//! Every run is appended as one JSON line to a history file. The CLI keeps one history in the
+//! `.rackup` directory on the destination and one in the local data directory.
+//!
+//! A run is also recorded locally when it starts, as [running](RunStatus::Running), so that a
+//! run that is killed or loses power still shows up. The record it appends when it has
+//! finished replaces that one, as both have the same [id](RunRecord::id).
+//!
+use crate::error::{self, Error};
+use crate::report::BackupReport;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+/// The directory on the destination where rackup keeps its own files.
+pub const METADATA_DIR: &str = ".rackup";
+
+/// The name of the history file.
+pub const HISTORY_FILE: &str = "history.jsonl";
+
+/// How a run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    /// All files have been backed up.
+    Success,
+    /// Some files could not be backed up.
+    PartialFailure,
+    /// The backup was interrupted before all files were backed up. It stays incomplete
+    /// until it is resumed, which is recorded as a run of its own.
+    Incomplete,
+    /// The backup could not run.
+    Error,
+    /// The backup has started and not finished yet. A run that has been killed stays
+    /// running in the history, it can be told apart as its lock is not held anymore.
+    Running,
+}
+
+/// The record of a single run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RunRecord {
+    /// Identifies the records of the same run, a later one replaces the earlier ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+    #[serde(with = "timestamp")]
+    pub started: SystemTime,
+    #[serde(with = "timestamp")]
+    pub finished: SystemTime,
+    /// The profile that was run, `None` for a backup given on the command line.
+    pub profile: Option<String>,
+    pub sources: Vec<PathBuf>,
+    pub destination: PathBuf,
+    pub copied: usize,
+    pub unchanged: usize,
+    pub failed: usize,
+    pub bytes_copied: u64,
+    /// The error messages of the run.
+    pub errors: Vec<String>,
+    pub status: RunStatus,
+    /// The exit code of the process.
+    pub exit_code: u8,
+}
+
+impl RunRecord {
+    /// Creates the record of a run that started at `started` and has just finished.
+    pub fn new(
+        started: SystemTime,
+        profile: Option<&str>,
+        sources: &[&Path],
+        destination: &Path,
+        result: &error::Result<BackupReport>,
+        exit_code: u8,
+    ) -> Self {
+        let mut record = RunRecord {
+            id: None,
+            started,
+            finished: SystemTime::now(),
+            profile: profile.map(str::to_string),
+            sources: sources.iter().map(|s| s.to_path_buf()).collect(),
+            destination: destination.to_path_buf(),
+            copied: 0,
+            unchanged: 0,
+            failed: 0,
+            bytes_copied: 0,
+            errors: Vec::new(),
+            status: RunStatus::Success,
+            exit_code,
+        };
+
+        match result {
+            Ok(report) => {
+                record.copied = report.copied();
+                record.unchanged = report.unchanged();
+                record.failed = report.failed();
+                record.bytes_copied = report.bytes_copied();
+                record.errors = report.errors().map(|(_, err)| err.to_string()).collect();
+                if report.interrupted {
+                    record.status = RunStatus::Incomplete;
+                } else if !report.is_success() {
+                    record.status = RunStatus::PartialFailure;
+                }
+            }
+            Err(err) => {
+                record.errors = vec![err.to_string()];
+                record.status = RunStatus::Error;
+            }
+        }
+
+        record
+    }
+
+    /// Creates the record of a run that started at `started` and is [running](RunStatus::Running),
+    /// to be replaced by the one created with [`RunRecord::new`] when it finishes. Its exit
+    /// code is the one of an interrupted run, `exit_code`, for the case it never finishes.
+    pub fn unfinished(
+        started: SystemTime,
+        profile: Option<&str>,
+        sources: &[&Path],
+        destination: &Path,
+        exit_code: u8,
+    ) -> Self {
+        RunRecord {
+            id: Some(Uuid::new_v4()),
+            started,
+            finished: started,
+            profile: profile.map(str::to_string),
+            sources: sources.iter().map(|s| s.to_path_buf()).collect(),
+            destination: destination.to_path_buf(),
+            copied: 0,
+            unchanged: 0,
+            failed: 0,
+            bytes_copied: 0,
+            errors: vec!["The run has not finished".to_string()],
+            status: RunStatus::Running,
+            exit_code,
+        }
+    }
+
+    /// The name under which the run is shown: the profile, or the source and destination
+    /// for a backup given on the command line.
+    pub fn label(&self) -> String {
+        match &self.profile {
+            Some(profile) => profile.clone(),
+            None => format!(
+                "{} -> {}",
+                self.sources
+                    .iter()
+                    .map(|s| s.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                self.destination.to_string_lossy()
+            ),
+        }
+    }
+}
+
+/// The recent runs of a profile.
+#[derive(Debug, Clone)]
+pub struct ProfileStatus {
+    /// See [`RunRecord::label`].
+    pub label: String,
+    /// The most recent runs, the latest first.
+    pub last_runs: Vec<RunRecord>,
+    /// When the last successful run finished.
+    pub last_success: Option<SystemTime>,
+}
+
+impl ProfileStatus {
+    /// `true` if the profile has not been backed up successfully within `max_age`.
+    pub fn is_stale(&self, max_age: Duration, now: SystemTime) -> bool {
+        match self.last_success {
+            Some(last_success) => now
+                .duration_since(last_success)
+                .is_ok_and(|age| age > max_age),
+            None => true,
+        }
+    }
+}
+
+/// A line of a history file.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum HistoryLine {
+    Run(RunRecord),
+    /// The records of the run `discarded` are left out, as the run did not happen.
+    Discarded {
+        discarded: Uuid,
+    },
+}
+
+/// A history file.
+#[derive(Debug, Clone)]
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    /// The history in the file `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        History { path: path.into() }
+    }
+
+    /// The history kept on the destination of a backup.
+    pub fn on_destination(destination: &Path) -> Self {
+        History::new(destination.join(METADATA_DIR).join(HISTORY_FILE))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends the record of a run.
+    pub fn append(&self, record: &RunRecord) -> io::Result<()> {
+        self.append_line(&HistoryLine::Run(record.clone()))
+    }
+
+    /// Leaves out the records of the run `id`, e.g. of a run that has been recorded as
+    /// started but could not run because another one is running.
+    pub fn discard(&self, id: Uuid) -> io::Result<()> {
+        self.append_line(&HistoryLine::Discarded { discarded: id })
+    }
+
+    fn append_line(&self, line: &HistoryLine) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut line = serde_json::to_string(line)?;
+        line.push('\n');
+
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(line.as_bytes())
+    }
+
+    /// All the records in the order they were appended. A record that has been replaced by a
+    /// later one of the same run is left out, as are the records of discarded runs.
+    ///
+    /// Lines that cannot be read, e.g. because a run was killed while writing its record,
+    /// are skipped.
+    pub fn records(&self) -> error::Result<Vec<RunRecord>> {
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(source) => {
+                return Err(Error::History {
+                    path: self.path.clone(),
+                    source,
+                })
+            }
+        };
+
+        let mut records: Vec<RunRecord> = Vec::new();
+        let mut discarded = HashSet::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|source| Error::History {
+                path: self.path.clone(),
+                source,
+            })?;
+            match serde_json::from_str(&line) {
+                Ok(HistoryLine::Run(record)) => records.push(record),
+                Ok(HistoryLine::Discarded { discarded: id }) => {
+                    discarded.insert(id);
+                }
+                Err(_) => {}
+            }
+        }
+
+        // The last record of every run
+        let mut last = HashMap::new();
+        for (index, record) in records.iter().enumerate() {
+            if let Some(id) = record.id {
+                last.insert(id, index);
+            }
+        }
+        let records = records
+            .into_iter()
+            .enumerate()
+            .filter(|(index, record)| match record.id {
+                Some(id) => last[&id] == *index && !discarded.contains(&id),
+                None => true,
+            })
+            .map(|(_, record)| record)
+            .collect();
+
+        Ok(records)
+    }
+
+    /// The status of every profile in the history, with up to `runs` of its latest runs.
+    pub fn status(&self, runs: usize) -> error::Result<Vec<ProfileStatus>> {
+        let mut by_label: BTreeMap<String, ProfileStatus> = BTreeMap::new();
+
+        for record in self.records()?.into_iter().rev() {
+            let status = by_label
+                .entry(record.label())
+                .or_insert_with_key(|label| ProfileStatus {
+                    label: label.clone(),
+                    last_runs: Vec::new(),
+                    last_success: None,
+                });
+
+            if status.last_success.is_none() && record.status == RunStatus::Success {
+                status.last_success = Some(record.finished);
+            }
+            if status.last_runs.len() < runs {
+                status.last_runs.push(record);
+            }
+        }
+
+        Ok(by_label.into_values().collect())
+    }
+}
+
+/// (De)serialization of timestamps as RFC 3339 strings.
+pub(crate) mod timestamp {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use std::time::SystemTime;
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&humantime::format_rfc3339_seconds(*time).to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        humantime::parse_rfc3339_weak(&s).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(profile: &str, status: RunStatus, finished: SystemTime) -> RunRecord {
+        RunRecord {
+            id: None,
+            started: finished,
+            finished,
+            profile: Some(profile.to_string()),
+            sources: vec![PathBuf::from("/home/bob")],
+            destination: PathBuf::from("/media/backup"),
+            copied: 1,
+            unchanged: 2,
+            failed: 0,
+            bytes_copied: 3,
+            errors: Vec::new(),
+            status,
+            exit_code: 0,
+        }
+    }
+
+    #[test]
+    fn test_append_and_read() -> Result<(), Error> {
+        let test_dir = tempfile::tempdir().unwrap();
+        let history = History::on_destination(test_dir.path());
+
+        assert!(history.records()?.is_empty());
+
+        // Timestamps are stored with a precision of seconds
+        let finished = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let first = record("documents", RunStatus::Success, finished);
+        let second = record("music", RunStatus::Error, finished);
+        history.append(&first).unwrap();
+        history.append(&second).unwrap();
+
+        assert!(test_dir.path().join(".rackup/history.jsonl").exists());
+        assert_eq!(history.records()?, vec![first, second]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unfinished_run() -> Result<(), Error> {
+        let test_dir = tempfile::tempdir().unwrap();
+        let history = History::new(test_dir.path().join("history.jsonl"));
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let sources = [Path::new("/home/bob")];
+        let destination = Path::new("/media/backup");
+
+        // A run that was killed stays running
+        let killed = RunRecord::unfinished(now, Some("documents"), &sources, destination, 130);
+        history.append(&killed).unwrap();
+
+        // A run that finished replaces its record
+        let started = RunRecord::unfinished(now, Some("music"), &sources, destination, 130);
+        history.append(&started).unwrap();
+        let other = record("photos", RunStatus::Success, now);
+        history.append(&other).unwrap();
+        let finished = RunRecord {
+            id: started.id,
+            ..record("music", RunStatus::Success, now)
+        };
+        history.append(&finished).unwrap();
+
+        // A run that did not happen is left out
+        let locked = RunRecord::unfinished(now, Some("videos"), &sources, destination, 130);
+        history.append(&locked).unwrap();
+        history.discard(locked.id.unwrap()).unwrap();
+
+        assert_eq!(history.records()?, vec![killed.clone(), other, finished]);
+        assert_eq!(killed.status, RunStatus::Running);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_status() -> Result<(), Error> {
+        let test_dir = tempfile::tempdir().unwrap();
+        let history = History::new(test_dir.path().join("history.jsonl"));
+
+        let day = Duration::from_secs(24 * 60 * 60);
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        history
+            .append(&record("documents", RunStatus::Success, now - 10 * day))
+            .unwrap();
+        history
+            .append(&record("documents", RunStatus::PartialFailure, now - day))
+            .unwrap();
+        history
+            .append(&record("music", RunStatus::Success, now - 9 * day))
+            .unwrap();
+        history
+            .append(&record("music", RunStatus::Success, now - day))
+            .unwrap();
+        history
+            .append(&record("photos", RunStatus::Error, now))
+            .unwrap();
+
+        let status = history.status(1)?;
+        assert_eq!(status.len(), 3);
+
+        assert_eq!(status[0].label, "documents");
+        assert_eq!(status[0].last_runs.len(), 1);
+        assert_eq!(status[0].last_runs[0].status, RunStatus::PartialFailure);
+        assert_eq!(status[0].last_success, Some(now - 10 * day));
+        assert!(status[0].is_stale(7 * day, now));
+
+        assert_eq!(status[1].label, "music");
+        assert!(!status[1].is_stale(7 * day, now));
+
+        // Never backed up successfully
+        assert_eq!(status[2].last_success, None);
+        assert!(status[2].is_stale(7 * day, now));
+
+        Ok(())
+    }
+}
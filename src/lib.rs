@@ -0,0 +1,81 @@
+//! # Usage
+//! Performs a simple backup on a specified directory.
+//!
+//! The files it backs up are determined by the following rules:
+//!
+//! * It recursively traverses the directory specified looking for files that should be backed up.
+//! * Directories marked with a `.nobackup` file will not be backed up, everything in a directory
+//!   marked with a `.backup-include` file will be (see [`marker_rule`](rules::marker_rule)).
+//! * If a `.gitignore` file is found then the files and driectories specified to be ignored  will not be backed up.
+//! * Programs will not be backed up, recognized by their content (see
+//!   [`ExecutableKind`](rules::ExecutableKind)).
+//! * Files are only backed up if they are newer then the ones in the backup.  
+//!
+//! A backup can be exported as a tar stream with `rackup export <backup> [subpath] -`.
+//!
+//! Backups that are run regularly can be configured as named profiles in a
+//! [configuration file](config) and run with `rackup run <profile>`. Every run is recorded in a
+//! [history](history), which `rackup status` shows. `rackup explain <profile> <path>` shows
+//! why an item is or is not backed up (see [`explain`]). The items backed up from each source
+//! by the last full backup are listed in its [manifest](manifest).
+//!
+//! # Library
+//! The backup can also be run from other programs with a [`BackupBuilder`]. Its `run()`
+//! returns a [`BackupReport`] with the outcome of every file:
+//!
+//! ```no_run
+//! let report = rackup::Backup::builder()
+//!     .source("/home/bob/Documents")
+//!     .destination("/media/backup")
+//!     .rules(rackup::rules::default_rules())
+//!     .run()?;
+//!
+//! for (_, err) in report.errors() {
+//!     eprintln!("{}", err);
+//! }
+//! # Ok::<(), rackup::Error>(())
+//! ```
+//!
+//! Errors that stop the whole backup are returned as an [`Error`], errors copying single
+//! files are recorded in the report as a [`FileError`].
+//!
+//! The progress of a running backup can be followed by setting a [`BackupObserver`] that
+//! receives [`BackupEvent`]s.
+//!
+//! # Project Status
+//! * It is very slow. Perhaps it can be speeded up by:
+//!   - Writing it in an asynchronise style.
+//!   - The ignoring of files in the `.gitignore` file is currently performed by starting a process and
+//!     running `git check-ignore`. Parsing the `.gitignore` file directly (using, for instance,
+//!     the crate [ignore](https://docs.rs/ignore/latest/ignore/)) could be quicker.
+//! * If a `.rackup_ignore` file is found then the files and directories specified in it will not be backed up.
+//! * Have the backup directory specified by an environment variable.
+//!
+mod backup;
+mod cancel;
+mod checkpoint;
+pub mod config;
+mod error;
+pub mod explain;
+pub mod export;
+mod files;
+pub mod filter;
+pub mod history;
+pub mod lock;
+pub mod manifest;
+pub mod marker;
+mod observer;
+pub mod preset;
+mod report;
+pub mod rules;
+mod scan;
+pub mod schedule;
+pub mod script;
+pub mod watch;
+
+pub use backup::{Backup, BackupBuilder};
+pub use cancel::CancelToken;
+pub use error::{Error, FileError, Result, RuleItemError};
+pub use files::create_backup_file_path;
+pub use observer::{BackupEvent, BackupObserver};
+pub use report::{BackupReport, FileOutcome, FileReport, Limit, LimitedFile};
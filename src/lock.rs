@@ -0,0 +1,387 @@
+//! Locks preventing two backups from running at the same time.
+//!
+//! A lock is a file that is created when the backup starts and removed when it finishes. It
+//! records the process holding it, so that a lock left behind by a process that was killed
+//! can be detected as stale and taken over.
+//!
+use crate::error::{self, Error};
+use fs4::fs_std::FileExt;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// The name of the lock file in the `.rackup` directory of a destination.
+pub const LOCK_FILE: &str = "lock";
+
+/// The extension of the file locked while a stale lock is taken over.
+const TAKEOVER_EXTENSION: &str = "takeover";
+
+/// How often a lock that is held is checked again while waiting for it.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How old an unreadable lock file has to be to be considered stale. A lock file can only be
+/// read partially while the process taking the lock is still writing it.
+const UNREADABLE_LOCK_AGE: Duration = Duration::from_secs(60);
+
+/// The process holding a lock.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockHolder {
+    pub pid: u32,
+    pub hostname: String,
+    #[serde(with = "crate::history::timestamp")]
+    pub started: SystemTime,
+    /// The name of the backup, e.g. its profile.
+    pub name: Option<String>,
+}
+
+impl LockHolder {
+    /// The current process.
+    pub fn current(name: Option<&str>) -> Self {
+        LockHolder {
+            pid: std::process::id(),
+            hostname: hostname(),
+            started: SystemTime::now(),
+            name: name.map(str::to_string),
+        }
+    }
+
+    /// `true` if the holder has certainly gone away: it ran on this host and the process
+    /// does not exist anymore. Locks held on other hosts are never stale.
+    fn is_stale(&self) -> bool {
+        self.hostname == hostname() && !process_exists(self.pid)
+    }
+}
+
+impl fmt::Display for LockHolder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(name) = &self.name {
+            write!(f, "{} ", name)?;
+        }
+        write!(
+            f,
+            "(pid {} on {}, started {})",
+            self.pid,
+            self.hostname,
+            humantime::format_rfc3339_seconds(self.started)
+        )
+    }
+}
+
+/// A lock that is held until it is dropped.
+#[derive(Debug)]
+pub struct RunLock {
+    path: PathBuf,
+}
+
+impl RunLock {
+    /// Takes the lock `path` for the current process, named `name`.
+    ///
+    /// If the lock is held by another process, this waits up to `wait` for it to be released,
+    /// calling `on_wait` once with the holder. After that [`Error::Locked`] is returned.
+    pub fn acquire(
+        path: &Path,
+        name: Option<&str>,
+        wait: Duration,
+        mut on_wait: impl FnMut(&LockHolder),
+    ) -> error::Result<RunLock> {
+        let lock_error = |source| Error::Lock {
+            path: path.to_path_buf(),
+            source,
+        };
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(lock_error)?;
+        }
+
+        let holder = LockHolder::current(name);
+        let deadline = SystemTime::now().checked_add(wait);
+        let mut waiting = false;
+
+        loop {
+            let existing = match RunLock::try_acquire(path, &holder) {
+                Ok(None) => {
+                    return Ok(RunLock {
+                        path: path.to_path_buf(),
+                    })
+                }
+                Ok(Some(existing)) => existing,
+                Err(err) => return Err(lock_error(err)),
+            };
+
+            match existing {
+                Some(existing) if existing.is_stale() => {
+                    RunLock::remove_stale(path, Some(&existing)).map_err(lock_error)?;
+                    continue;
+                }
+                None if is_old(path) => {
+                    RunLock::remove_stale(path, None).map_err(lock_error)?;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let remaining = match deadline {
+                Some(deadline) => deadline
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default(),
+                None => RETRY_INTERVAL,
+            };
+            if remaining.is_zero() {
+                return Err(Error::Locked {
+                    path: path.to_path_buf(),
+                    holder: existing
+                        .map_or_else(|| "an unknown process".to_string(), |h| h.to_string()),
+                });
+            }
+            if !waiting {
+                if let Some(existing) = &existing {
+                    on_wait(existing);
+                }
+                waiting = true;
+            }
+            thread::sleep(remaining.min(RETRY_INTERVAL));
+        }
+    }
+
+    /// Creates the lock file. If it exists already its holder is returned instead, `None`
+    /// if it cannot be read.
+    fn try_acquire(path: &Path, holder: &LockHolder) -> io::Result<Option<Option<LockHolder>>> {
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+        {
+            Ok(mut file) => {
+                let written = serde_json::to_writer(&mut file, holder)
+                    .map_err(io::Error::from)
+                    .and_then(|_| file.write_all(b"\n"))
+                    .and_then(|_| file.sync_all());
+                if let Err(err) = written {
+                    let _ = fs::remove_file(path);
+                    return Err(err);
+                }
+                Ok(None)
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                Ok(Some(RunLock::holder(path)))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// The holder of the lock `path`, `None` if the lock file cannot be read.
+    pub fn holder(path: &Path) -> Option<LockHolder> {
+        let content = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    /// `true` if the lock `path` is held by a process that is still running, or by a process
+    /// that may still be writing it.
+    pub fn is_held(path: &Path) -> bool {
+        match RunLock::holder(path) {
+            Some(holder) => !holder.is_stale(),
+            None => path.exists() && !is_old(path),
+        }
+    }
+
+    /// Removes a stale lock, unless it has been taken over by another process in the meantime.
+    ///
+    /// Only one process at a time checks the holder again and removes the lock, the one
+    /// holding the system lock of the takeover file next to it. A lock that another process
+    /// has just taken over is therefore never removed. The system lock is released when the
+    /// process ends, so a killed process cannot block a takeover.
+    fn remove_stale(path: &Path, stale: Option<&LockHolder>) -> io::Result<()> {
+        let takeover = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.with_extension(TAKEOVER_EXTENSION))?;
+        takeover.lock_exclusive()?;
+
+        // A lock that cannot be read may also have been created since
+        if RunLock::holder(path).as_ref() != stale || (stale.is_none() && !is_old(path)) {
+            return Ok(());
+        }
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// `true` if the file `path` was last modified long enough ago to not be in the middle of
+/// being written.
+fn is_old(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .is_ok_and(|modified| {
+            modified
+                .elapsed()
+                .is_ok_and(|age| age > UNREADABLE_LOCK_AGE)
+        })
+}
+
+/// The name of this host.
+#[cfg(unix)]
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its length, and the result is only read up to the
+    // terminating zero within the buffer.
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if result != 0 {
+        return "localhost".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
+/// The name of this host.
+#[cfg(not(unix))]
+pub fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "localhost".to_string())
+}
+
+/// `true` if a process with the id `pid` exists on this host.
+#[cfg(unix)]
+fn process_exists(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks whether the process exists.
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    // The process exists, but belongs to another user
+    io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// `true` if a process with the id `pid` exists on this host.
+///
+/// Processes cannot be checked on this platform, so locks are never considered stale.
+#[cfg(not(unix))]
+fn process_exists(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acquire_and_release() -> io::Result<()> {
+        let test_dir = tempfile::tempdir()?;
+        let path = test_dir.path().join(".rackup").join(LOCK_FILE);
+
+        let lock = RunLock::acquire(&path, Some("documents"), Duration::ZERO, |_| {})
+            .expect("Failed to take the lock");
+        let holder = RunLock::holder(&path).unwrap();
+        assert_eq!(holder.pid, std::process::id());
+        assert_eq!(holder.name.as_deref(), Some("documents"));
+
+        // A lock held by a running process is not taken over
+        let mut waited_for = None;
+        let result = RunLock::acquire(&path, None, Duration::from_millis(10), |holder| {
+            waited_for = Some(holder.clone())
+        });
+        assert!(matches!(result, Err(Error::Locked { .. })));
+        assert_eq!(waited_for, Some(holder));
+
+        drop(lock);
+        assert!(!path.exists());
+        assert!(RunLock::acquire(&path, None, Duration::ZERO, |_| {}).is_ok());
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stale_lock() -> io::Result<()> {
+        let test_dir = tempfile::tempdir()?;
+        let path = test_dir.path().join(LOCK_FILE);
+
+        // A process that has exited
+        let mut child = std::process::Command::new("true").spawn()?;
+        child.wait()?;
+        let stale = LockHolder {
+            pid: child.id(),
+            ..LockHolder::current(Some("music"))
+        };
+        fs::write(&path, serde_json::to_string(&stale)?)?;
+
+        let _lock = RunLock::acquire(&path, Some("documents"), Duration::ZERO, |_| {})
+            .expect("Failed to take over the stale lock");
+        assert_eq!(
+            RunLock::holder(&path).unwrap().name.as_deref(),
+            Some("documents")
+        );
+
+        // Locks of other hosts are never stale
+        let remote = LockHolder {
+            hostname: "another-host".to_string(),
+            ..stale
+        };
+        let remote_path = test_dir.path().join("remote");
+        fs::write(&remote_path, serde_json::to_string(&remote)?)?;
+        let result = RunLock::acquire(&remote_path, None, Duration::ZERO, |_| {});
+        assert!(matches!(result, Err(Error::Locked { .. })));
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stale_lock_taken_over_once() -> io::Result<()> {
+        let test_dir = tempfile::tempdir()?;
+        let path = test_dir.path().join(LOCK_FILE);
+
+        let mut child = std::process::Command::new("true").spawn()?;
+        child.wait()?;
+        let stale = LockHolder {
+            pid: child.id(),
+            ..LockHolder::current(Some("music"))
+        };
+        fs::write(&path, serde_json::to_string(&stale)?)?;
+
+        // A lock taken over in the meantime is not removed
+        let lock = RunLock::acquire(&path, Some("documents"), Duration::ZERO, |_| {})
+            .expect("Failed to take over the stale lock");
+        RunLock::remove_stale(&path, Some(&stale))?;
+        assert!(path.exists());
+        drop(lock);
+
+        // Of several backups finding the same stale lock only one takes it over
+        fs::write(&path, serde_json::to_string(&stale)?)?;
+        let barrier = std::sync::Barrier::new(8);
+        let acquired = thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        RunLock::acquire(&path, None, Duration::ZERO, |_| {}).ok()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(acquired.iter().flatten().count(), 1);
+
+        Ok(())
+    }
+}
@@ -1,559 +1,815 @@
-//! # Usage
-//! Performs a simple backup on a specified directory.
+//! Command line interface of rackup, see the library documentation for the details.
 //!
-//! The files it backs up are determined by the following rules:
-//!
-//! * It recursively traverses the directory specified looking for files that should be backed up.
-//! * If a `.gitignore` file is found then the files and driectories specified to be ignored  will not be backed up.
-//! * `.exe` files will not be backed up.
-//! * Files are only backed up if they are newer then the ones in the backup.  
-//!
-//! # Project Status
-//! * It is very slow. Perhaps it can be speeded up by:
-//!   - Writing it in an asynchronise style.
-//!   - The ignoring of files in the `.gitignore` file is currently performed by starting a process and
-//! running `git check-ignore`. Parsing the `.gitignore` file directly (using, for instance,
-//! the crate [ignore](https://docs.rs/ignore/latest/ignore/)) could be quicker.
-//! * If a `.rackup_ignore` file is found then the files and directories specified in it will not be backed up.
-//! * Have the backup directory specified by an environment variable.
-//!
-use clap::Parser;
-use rebackup::{walk, WalkerConfig, WalkerRule, WalkerRuleResult};
-use std::ffi::OsStr;
-use std::io::{self, Read, Write};
-use std::path::{Component, Path, PathBuf, Prefix};
-use std::process::Command;
-use std::{env, fs};
+mod cli;
+
+use clap::{Parser, Subcommand};
+use cli::api::Api;
+use cli::health::{HealthState, Thresholds};
+use cli::output::{NdjsonOutput, OutputFormat, Summary};
+use cli::progress::{TerminalProgress, Verbosity};
+use indicatif::HumanBytes;
+use rackup::config::{parse_size, Config, Profile, SourceConfig};
+use rackup::history::{History, RunRecord, HISTORY_FILE};
+use rackup::lock::RunLock;
+use rackup::marker::{DestinationCheck, DestinationMarker};
+use rackup::watch::{watch, WatchEvent, WatchOptions, WatchRun};
+use rackup::{export, BackupBuilder, BackupReport, CancelToken, Error};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// The source directory to be backed up
-    source: PathBuf,
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    /// The source directories to be backed up
+    #[arg(required = true, num_args = 1..)]
+    sources: Vec<PathBuf>,
 
     /// The backup directory or drive
-    backup: PathBuf,
-}
+    #[arg(required = true)]
+    backup: Option<PathBuf>,
 
-fn main() {
-    println!("Backing up ...");
+    #[command(flatten)]
+    run_options: RunOptions,
 
-    let cli = Args::parse();
+    /// Leave the backup directory out of the backup if it is inside the source directory,
+    /// instead of refusing to back up
+    #[arg(long)]
+    exclude_destination: bool,
 
-    let source_dir_path = cli.source;
+    /// Only back up if the backup directory has been initialized with `rackup init`
+    #[arg(long)]
+    require_marker: bool,
 
-    let backup_dir_path = cli.backup;
+    /// Only back up if the backup directory is a mount point
+    #[arg(long)]
+    require_mount_point: bool,
 
-    perform_backup(&source_dir_path, &backup_dir_path);
-}
+    /// Space to keep free in the backup directory, e.g. `10GB`
+    #[arg(long, default_value = "0", value_parser = parse_size)]
+    reserve: u64,
 
-fn perform_backup(source_dir_path: &Path, backup_dir_path: &Path) {
-    // Setup the rule to ignore files that git ignnores
-    // from https://docs.rs/rebackup/1.0.2/rebackup/index.html
-    let gitignore_rule = WalkerRule {
-        name: "gitignore",
-        description: None,
-        only_for: None,
-        matches: Box::new(|path, _, _| path.ancestors().any(|path| path.join(".git").is_dir())),
-        action: Box::new(|dir, _, _| {
-            let cwd = env::current_dir()?;
-
-            if dir.is_dir() {
-                env::set_current_dir(dir)?;
-            } else if let Some(parent) = dir.parent() {
-                env::set_current_dir(parent)?;
-            }
+    /// Leave out files larger than this, e.g. `2GB`
+    #[arg(long, value_parser = parse_size)]
+    max_file_size: Option<u64>,
 
-            let is_excluded = Command::new("git")
-                .arg("check-ignore")
-                .arg(dir.to_string_lossy().to_string())
-                .output();
+    /// Leave out files not modified for longer than this, e.g. `5years`
+    #[arg(long, value_parser = humantime::parse_duration)]
+    max_file_age: Option<Duration>,
 
-            // Restore the current directory before returning eventual error from the command
-            env::set_current_dir(cwd)?;
+    /// Only print errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
 
-            if is_excluded?.status.success() {
-                Ok(WalkerRuleResult::ExcludeItem)
-            } else {
-                Ok(WalkerRuleResult::IncludeItem)
-            }
-        }),
-    };
+    /// Also print every file copied and every item excluded
+    #[arg(short, long, global = true)]
+    verbose: bool,
 
-    let exe_rule = WalkerRule {
-        name: "noexe",
-        description: Some("Do not backup exe files".to_string()),
-        //only_for: Some(rebackup::WalkerItemType::Directory),
-        only_for: Some(rebackup::WalkerItemType::File),
-        //matches: Box::new(|path, _, _| path.join(".exe").is_file()),
-        matches: Box::new(|path, _, _| path.is_file()),
-        action: Box::new(|path, _, _| {
-            let ext = path.extension().unwrap_or_else(|| OsStr::new(""));
-
-            if ext == "exe" {
-                Ok(WalkerRuleResult::ExcludeItem)
-            } else {
-                Ok(WalkerRuleResult::IncludeItem)
-            }
-        }),
-    };
+    /// The format of the output
+    #[arg(long, value_enum, global = true, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
 
-    // All rules
-    let rules = vec![gitignore_rule, exe_rule];
+    /// The configuration file with the profiles [default: <config dir>/rackup/config.toml]
+    #[arg(long, global = true, env = "RACKUP_CONFIG")]
+    config: Option<PathBuf>,
+}
 
-    // NOTE: This can be shortened to `WalkerConfig::new(vec![])`
-    //       (expanded here for explanations purpose)
-    let config = WalkerConfig {
-        rules,
-        follow_symlinks: false,
-        drop_empty_dirs: false,
-    };
+// Options of a backup, given with a profile or with the source and backup directory.
+#[derive(clap::Args, Debug)]
+struct RunOptions {
+    /// Wait for another backup of the profile or to the backup directory to finish instead
+    /// of failing, at most for the given time if there is one, e.g. `--wait 30min`
+    #[arg(long, value_name = "DURATION", num_args = 0..=1, default_missing_value = "forever",
+          value_parser = parse_wait)]
+    wait: Option<Duration>,
+
+    /// Continue an interrupted backup, copying only the files it has not copied yet
+    #[arg(long)]
+    resume: bool,
+}
+
+/// Parses the time to wait for a lock, `forever` to wait as long as it takes.
+fn parse_wait(wait: &str) -> Result<Duration, humantime::DurationError> {
+    match wait {
+        "forever" => Ok(Duration::MAX),
+        wait => humantime::parse_duration(wait),
+    }
+}
 
-    let source_files_list = walk(source_dir_path, &config).expect("Failed to build the files list");
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Export a backup (or a path within it) as a tar stream
+    Export {
+        /// The backup directory to export
+        snapshot: PathBuf,
+
+        /// An optional path within the backup to export, followed by where to write
+        /// the tar stream (`-` for stdout)
+        #[arg(num_args = 1..=2, required = true, value_names = ["SUBPATH", "OUTPUT"])]
+        targets: Vec<PathBuf>,
+
+        /// Normalize the ownership, timestamps and permissions of the entries
+        #[arg(long)]
+        normalize: bool,
+    },
+
+    /// Initialize a backup directory or drive with an identity marker
+    Init {
+        /// The backup directory or drive
+        destination: PathBuf,
+
+        /// A name for the destination [default: the name of the directory]
+        #[arg(long)]
+        label: Option<String>,
+
+        /// Only allow this profile to back up to the destination
+        #[arg(long)]
+        profile: Option<String>,
+
+        /// Replace an existing marker, giving the destination a new id
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Back up a profile from the configuration file
+    Run {
+        /// The name of the profile
+        profile: String,
+
+        #[command(flatten)]
+        run_options: RunOptions,
+    },
+
+    /// Back up a profile whenever its source changes
+    ///
+    /// After an initial backup the changed files are backed up shortly after they change,
+    /// until the command is interrupted.
+    Watch {
+        /// The name of the profile
+        profile: String,
+
+        /// How long no more changes have to arrive before the changes are backed up
+        #[arg(long, default_value = "2s", value_parser = humantime::parse_duration)]
+        debounce: Duration,
+
+        /// How often everything is backed up again, to catch changes that have been missed
+        #[arg(long, default_value = "1h", value_parser = humantime::parse_duration)]
+        reconcile: Duration,
+    },
+
+    /// Show why an item is or is not backed up by a profile
+    ///
+    /// Lists every rule checked on the item and on the directories containing it, with the
+    /// matching ignore file and line, and whether the next backup would copy the item.
+    Explain {
+        /// The name of the profile
+        profile: String,
+
+        /// The file or directory in the source of the profile
+        path: PathBuf,
+    },
+
+    /// Back up the profiles that have a schedule, until the command is interrupted
+    ///
+    /// Runs missed while the computer was asleep or the daemon was not running are caught
+    /// up when it starts or wakes up. `rackup status` shows when the profiles run next.
+    Daemon,
+
+    /// Show the last runs of every profile
+    Status {
+        /// The number of runs shown for every profile
+        #[arg(long, default_value_t = 5)]
+        runs: usize,
+
+        /// Highlight the profiles whose last successful backup is older than this, e.g. `36h`
+        #[arg(long, default_value = "7days", value_parser = humantime::parse_duration)]
+        max_age: Duration,
+    },
+
+    /// Check the backups of all profiles for a monitoring system
+    ///
+    /// Exits with 0 if all profiles are OK, 1 on a warning, 2 if a profile is critical
+    /// and 3 if the state is unknown, like a Nagios plugin.
+    Health {
+        /// Critical when the last successful backup of a profile is older than this
+        #[arg(long, default_value = "7days", value_parser = humantime::parse_duration)]
+        max_age: Duration,
+
+        /// Warning when less space is available on a destination, e.g. `10GB`
+        #[arg(long, default_value = "1GB", value_parser = parse_size)]
+        min_free: u64,
+
+        /// Also write the metrics to this file for the Prometheus textfile collector
+        #[arg(long, value_name = "FILE")]
+        prometheus: Option<PathBuf>,
+    },
+}
 
-    for source_file_path in source_files_list {
-        let backup_file_path = create_backup_file_path(&source_file_path, backup_dir_path);
+// Exit codes, so that scripts can tell a partial failure from a full success.
+// (2 is also used by clap for invalid arguments.)
+const EXIT_PARTIAL_FAILURE: u8 = 1;
+const EXIT_CONFIG_ERROR: u8 = 2;
+const EXIT_WALK_ERROR: u8 = 3;
+const EXIT_DESTINATION_ERROR: u8 = 4;
+const EXIT_IO_ERROR: u8 = 5;
+const EXIT_LOCKED: u8 = 6;
+const EXIT_INTERRUPTED: u8 = 130;
+
+fn main() -> ExitCode {
+    let cli = Args::parse();
 
-        if is_newer(&source_file_path, &backup_file_path) {
-            if let Err(err) = copy_file(&source_file_path, &backup_file_path) {
-                eprintln!(
-                    "Error copying {}: {}",
-                    source_file_path.to_string_lossy(),
-                    err
-                );
-            } else {
-                println!(
-                    "File {} copied successfully.",
-                    source_file_path.to_string_lossy()
-                );
+    let verbosity = if cli.quiet {
+        Verbosity::Quiet
+    } else if cli.verbose {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    };
+
+    match cli.command {
+        Some(Commands::Export {
+            snapshot,
+            mut targets,
+            normalize,
+        }) => {
+            // The output is always the last target, the optional sub path comes before it
+            let output = targets.pop().unwrap_or_else(|| PathBuf::from("-"));
+            let sub_path = targets.pop();
+
+            if let Err(err) = export_backup(&snapshot, sub_path.as_deref(), &output, normalize) {
+                eprintln!("Error exporting {}: {}", snapshot.to_string_lossy(), err);
+                return ExitCode::from(EXIT_IO_ERROR);
             }
+            ExitCode::SUCCESS
+        }
+        Some(Commands::Init {
+            destination,
+            label,
+            profile,
+            force,
+        }) => {
+            let result = load_config(cli.config.as_deref())
+                .and_then(|config| init_destination(&config, &destination, label, profile, force));
+            match result {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(err) => report_error(&err),
+            }
+        }
+        Some(Commands::Run {
+            profile,
+            run_options,
+        }) => {
+            let config = match load_config(cli.config.as_deref()) {
+                Ok(config) => config,
+                Err(err) => return report_error(&err),
+            };
+            match config.profile(&profile) {
+                Ok(p) => perform_backup(Some(&profile), p, &run_options, verbosity, cli.output),
+                Err(err) => report_error(&err),
+            }
+        }
+        Some(Commands::Watch {
+            profile,
+            debounce,
+            reconcile,
+        }) => {
+            let config = match load_config(cli.config.as_deref()) {
+                Ok(config) => config,
+                Err(err) => return report_error(&err),
+            };
+            let options = WatchOptions {
+                debounce,
+                reconcile,
+            };
+            match config.profile(&profile) {
+                Ok(p) => watch_profile(&profile, p, &options, verbosity, cli.output),
+                Err(err) => report_error(&err),
+            }
+        }
+        Some(Commands::Explain { profile, path }) => {
+            let result = load_config(cli.config.as_deref()).and_then(|config| {
+                let explanation = config.profile(&profile)?.explain(&path)?;
+                cli::explain::print_explanation(&profile, &explanation, cli.output);
+                Ok(())
+            });
+            match result {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(err) => report_error(&err),
+            }
+        }
+        Some(Commands::Daemon) => {
+            let result = load_config(cli.config.as_deref()).and_then(|config| {
+                let history = History::new(cli::data_dir().join(HISTORY_FILE));
+                let socket = cli::daemon::socket_path(&config);
+                let api = config
+                    .daemon
+                    .api
+                    .map(|address| Api::bind(address, &cli::api::token_path(&config)))
+                    .transpose()?;
+                let cancel = cancel_on_signal(verbosity, OutputFormat::Text);
+                cli::daemon::run_daemon(&config, &history, &socket, api, &cancel, verbosity)
+            });
+            match result {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(err) => report_error(&err),
+            }
+        }
+        Some(Commands::Status { runs, max_age }) => {
+            let result = load_config(cli.config.as_deref()).and_then(|config| {
+                let history = History::new(cli::data_dir().join(HISTORY_FILE));
+                // The daemon is usually not running
+                let daemon = cli::daemon::query_status(&cli::daemon::socket_path(&config)).ok();
+                cli::status::print_status(
+                    &history,
+                    &config,
+                    daemon.as_ref(),
+                    runs,
+                    max_age,
+                    cli.output,
+                )
+            });
+            match result {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(err) => report_error(&err),
+            }
+        }
+        Some(Commands::Health {
+            max_age,
+            min_free,
+            prometheus,
+        }) => {
+            let thresholds = Thresholds { max_age, min_free };
+            let result = load_config(cli.config.as_deref()).and_then(|config| {
+                let history = History::new(cli::data_dir().join(HISTORY_FILE));
+                cli::health::check_health(
+                    &history,
+                    &config,
+                    &thresholds,
+                    prometheus.as_deref(),
+                    cli.output,
+                )
+            });
+            match result {
+                Ok(state) => ExitCode::from(state.exit_code()),
+                Err(err) => {
+                    // A check that cannot run has an unknown state
+                    println!("RACKUP UNKNOWN - {}", err);
+                    ExitCode::from(HealthState::Unknown.exit_code())
+                }
+            }
+        }
+        None => {
+            // Both are required by clap when no subcommand is given
+            let mut source_dir_paths = cli.sources.into_iter();
+            let source_dir_path = source_dir_paths.next().unwrap_or_default();
+            let backup_dir_path = cli.backup.unwrap_or_default();
+
+            let mut profile = Profile::new(source_dir_path, backup_dir_path);
+            profile.sources = source_dir_paths.map(SourceConfig::new).collect();
+            profile.exclude_destination = cli.exclude_destination;
+            profile.require_marker = cli.require_marker;
+            profile.require_mount_point = cli.require_mount_point;
+            profile.reserve = cli.reserve;
+            profile.max_file_size = cli.max_file_size;
+            profile.max_file_age = cli.max_file_age;
+            perform_backup(None, &profile, &cli.run_options, verbosity, cli.output)
         }
     }
 }
 
-/// Checks if the `source_file`is newer then the `backup_file`.
-/// If the `backup_file`does not exist then this always returns `true`.
-///
-fn is_newer(source_file: &PathBuf, backup_file: &std::path::PathBuf) -> bool {
-    // Check if the backup file exists. If it does not return true as the source file
-    // is "newer"
-    if !backup_file.exists() || !backup_file.is_file() {
-        return true;
+fn load_config(path: Option<&Path>) -> rackup::Result<Config> {
+    match path {
+        Some(path) => Config::load(path),
+        None => Config::load(&cli::default_config_path()),
     }
+}
 
-    // Check the modifed times of the files to find the newest
-    if let (Ok(source_metadata), Ok(backup_metadata)) =
-        (fs::metadata(source_file), fs::metadata(backup_file))
-    {
-        if let (Ok(source_modified), Ok(existing_modified)) =
-            (source_metadata.modified(), backup_metadata.modified())
-        {
-            return source_modified > existing_modified;
-        }
+/// Prints an error that stopped a command and returns its exit code.
+fn report_error(err: &Error) -> ExitCode {
+    eprintln!("{}", err);
+    ExitCode::from(error_exit_code(err))
+}
+
+fn error_exit_code(err: &Error) -> u8 {
+    match err {
+        Error::Config(_) => EXIT_CONFIG_ERROR,
+        Error::Walk { .. } | Error::Watch { .. } => EXIT_WALK_ERROR,
+        Error::Destination { .. }
+        | Error::Overlap { .. }
+        | Error::UnverifiedDestination { .. }
+        | Error::InsufficientSpace { .. } => EXIT_DESTINATION_ERROR,
+        Error::Locked { .. } => EXIT_LOCKED,
+        Error::History { .. } | Error::Lock { .. } => EXIT_IO_ERROR,
     }
-    false
 }
 
-/// Copies over the backup file.
-fn copy_file(source_file_path: &PathBuf, backup_file_path: &PathBuf) -> io::Result<()> {
-    // Create the directory/directories the file is in if they have not already been created.
-    let mut dir = backup_file_path.clone();
-    dir.pop();
-    fs::create_dir_all(dir)?;
-
-    // Open the source file for reading, but only if it is a file
-    // (directories hve been created before).
-    if source_file_path.is_file() {
-        let mut source_file_content = Vec::new();
-        let mut source_file = fs::File::open(source_file_path)?;
-        source_file.read_to_end(&mut source_file_content)?;
-
-        // Create or open the existing file for writing
-        let mut backup_file = fs::OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(backup_file_path)?;
-
-        // Write the contents of the checked file to the existing file
-        backup_file.write_all(&source_file_content)?;
-    } else {
-        // Is just a directory so create it
-        fs::create_dir_all(backup_file_path)?;
+/// Writes the identity marker of a destination.
+fn init_destination(
+    config: &Config,
+    destination: &Path,
+    label: Option<String>,
+    profile: Option<String>,
+    force: bool,
+) -> rackup::Result<()> {
+    if let Some(profile) = &profile {
+        config.profile(profile)?;
     }
 
+    let label = label.unwrap_or_else(|| match destination.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => "backup".to_string(),
+    });
+    let marker = DestinationMarker::new(label, profile.as_deref());
+
+    marker.write(destination, force).map_err(|source| {
+        let source = match DestinationMarker::read(destination) {
+            Ok(Some(existing)) if source.kind() == io::ErrorKind::AlreadyExists => io::Error::new(
+                source.kind(),
+                format!(
+                    "already initialized as {} with the id {}, use --force to replace the marker",
+                    existing.label, existing.id
+                ),
+            ),
+            _ => source,
+        };
+        Error::Destination {
+            path: destination.to_path_buf(),
+            source,
+        }
+    })?;
+
+    println!(
+        "Initialized {} as {} with the id {}.",
+        destination.to_string_lossy(),
+        marker.label,
+        marker.id
+    );
+    println!(
+        "Set `destination_id = \"{}\"` in a profile to only back up to this destination.",
+        marker.id
+    );
     Ok(())
 }
 
-// Create the path of the file being backed up, i.e.:
-// with source file: C:/Users/bob/Documents/test.txt
-// and backup directory C:/Users/bob/Backup it will create a PathBuf of
-//      C:/Users/bob/Backup/C/Users/bob/Documents/test.txt
-
-fn create_backup_file_path(source_file_path: &Path, backup_dir_path: &Path) -> PathBuf {
-    let components = source_file_path.components();
-
-    let mut backup_file_path = PathBuf::from(backup_dir_path);
-
-    let mut sub_path = String::new();
-
-    for component in components {
-        match component {
-            Component::Prefix(p) => match p.kind() {
-                Prefix::Verbatim(_osstr) | Prefix::DeviceNS(_osstr) => {
-                    //sub_path.push_str(osstr.to_str().unwrap_or("?"))
-                    sub_path.push_str(""); // Ignored
-                }
-                Prefix::VerbatimUNC(hostname, sharename) | Prefix::UNC(hostname, sharename) => {
-                    sub_path.push_str(hostname.to_str().unwrap_or("?"));
-                    sub_path.push('/');
-                    sub_path.push_str(sharename.to_str().unwrap_or("?"));
-                }
-                Prefix::Disk(disk_chr) | Prefix::VerbatimDisk(disk_chr) => {
-                    sub_path.push(disk_chr as char);
-                }
-            },
-            Component::RootDir => sub_path.push('/'),
-            Component::Normal(c) => {
-                sub_path.push_str(c.to_str().unwrap());
-                sub_path.push('/');
-            }
-            _ => sub_path.push_str("unknown"),
-        };
+fn export_backup(
+    snapshot: &Path,
+    sub_path: Option<&Path>,
+    output: &Path,
+    normalize: bool,
+) -> io::Result<()> {
+    if output == Path::new("-") {
+        let stdout = io::stdout().lock();
+        export::export_tar(snapshot, sub_path, normalize, stdout)?.flush()
+    } else {
+        let file = fs::File::create(output)?;
+        export::export_tar(snapshot, sub_path, normalize, file)?.sync_all()
     }
-
-    // Remove the trailing "/"
-    sub_path.pop();
-
-    backup_file_path.push(sub_path);
-
-    backup_file_path
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    use core::time;
-    use std::fs::{File, OpenOptions};
-    use std::io::{self, Write};
-
-    #[test]
-    fn test_is_newer_where_backup_file_does_not_exist() -> Result<(), std::io::Error> {
-        // Set up test data
-        let test_dir = tempfile::tempdir()?;
-
-        let source_path = test_dir.path().join("source_test_data");
-        let mut source_file = File::create(source_path)?;
-        writeln!(source_file, "Some test data")?;
-
-        // test the is_newer function
-        let backup_path = test_dir.path().join("backup");
-        let source_path_created = test_dir.path().join("source_test_data");
-        let newer = is_newer(&source_path_created, &backup_path);
-        assert!(newer);
-
-        //Cleanup
-        drop(source_file);
-        test_dir.close()?;
-
-        Ok(())
+/// A token that is cancelled when the process is sent SIGINT or SIGTERM, so that the backup
+/// can stop after the file being copied. A second signal exits immediately.
+fn cancel_on_signal(verbosity: Verbosity, output: OutputFormat) -> CancelToken {
+    let cancel = CancelToken::new();
+    let token = cancel.clone();
+    let result = ctrlc::set_handler(move || {
+        if token.is_cancelled() {
+            std::process::exit(EXIT_INTERRUPTED.into());
+        }
+        token.cancel();
+        if verbosity > Verbosity::Quiet && output == OutputFormat::Text {
+            eprintln!("Stopping the backup, interrupt again to exit immediately ...");
+        }
+    });
+    if let Err(err) = result {
+        eprintln!("Cannot handle interruptions: {}", err);
     }
+    cancel
+}
 
-    #[test]
-    fn test_is_newer_where_source_older() -> Result<(), std::io::Error> {
-        // Set up test data
-        let test_dir = tempfile::tempdir()?;
-
-        // Create backup file after source file
-        let source_path = test_dir.path().join("source_test_data");
-        let mut source_file = File::create(source_path)?;
-        writeln!(source_file, "Some test data")?;
-
-        let backup_path = test_dir.path().join("backup");
-        let mut backup_file = File::create(backup_path)?;
-        writeln!(backup_file, "Some test data")?;
-
-        // Test the is_newer function
-        let source_path_created = test_dir.path().join("source_test_data");
-        let backup_path_created = test_dir.path().join("backup");
-        let newer = is_newer(&source_path_created, &backup_path_created);
-        assert!(!newer); // Backup file is younger than source file
-
-        //Cleanup
-        drop(source_file);
-        drop(backup_file);
-        test_dir.close()?;
-
-        Ok(())
+/// Backs up a profile, `name` is `None` for a backup given on the command line.
+fn perform_backup(
+    name: Option<&str>,
+    profile: &Profile,
+    options: &RunOptions,
+    verbosity: Verbosity,
+    output: OutputFormat,
+) -> ExitCode {
+    let started = SystemTime::now();
+
+    if verbosity > Verbosity::Quiet && output == OutputFormat::Text {
+        println!("Backing up ...");
     }
-    #[test]
-    fn test_is_newer_where_source_younger() -> Result<(), std::io::Error> {
-        // Set up test data
-        let test_dir = tempfile::tempdir()?;
-
-        // Crate backup file before source file
-        let backup_path = test_dir.path().join("backup");
-        let mut backup_file = File::create(backup_path)?;
-        writeln!(backup_file, "Some test data")?;
-
-        std::thread::sleep(time::Duration::from_millis(250));
-
-        let source_path = test_dir.path().join("source_test_data");
-        let mut source_file = File::create(source_path)?;
-        writeln!(source_file, "Some test data")?;
 
-        // Test the is_newer function
-        let source_path_created = test_dir.path().join("source_test_data");
-        let backup_path_created = test_dir.path().join("backup");
-        let newer = is_newer(&source_path_created, &backup_path_created);
-        assert!(newer); // Backup file is older then source file
+    let wait = options.wait.unwrap_or_default();
+    let builder = backup_builder(name, profile, verbosity, output)
+        .lock_wait(wait)
+        .cancel_token(cancel_on_signal(verbosity, output))
+        .resume(options.resume);
+
+    let mut id = None;
+    let result = lock_profile(name, wait, verbosity, output).and_then(|_lock| {
+        id = start_run(name, profile, started);
+        builder.run()
+    });
+    ExitCode::from(finish_run(name, profile, started, id, &result, output))
+}
 
-        //Cleanup
-        drop(source_file);
-        drop(backup_file);
-        test_dir.close()?;
+/// A builder for the backup of `profile`, printing its progress in the `output` format.
+fn backup_builder(
+    name: Option<&str>,
+    profile: &Profile,
+    verbosity: Verbosity,
+    output: OutputFormat,
+) -> BackupBuilder {
+    let label = match name {
+        Some(name) => name.to_string(),
+        None => format!(
+            "{} -> {}",
+            profile
+                .source_paths()
+                .iter()
+                .map(|source| source.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(", "),
+            profile.destination.to_string_lossy()
+        ),
+    };
 
-        Ok(())
+    let builder = profile
+        .backup_builder()
+        .verify_destination(DestinationCheck {
+            profile: name.map(str::to_string),
+            ..profile.destination_check()
+        })
+        .name(&label);
+    match output {
+        OutputFormat::Text => builder.observer(TerminalProgress::new(verbosity)),
+        OutputFormat::Json => builder,
+        OutputFormat::Ndjson => builder.observer(NdjsonOutput::new(io::stdout())),
     }
+}
 
-    #[test]
-    fn test_first_backup() -> Result<(), std::io::Error> {
-        // Set up test data
-        let test_content = "Some test content".to_string();
+/// Takes the local lock of the profile `name`. A profile can also be locked locally, in case
+/// its destination changes between runs.
+fn lock_profile(
+    name: Option<&str>,
+    wait: Duration,
+    verbosity: Verbosity,
+    output: OutputFormat,
+) -> rackup::Result<Option<RunLock>> {
+    name.map(|name| {
+        RunLock::acquire(&cli::profile_lock_path(name), Some(name), wait, |holder| {
+            if verbosity > Verbosity::Quiet && output == OutputFormat::Text {
+                println!("Waiting for the profile {} locked by {} ...", name, holder);
+            }
+        })
+    })
+    .transpose()
+}
 
-        let test_dir = tempfile::tempdir()?;
+/// Records locally that a run of `profile` has started at `started`, so that the run shows up
+/// as incomplete if the process is killed. Returns the id of the run for [`finish_run`].
+fn start_run(name: Option<&str>, profile: &Profile, started: SystemTime) -> Option<Uuid> {
+    let history = History::new(cli::data_dir().join(HISTORY_FILE));
+    let record = RunRecord::unfinished(
+        started,
+        name,
+        &profile.source_paths(),
+        &profile.destination,
+        EXIT_INTERRUPTED,
+    );
+    match history.append(&record) {
+        Ok(()) => record.id,
+        Err(err) => {
+            eprintln!(
+                "Error recording the run in {}: {}",
+                history.path().to_string_lossy(),
+                err
+            );
+            None
+        }
+    }
+}
 
-        let source_path = test_dir.path().join("source_test_data");
-        let mut source_file = File::create(source_path)?;
-        write!(source_file, "{}", test_content)?;
+/// Prints the result of a run of `profile` that started at `started`, records it in the
+/// history, replacing the record of [`start_run`] with the `id`, and returns its exit code.
+fn finish_run(
+    name: Option<&str>,
+    profile: &Profile,
+    started: SystemTime,
+    id: Option<Uuid>,
+    result: &rackup::Result<BackupReport>,
+    output: OutputFormat,
+) -> u8 {
+    let elapsed = started.elapsed().unwrap_or_default();
+    let sources = profile.source_paths();
+
+    if output == OutputFormat::Json {
+        let summary = match result {
+            Ok(report) => Summary::from_report(report, &sources, &profile.destination, elapsed),
+            Err(err) => Summary::from_error(err, &sources, &profile.destination, elapsed),
+        };
+        if let Err(err) = summary.print() {
+            eprintln!("Error writing the summary: {}", err);
+        }
+    }
 
-        // Test the back_file function
-        let backup_path = test_dir.path().join("backup");
-        let source_path_created = test_dir.path().join("source_test_data");
-        copy_file(&source_path_created, &backup_path)?;
+    if let Ok(report) = result {
+        if output == OutputFormat::Text && !report.limited.is_empty() {
+            eprintln!(
+                "{} files ({}) are over the limits and have not been backed up:",
+                report.limited.len(),
+                HumanBytes(report.bytes_limited())
+            );
+            for file in &report.limited {
+                eprintln!("  {} ({})", file.path.to_string_lossy(), file);
+            }
+        }
+    }
 
-        let mut created_backup_file = File::open(backup_path)?;
-        let mut buf = String::new();
-        created_backup_file.read_to_string(&mut buf)?;
-        assert_eq!(buf, test_content);
+    let exit_code = match result {
+        Ok(report) if report.is_success() => 0,
+        Ok(report) if report.interrupted => EXIT_INTERRUPTED,
+        Ok(report) => {
+            // Summary of the files that are missing from the backup
+            if output == OutputFormat::Text {
+                if report.destination_full() {
+                    eprintln!(
+                        "The backup directory {} is full, the backup has been stopped.",
+                        profile.destination.to_string_lossy()
+                    );
+                }
+                eprintln!(
+                    "{} of {} files could not be backed up:",
+                    report.failed(),
+                    report.files.len()
+                );
+                for (file, _) in report.errors() {
+                    eprintln!("  {}", file.source.to_string_lossy());
+                }
+            }
+            EXIT_PARTIAL_FAILURE
+        }
+        Err(err) => {
+            if output == OutputFormat::Text {
+                eprintln!("{}", err);
+                if let Error::Overlap { .. } = err {
+                    eprintln!(
+                        "Choose a backup directory outside the source, or leave it out of the \
+                         backup with --exclude-destination (`exclude_destination = true` in a profile)."
+                    );
+                }
+            }
+            error_exit_code(err)
+        }
+    };
 
-        //Cleanup
-        drop(source_file);
-        test_dir.close()?;
+    // A backup that did not run because another one is running is not recorded, as it
+    // would hide the result of the other one
+    if let Err(Error::Locked { .. }) = result {
+        if let Some(id) = id {
+            let history = History::new(cli::data_dir().join(HISTORY_FILE));
+            if let Err(err) = history.discard(id) {
+                eprintln!(
+                    "Error recording the run in {}: {}",
+                    history.path().to_string_lossy(),
+                    err
+                );
+            }
+        }
+        return exit_code;
+    }
 
-        Ok(())
+    // Record the run on the destination and locally
+    let record = RunRecord {
+        id,
+        ..RunRecord::new(
+            started,
+            name,
+            &sources,
+            &profile.destination,
+            result,
+            exit_code,
+        )
+    };
+    let mut histories = vec![History::new(cli::data_dir().join(HISTORY_FILE))];
+    // Only record on a destination that has been used
+    if matches!(
+        result,
+        Ok(_) | Err(Error::Walk { .. } | Error::InsufficientSpace { .. })
+    ) {
+        histories.push(History::on_destination(&profile.destination));
     }
-    #[test]
-    fn test_subsequent_backup() -> Result<(), std::io::Error> {
-        // Set up test data
-
-        let test_dir = tempfile::tempdir()?;
-
-        let backup_path = test_dir.path().join("backup");
-        let mut backup_file = File::create(backup_path)?;
-        write!(
-            backup_file,
-            "Some really old data that should be overwritten."
-        )?;
-        assert!(test_dir.path().join("backup").exists());
-
-        let test_content = "Some test content".to_string();
-        let source_path = test_dir.path().join("source_test_data");
-        let mut source_file = File::create(source_path)?;
-        write!(source_file, "{}", test_content)?;
-
-        // Test the copy_file function
-        let backup_path = test_dir.path().join("backup");
-        let source_path_created = test_dir.path().join("source_test_data");
-        copy_file(&source_path_created, &backup_path)?;
-
-        let mut created_backup_file = File::open(backup_path)?;
-        let mut buf = String::new();
-        created_backup_file.read_to_string(&mut buf)?;
-        assert_eq!(buf, test_content);
-
-        //Cleanup
-        drop(source_file);
-        test_dir.close()?;
-
-        Ok(())
+    for history in histories {
+        if let Err(err) = history.append(&record) {
+            eprintln!(
+                "Error recording the run in {}: {}",
+                history.path().to_string_lossy(),
+                err
+            );
+        }
     }
 
-    #[test]
-    fn test_create_backup_path() {
-        // With source file: C:/Users/bob/Documents/test.txt
-        // and backup directory C:/Users/bob/Backup it will create a path of
-        //  C:/Users/bob/Backup/c/Users/bob/Documents/test.txt
-
-        let mut source_file_path = PathBuf::from("C:/Users/bob/Documents/test.txt");
-        let mut backup_dir_path = PathBuf::from("C:/Users/bob/Backup");
-
-        let mut backup_path = create_backup_file_path(&source_file_path, &backup_dir_path);
-
-        assert_eq!(
-            PathBuf::from("C:/Users/bob/Backup/C/Users/bob/Documents/test.txt"),
-            backup_path
-        );
-
-        // With other drives
-        // With source file: D:/Users/bob/Documents/test.txt
-        // and backup directory G:/Users/bob/Backup it will create a path of
-        //  G:/Users/bob/Backup/D/Users/bob/Documents/test.txt
-        source_file_path = PathBuf::from("D:/Users/bob/Documents/test.txt");
-        backup_dir_path = PathBuf::from("G:/Users/bob/Backup");
-
-        backup_path = create_backup_file_path(&source_file_path, &backup_dir_path);
-
-        assert_eq!(
-            PathBuf::from("G:/Users/bob/Backup/D/Users/bob/Documents/test.txt"),
-            backup_path
-        );
-    }
+    exit_code
+}
 
-    #[test]
-    fn test_perform_new_backup() -> Result<(), std::io::Error> {
-        let test_dir = setup_file_structure()?;
-
-        // Test perform_backup()
-        let source_dir_path = test_dir.path().join("TestUser");
-        let backup_dir_path = test_dir.path().join("Backup");
-        perform_backup(&source_dir_path, &backup_dir_path);
-
-        // Check if the files and directories have been created.
-        let full_backup_path = get_full_backup_path(&test_dir, &backup_dir_path);
-
-        assert!(test_dir.path().join("Backup").exists());
-        assert!(full_backup_path.join("TestUser/DocumentsA").exists());
-        assert!(full_backup_path.join("TestUser/DocumentsB").exists());
-        assert!(full_backup_path.join("TestUser/DocumentsC").exists());
-
-        assert!(full_backup_path
-            .join("TestUser/DocumentsA/fileAA.txt")
-            .exists());
-        assert!(full_backup_path
-            .join("TestUser/DocumentsA/fileAB.txt")
-            .exists());
-
-        assert!(full_backup_path
-            .join("TestUser/DocumentsB/fileBA.pdf")
-            .exists());
-        assert!(full_backup_path
-            .join("TestUser/DocumentsB/fileBB.doc")
-            .exists());
-        assert!(full_backup_path
-            .join("TestUser/DocumentsB/fileBC.txt")
-            .exists());
-
-        assert!(full_backup_path.join("TestUser/DocumentsC").exists());
-
-        // Sample if the files contain the data
-        let p = full_backup_path.join("TestUser/DocumentsA/fileAA.txt");
-        let mut contents = String::new();
-        let mut file = fs::File::open(p)?;
-        file.read_to_string(&mut contents)?;
-        assert_eq!(contents, "fileAA.txt");
-
-        let p = full_backup_path.join("TestUser/DocumentsB/fileBB.doc");
-        let mut file = fs::File::open(p)?;
-        contents.clear();
-        file.read_to_string(&mut contents)?;
-        assert_eq!(contents, "fileBB.doc");
-
-        Ok(())
+/// Backs up the profile `name` whenever its source changes, until the process is interrupted.
+fn watch_profile(
+    name: &str,
+    profile: &Profile,
+    options: &WatchOptions,
+    verbosity: Verbosity,
+    output: OutputFormat,
+) -> ExitCode {
+    let text = verbosity > Verbosity::Quiet && output == OutputFormat::Text;
+    let cancel = cancel_on_signal(verbosity, output);
+
+    // Backups started while watching wait for other backups to the destination to finish
+    let backup = backup_builder(Some(name), profile, verbosity, output)
+        .lock_wait(Duration::MAX)
+        .cancel_token(cancel.clone())
+        .build();
+    let result = lock_profile(Some(name), Duration::ZERO, verbosity, output)
+        .and_then(|lock| Ok((lock, backup?)));
+    let (_lock, backup) = match result {
+        Ok(locked) => locked,
+        Err(err) => return report_error(&err),
+    };
+
+    let mut started = SystemTime::now();
+    let mut id = None;
+    let mut exit_code = 0;
+    let result = watch(&backup, options, &cancel, |event| match event {
+        WatchEvent::Watching { sources } if text => {
+            for source in *sources {
+                println!(
+                    "Watching {} for changes, press Ctrl-C to stop.",
+                    source.to_string_lossy()
+                );
+            }
+        }
+        WatchEvent::RunStarted { run } => {
+            started = SystemTime::now();
+            id = run
+                .is_full()
+                .then(|| start_run(Some(name), profile, started))
+                .flatten();
+            if text {
+                match run {
+                    WatchRun::Initial => println!("Backing up ..."),
+                    WatchRun::Changes { paths } => {
+                        println!("Backing up {} changed items ...", paths)
+                    }
+                    WatchRun::Rescan => {
+                        println!("Changes have been lost, backing up everything again ...")
+                    }
+                    WatchRun::Reconcile => println!("Backing up everything again ..."),
+                }
+            }
+        }
+        // Only full backups and failures are recorded, so that the history is not flooded
+        // with the backups of single files
+        WatchEvent::RunFinished { run, result } => {
+            let successful = matches!(result, Ok(report) if report.is_success());
+            exit_code = if run.is_full() || !successful {
+                finish_run(Some(name), profile, started, id.take(), result, output)
+            } else {
+                0
+            };
+        }
+        _ => {}
+    });
+
+    match result {
+        // Only an interrupted backup is reported, stopping the watch is not an error
+        Ok(()) if exit_code == EXIT_INTERRUPTED => ExitCode::from(EXIT_INTERRUPTED),
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => report_error(&err),
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
     #[test]
-    fn test_perform_overwrite_backup() -> Result<(), std::io::Error> {
-        let test_dir = setup_file_structure()?;
-
-        // Test perform_backup()
-        let source_dir_path = test_dir.path().join("TestUser");
-        let backup_dir_path = test_dir.path().join("Backup");
-        perform_backup(&source_dir_path, &backup_dir_path);
-
-        let full_backup_path = get_full_backup_path(&test_dir, &backup_dir_path);
-
-        // Change two of the files in the source
-        let p = full_backup_path.join("TestUser/DocumentsA/fileAA.txt");
-        let mut file = OpenOptions::new().append(true).open(p).unwrap();
-        //file.write_all("fileAA.txt".as_bytes()).unwrap();
-        file.write_all(" has been updated".as_bytes()).unwrap();
-
-        let p = full_backup_path.join("TestUser/DocumentsB/fileBB.doc");
-        let mut file = OpenOptions::new().append(true).open(p).unwrap();
-        //file.write_all("fileBB.doc".as_bytes()).unwrap();
-        file.write_all(" has been updated".as_bytes()).unwrap();
-
-        // Now perform the backup again
-        perform_backup(&source_dir_path, &backup_dir_path);
-
-        // Now check that the changed file have been overwritten
-        let p = full_backup_path.join("TestUser/DocumentsA/fileAA.txt");
-        let mut contents = String::new();
-        let mut file = fs::File::open(p)?;
-        file.read_to_string(&mut contents)?;
-        assert_eq!(contents, "fileAA.txt has been updated");
-
-        let p = full_backup_path.join("TestUser/DocumentsB/fileBB.doc");
-        let mut file = fs::File::open(p)?;
-        contents.clear();
-        file.read_to_string(&mut contents)?;
-        assert_eq!(contents, "fileBB.doc has been updated");
-
-        Ok(())
-    }
+    fn test_parse_args() {
+        let args = Args::try_parse_from(["rackup", "/home/bob", "/media/backup"]).unwrap();
+        assert_eq!(args.sources, vec![PathBuf::from("/home/bob")]);
+        assert!(args.command.is_none());
 
-    fn get_full_backup_path(test_dir: &tempfile::TempDir, backup_dir_path: &PathBuf) -> PathBuf {
-        // First get the path of the temp directory.
-        let tail = test_dir.path().to_str().unwrap().to_string();
-        // Assuming that the temp dir used for test in the C: drive. For the backup path remove
-        // the C: and replace it with C
-        let tail_norm = tail.replace(":", "");
-        // Get the full backup path, i.e.
-        // <temp test dir>/Backup/<temp test dir with C: changed to C>
-        //let full_backup_path = test_dir.path().join("Backup").join(tail_norm);
-        let full_backup_path = backup_dir_path.join(tail_norm);
-        full_backup_path
-    }
+        let args = Args::try_parse_from(["rackup", "status", "--output", "json"]).unwrap();
+        assert!(matches!(args.command, Some(Commands::Status { .. })));
+        assert_eq!(args.output, OutputFormat::Json);
 
-    // Test utilities
-
-    // Create a temporary directory/file stucture to back up. Each file contains a with the name of the file.
-    // Structure is:
-    // TempDir
-    //   -> TestUser
-    //       -> DocumentsA
-    //          --> fileAA.txt
-    //          --> fileBA.txt
-    //       --> DocumentsB
-    //           --> fileBA.pdf
-    //           --> fileBB.doc
-    //           --> fileBC.txt
-    //       --> DocumentsC
-    //           --> (empty)
-    //
-    fn setup_file_structure() -> Result<tempfile::TempDir, io::Error> {
-        let test_dir = tempfile::tempdir()?;
-        fs::create_dir(test_dir.path().join("TestUser"))?;
-        fs::create_dir(test_dir.path().join("TestUser/DocumentsA"))?;
-        fs::create_dir(test_dir.path().join("TestUser/DocumentsB"))?;
-        fs::create_dir(test_dir.path().join("TestUser/DocumentsC"))?;
-        let mut f = File::create(test_dir.path().join("TestUser/DocumentsA/fileAA.txt"))?;
-        write!(f, "fileAA.txt")?;
-        f = File::create(test_dir.path().join("TestUser/DocumentsA/fileAB.txt"))?;
-        write!(f, "fileAB.txt")?;
-        f = File::create(test_dir.path().join("TestUser/DocumentsB/fileBA.pdf"))?;
-        write!(f, "fileBA.pdf")?;
-        f = File::create(test_dir.path().join("TestUser/DocumentsB/fileBB.doc"))?;
-        write!(f, "fileBB.doc")?;
-        f = File::create(test_dir.path().join("TestUser/DocumentsB/fileBC.txt"))?;
-        write!(f, "fileBC.txt")?;
-        assert!(test_dir
-            .path()
-            .join("TestUser/DocumentsA/fileAB.txt")
-            .exists());
-        assert!(test_dir.path().join("TestUser/DocumentsC").exists());
-        Ok(test_dir)
+        // A source is not silently ignored when a command is given
+        assert!(Args::try_parse_from(["rackup", "/home/bob", "status"]).is_err());
     }
 }
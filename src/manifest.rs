@@ -0,0 +1,160 @@
+//! The manifest of the last full backup to a destination.
+//!
+//! When a backup that walked all its sources has finished, the resolved sources and the
+//! items backed up from each of them are written to `.rackup/manifest.json` on the
+//! destination, replacing the manifest of the previous backup. Overlapping sources are
+//! listed once, and every item is listed under the innermost source it is in, which is the
+//! source whose rules decided about it.
+//!
+use crate::history::METADATA_DIR;
+use crate::report::{BackupReport, FileOutcome};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// The name of the manifest file in the `.rackup` directory of the destination.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// The sources of a backup and the items backed up from them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    #[serde(with = "crate::history::timestamp")]
+    pub created: SystemTime,
+    pub sources: Vec<ManifestSource>,
+}
+
+/// A source of a backup with the items backed up from it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestSource {
+    /// The source as it was given.
+    pub path: PathBuf,
+    /// The source with symbolic links resolved.
+    pub resolved: PathBuf,
+    /// The files and empty directories in the backup, relative to `resolved` and sorted.
+    /// Items that could not be copied are left out.
+    pub items: Vec<PathBuf>,
+}
+
+impl Manifest {
+    /// The manifest of a backup of the `sources`, given with their resolved path, that
+    /// returned `report`.
+    pub(crate) fn new(sources: &[(PathBuf, PathBuf)], report: &BackupReport) -> Self {
+        let mut sources: Vec<ManifestSource> = sources
+            .iter()
+            .map(|(path, resolved)| ManifestSource {
+                path: path.clone(),
+                resolved: resolved.clone(),
+                items: Vec::new(),
+            })
+            .collect();
+
+        for file in &report.files {
+            if matches!(file.outcome, FileOutcome::Failed(_)) {
+                continue;
+            }
+            let source = sources
+                .iter_mut()
+                .filter(|source| file.source.starts_with(&source.resolved))
+                .max_by_key(|source| source.resolved.components().count());
+            if let Some(source) = source {
+                if let Ok(relative) = file.source.strip_prefix(&source.resolved) {
+                    source.items.push(relative.to_path_buf());
+                }
+            }
+        }
+        for source in &mut sources {
+            source.items.sort();
+        }
+
+        Manifest {
+            created: SystemTime::now(),
+            sources,
+        }
+    }
+
+    /// Writes the manifest to `destination`, replacing the previous one.
+    pub(crate) fn write(&self, destination: &Path) -> io::Result<()> {
+        let dir = destination.join(METADATA_DIR);
+        fs::create_dir_all(&dir)?;
+        let temp_path = dir.join(format!("{}.tmp", MANIFEST_FILE));
+        fs::write(&temp_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&temp_path, dir.join(MANIFEST_FILE))
+    }
+
+    /// Reads the manifest of the last full backup to `destination`, `None` if there is none.
+    pub fn load(destination: &Path) -> io::Result<Option<Manifest>> {
+        match fs::read(destination.join(METADATA_DIR).join(MANIFEST_FILE)) {
+            Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::FileError;
+    use crate::report::FileReport;
+
+    fn file(source: &str, outcome: FileOutcome) -> FileReport {
+        FileReport {
+            source: PathBuf::from(source),
+            destination: PathBuf::from("/media/backup").join(source.trim_start_matches('/')),
+            outcome,
+        }
+    }
+
+    #[test]
+    fn test_manifest() -> io::Result<()> {
+        let report = BackupReport {
+            files: vec![
+                file("/home/bob/projects/app/main.rs", FileOutcome::Unchanged),
+                file(
+                    "/home/bob/projects/client-a/logo.svg",
+                    FileOutcome::Copied { bytes: 3 },
+                ),
+                file(
+                    "/home/bob/projects/README",
+                    FileOutcome::Copied { bytes: 5 },
+                ),
+                file(
+                    "/home/bob/projects/locked.db",
+                    FileOutcome::Failed(FileError {
+                        path: PathBuf::from("/home/bob/projects/locked.db"),
+                        source: io::Error::from(io::ErrorKind::PermissionDenied),
+                    }),
+                ),
+            ],
+            ..BackupReport::default()
+        };
+        let manifest = Manifest::new(
+            &[
+                (
+                    PathBuf::from("~/projects"),
+                    PathBuf::from("/home/bob/projects"),
+                ),
+                (
+                    PathBuf::from("/home/bob/projects/client-a"),
+                    PathBuf::from("/home/bob/projects/client-a"),
+                ),
+            ],
+            &report,
+        );
+        assert_eq!(
+            manifest.sources[0].items,
+            vec![PathBuf::from("README"), PathBuf::from("app/main.rs")]
+        );
+        assert_eq!(manifest.sources[1].items, vec![PathBuf::from("logo.svg")]);
+
+        let test_dir = tempfile::tempdir()?;
+        assert_eq!(Manifest::load(test_dir.path())?, None);
+        manifest.write(test_dir.path())?;
+        let loaded = Manifest::load(test_dir.path())?.unwrap();
+        assert_eq!(loaded.sources, manifest.sources);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+//! Identity marker of a backup destination.
+//!
+//! `rackup init` writes a marker file into the `.rackup` directory of a destination. Backups
+//! can then refuse to run if the marker is missing, e.g. because the backup drive is not
+//! plugged in and its mount point is just an empty directory on the system disk.
+//!
+use crate::error::{self, Error};
+use crate::history::METADATA_DIR;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// The name of the marker file.
+pub const MARKER_FILE: &str = "destination.json";
+
+/// The identity of a backup destination.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DestinationMarker {
+    /// Unique id of the destination, generated when it is initialized.
+    pub id: Uuid,
+    /// A name for the destination, e.g. the label of the drive.
+    pub label: String,
+    #[serde(with = "crate::history::timestamp")]
+    pub created: SystemTime,
+    /// The profile the destination belongs to, `None` if it can be used by any profile.
+    pub profile: Option<String>,
+}
+
+impl DestinationMarker {
+    /// A new marker with a random id.
+    pub fn new(label: impl Into<String>, profile: Option<&str>) -> Self {
+        DestinationMarker {
+            id: Uuid::new_v4(),
+            label: label.into(),
+            created: SystemTime::now(),
+            profile: profile.map(str::to_string),
+        }
+    }
+
+    /// The path of the marker file of `destination`.
+    pub fn path(destination: &Path) -> PathBuf {
+        destination.join(METADATA_DIR).join(MARKER_FILE)
+    }
+
+    /// Reads the marker of `destination`, `None` if the destination has not been initialized.
+    pub fn read(destination: &Path) -> io::Result<Option<Self>> {
+        match fs::read_to_string(DestinationMarker::path(destination)) {
+            Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Writes the marker into `destination`, creating the destination if needed.
+    ///
+    /// An existing marker is only replaced if `overwrite` is set, otherwise an error of kind
+    /// [`io::ErrorKind::AlreadyExists`] is returned.
+    pub fn write(&self, destination: &Path, overwrite: bool) -> io::Result<()> {
+        let path = DestinationMarker::path(destination);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true);
+        if overwrite {
+            options.create(true).truncate(true);
+        } else {
+            options.create_new(true);
+        }
+
+        let mut file = options.open(path)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        writeln!(file)?;
+        file.sync_all()
+    }
+}
+
+/// What is checked about the destination before a backup runs.
+///
+/// Nothing is checked by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DestinationCheck {
+    /// The destination must have been initialized with a marker.
+    pub require_marker: bool,
+    /// The id the marker must have.
+    pub id: Option<Uuid>,
+    /// The profile being backed up. A destination belonging to another profile is refused.
+    pub profile: Option<String>,
+    /// The destination must be the mount point of a file system, so that nothing is written
+    /// to the parent file system when the drive is not mounted. Only checked on Unix.
+    pub require_mount_point: bool,
+}
+
+impl DestinationCheck {
+    /// Checks `destination`, returning [`Error::UnverifiedDestination`] if it is not the one
+    /// expected.
+    pub fn verify(&self, destination: &Path) -> error::Result<()> {
+        let refuse = |reason: String| {
+            Err(Error::UnverifiedDestination {
+                path: destination.to_path_buf(),
+                reason,
+            })
+        };
+
+        if self.require_mount_point {
+            match is_mount_point(destination) {
+                Ok(true) => {}
+                Ok(false) => return refuse("it is not a mount point".to_string()),
+                Err(err) => return refuse(format!("cannot check the mount point: {}", err)),
+            }
+        }
+
+        if !self.require_marker && self.id.is_none() && self.profile.is_none() {
+            return Ok(());
+        }
+
+        let marker = match DestinationMarker::read(destination) {
+            Ok(marker) => marker,
+            Err(err) => return refuse(format!("cannot read the marker: {}", err)),
+        };
+        let Some(marker) = marker else {
+            if self.require_marker || self.id.is_some() {
+                return refuse("it has not been initialized with `rackup init`".to_string());
+            }
+            return Ok(());
+        };
+
+        if let Some(id) = self.id {
+            if marker.id != id {
+                return refuse(format!(
+                    "it is {} with the id {}, not {}",
+                    marker.label, marker.id, id
+                ));
+            }
+        }
+        if let (Some(owner), Some(profile)) = (&marker.profile, &self.profile) {
+            if owner != profile {
+                return refuse(format!("it belongs to the profile {}", owner));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// `true` if `path` is the root of a mounted file system.
+#[cfg(unix)]
+pub fn is_mount_point(path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = fs::canonicalize(path)?;
+    let Some(parent) = path.parent() else {
+        // The root directory
+        return Ok(true);
+    };
+    Ok(fs::metadata(&path)?.dev() != fs::metadata(parent)?.dev())
+}
+
+/// `true` if `path` is the root of a mounted file system.
+///
+/// Mount points are not detected on this platform, every directory counts as one.
+#[cfg(not(unix))]
+pub fn is_mount_point(path: &Path) -> io::Result<bool> {
+    fs::metadata(path).map(|m| m.is_dir())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_and_read() -> io::Result<()> {
+        let test_dir = tempfile::tempdir()?;
+        assert_eq!(DestinationMarker::read(test_dir.path())?, None);
+
+        let marker = DestinationMarker::new("USB backup", Some("documents"));
+        marker.write(test_dir.path(), false)?;
+        assert!(test_dir.path().join(".rackup/destination.json").exists());
+
+        // The creation time is stored with a precision of seconds
+        let read = DestinationMarker::read(test_dir.path())?.unwrap();
+        assert_eq!(read.id, marker.id);
+        assert_eq!(read.label, "USB backup");
+        assert_eq!(read.profile.as_deref(), Some("documents"));
+
+        let err = DestinationMarker::new("other", None)
+            .write(test_dir.path(), false)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify() -> io::Result<()> {
+        let test_dir = tempfile::tempdir()?;
+        let destination = test_dir.path();
+
+        let unverified = |check: &DestinationCheck| {
+            matches!(
+                check.verify(destination),
+                Err(Error::UnverifiedDestination { .. })
+            )
+        };
+
+        let required = DestinationCheck {
+            require_marker: true,
+            ..DestinationCheck::default()
+        };
+        assert!(DestinationCheck::default().verify(destination).is_ok());
+        assert!(unverified(&required));
+
+        let marker = DestinationMarker::new("USB backup", Some("documents"));
+        marker.write(destination, false)?;
+        assert!(required.verify(destination).is_ok());
+
+        let with_id = |id| DestinationCheck {
+            id: Some(id),
+            ..required.clone()
+        };
+        assert!(with_id(marker.id).verify(destination).is_ok());
+        assert!(unverified(&with_id(Uuid::new_v4())));
+
+        let with_profile = |profile: &str| DestinationCheck {
+            profile: Some(profile.to_string()),
+            ..required.clone()
+        };
+        assert!(with_profile("documents").verify(destination).is_ok());
+        assert!(unverified(&with_profile("music")));
+
+        // A temporary directory is not a mount point of its own
+        #[cfg(unix)]
+        assert!(unverified(&DestinationCheck {
+            require_mount_point: true,
+            ..DestinationCheck::default()
+        }));
+
+        Ok(())
+    }
+}
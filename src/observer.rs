@@ -0,0 +1,124 @@
+//! Events sent while a backup runs, so that applications can show its progress.
+//!
+use rebackup::{WalkerRule, WalkerRuleResult};
+use std::cell::RefCell;
+use std::io;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// Something that happened during a backup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackupEvent {
+    /// The destination is locked by another backup, which is waited for.
+    WaitingForLock { path: PathBuf, holder: String },
+    /// The scan of a source directory for the files to back up has started.
+    ScanStarted { source: PathBuf },
+    /// An item was found by the scan and is included in the backup.
+    Included { path: PathBuf },
+    /// An item was excluded from the backup by a rule.
+    Excluded { path: PathBuf, rule: &'static str },
+    /// The scan of a source directory has finished, `items` will be considered for the backup.
+    ScanFinished { source: PathBuf, items: usize },
+    /// All sources have been scanned. `files_to_copy` files with a total of `bytes_to_copy`
+    /// bytes out of the `items` found are newer than their backup and will be copied.
+    Planned {
+        items: usize,
+        files_to_copy: usize,
+        bytes_to_copy: u64,
+    },
+    /// An interrupted backup is resumed, `items` are left to be copied.
+    Resumed { items: usize },
+    /// Copying a file into the backup has started.
+    CopyStarted {
+        source: PathBuf,
+        destination: PathBuf,
+        size: u64,
+    },
+    /// Part of a file has been copied.
+    CopyProgress {
+        source: PathBuf,
+        bytes_copied: u64,
+        size: u64,
+    },
+    /// A file has been copied into the backup.
+    CopyFinished { source: PathBuf, bytes: u64 },
+    /// An empty directory has been created in the backup.
+    DirectoryCreated { source: PathBuf },
+    /// The backup of the item is already up to date.
+    Unchanged { source: PathBuf },
+    /// An item could not be backed up.
+    Error {
+        path: PathBuf,
+        kind: io::ErrorKind,
+        message: String,
+    },
+    /// The backup has been cancelled. `remaining` planned items have not been backed up, 0
+    /// if it was cancelled while scanning the sources.
+    Interrupted { remaining: usize },
+    /// The backup has finished.
+    RunFinished {
+        copied: usize,
+        directories_created: usize,
+        unchanged: usize,
+        failed: usize,
+        bytes_copied: u64,
+    },
+}
+
+/// Receives the [`BackupEvent`]s of a backup.
+///
+/// Closures taking a `&BackupEvent` can be used as an observer, e.g. to forward the events
+/// over a channel:
+///
+/// ```no_run
+/// let (sender, receiver) = std::sync::mpsc::channel();
+///
+/// let report = rackup::Backup::builder()
+///     .source("/home/bob/Documents")
+///     .destination("/media/backup")
+///     .observer(move |event: &rackup::BackupEvent| {
+///         let _ = sender.send(event.clone());
+///     })
+///     .run()?;
+/// # Ok::<(), rackup::Error>(())
+/// ```
+pub trait BackupObserver {
+    fn on_event(&mut self, event: &BackupEvent);
+}
+
+impl<F: FnMut(&BackupEvent)> BackupObserver for F {
+    fn on_event(&mut self, event: &BackupEvent) {
+        self(event)
+    }
+}
+
+/// An observer shared between the backup and the rules it wraps.
+pub(crate) type SharedObserver = Rc<RefCell<dyn BackupObserver>>;
+
+/// Wraps the action of `rule` so that the observer is told about the items it excludes.
+pub(crate) fn observe_rule(rule: WalkerRule, observer: SharedObserver) -> WalkerRule {
+    let WalkerRule {
+        name,
+        description,
+        only_for,
+        matches,
+        action,
+    } = rule;
+
+    WalkerRule {
+        name,
+        description,
+        only_for,
+        matches,
+        action: Box::new(move |path, config, source| {
+            let result = action(path, config, source)?;
+            if let WalkerRuleResult::ExcludeItem = result {
+                observer.borrow_mut().on_event(&BackupEvent::Excluded {
+                    path: path.to_path_buf(),
+                    rule: name,
+                });
+            }
+            Ok(result)
+        }),
+    }
+}
@@ -0,0 +1,372 @@
+//! Presets excluding the regenerable directories and files of common project types.
+//!
+//! Most presets only exclude a directory if the project type is detected by a marker file next
+//! to it, so that e.g. a `target` directory is only left out next to a `Cargo.toml`:
+//!
+//! ```toml
+//! [profiles.documents]
+//! presets = ["rust", "node", "os-junk"]
+//! exclude_caches = true
+//! ```
+//!
+//! With `exclude_caches` the directories tagged as caches with a `CACHEDIR.TAG` file are left
+//! out as well, see the [Cache Directory Tagging Specification](https://bford.info/cachedir/).
+//!
+use rebackup::glob::{MatchOptions, Pattern};
+use rebackup::{WalkerItemType, WalkerRule, WalkerRuleResult};
+use serde::Deserialize;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// The name of the file tagging a cache directory.
+pub const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+/// The start of a valid `CACHEDIR.TAG` file.
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// A set of regenerable items of a project type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+    /// `target` directories of Cargo projects.
+    Rust,
+    /// `node_modules` and the build caches of JavaScript projects.
+    Node,
+    /// Bytecode, tool caches and virtual environments of Python projects.
+    Python,
+    /// The build directories of Gradle and Maven projects.
+    Jvm,
+    /// The caches and project directories of IDEs.
+    Ide,
+    /// The files and directories that operating systems leave behind, like `.DS_Store`.
+    OsJunk,
+}
+
+/// What has to be found for an item to be excluded by a preset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Marker {
+    /// Nothing, the name is enough.
+    None,
+    /// Any of the files next to the item.
+    Sibling(&'static [&'static str]),
+    /// Any of the files inside the directory.
+    Inside(&'static [&'static str]),
+}
+
+/// An item excluded by a preset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PresetEntry {
+    /// A glob pattern of the name of the item.
+    pub name: &'static str,
+    pub item_type: WalkerItemType,
+    marker: Marker,
+}
+
+const fn dir(name: &'static str, marker: Marker) -> PresetEntry {
+    PresetEntry {
+        name,
+        item_type: WalkerItemType::Directory,
+        marker,
+    }
+}
+
+const fn file(name: &'static str) -> PresetEntry {
+    PresetEntry {
+        name,
+        item_type: WalkerItemType::File,
+        marker: Marker::None,
+    }
+}
+
+const CARGO: Marker = Marker::Sibling(&["Cargo.toml"]);
+const NPM: Marker = Marker::Sibling(&["package.json"]);
+const PYTHON: Marker = Marker::Sibling(&["pyproject.toml", "setup.py", "setup.cfg"]);
+const VENV: Marker = Marker::Inside(&["pyvenv.cfg"]);
+const GRADLE: Marker = Marker::Sibling(&[
+    "build.gradle",
+    "build.gradle.kts",
+    "settings.gradle",
+    "settings.gradle.kts",
+]);
+const MAVEN: Marker = Marker::Sibling(&["pom.xml"]);
+
+const RUST: &[PresetEntry] = &[dir("target", CARGO)];
+const NODE: &[PresetEntry] = &[
+    dir("node_modules", NPM),
+    dir(".next", NPM),
+    dir(".nuxt", NPM),
+    dir(".parcel-cache", NPM),
+    dir(".turbo", NPM),
+];
+const PYTHON_ENTRIES: &[PresetEntry] = &[
+    dir("__pycache__", Marker::None),
+    dir(".pytest_cache", Marker::None),
+    dir(".mypy_cache", Marker::None),
+    dir(".ruff_cache", Marker::None),
+    dir(".tox", Marker::None),
+    dir("*", VENV),
+    dir("build", PYTHON),
+    dir("*.egg-info", PYTHON),
+];
+const JVM: &[PresetEntry] = &[
+    dir(".gradle", GRADLE),
+    dir("build", GRADLE),
+    dir("target", MAVEN),
+];
+const IDE: &[PresetEntry] = &[
+    dir(".idea", Marker::None),
+    dir(".vs", Marker::None),
+    dir(".vscode-test", Marker::None),
+];
+const OS_JUNK: &[PresetEntry] = &[
+    file(".DS_Store"),
+    file("._*"),
+    file("Thumbs.db"),
+    file("ehthumbs.db"),
+    file("desktop.ini"),
+    dir(".Spotlight-V100", Marker::None),
+    dir(".Trashes", Marker::None),
+    dir(".fseventsd", Marker::None),
+    dir(".Trash-*", Marker::None),
+    dir("$RECYCLE.BIN", Marker::None),
+];
+
+impl Preset {
+    /// All presets.
+    pub const ALL: [Preset; 6] = [
+        Preset::Rust,
+        Preset::Node,
+        Preset::Python,
+        Preset::Jvm,
+        Preset::Ide,
+        Preset::OsJunk,
+    ];
+
+    /// The items excluded by the preset.
+    pub fn entries(self) -> &'static [PresetEntry] {
+        match self {
+            Preset::Rust => RUST,
+            Preset::Node => NODE,
+            Preset::Python => PYTHON_ENTRIES,
+            Preset::Jvm => JVM,
+            Preset::Ide => IDE,
+            Preset::OsJunk => OS_JUNK,
+        }
+    }
+
+    /// The entry of the preset that excludes the item at `path`, if any.
+    pub fn matching(self, path: &Path) -> Option<&'static PresetEntry> {
+        let name = path.file_name()?.to_str()?;
+        let item_type = if path.is_symlink() {
+            WalkerItemType::Symlink
+        } else if path.is_dir() {
+            WalkerItemType::Directory
+        } else {
+            WalkerItemType::File
+        };
+        self.entries()
+            .iter()
+            .find(|entry| entry.item_type == item_type && entry.matches(name, path))
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Preset::Rust => "rust",
+            Preset::Node => "node",
+            Preset::Python => "python",
+            Preset::Jvm => "jvm",
+            Preset::Ide => "ide",
+            Preset::OsJunk => "os-junk",
+        })
+    }
+}
+
+impl PresetEntry {
+    /// `true` if the item `name` at `path` is excluded, its type is not checked.
+    fn matches(&self, name: &str, path: &Path) -> bool {
+        let name_matches = if self.name.contains('*') {
+            let options = MatchOptions {
+                case_sensitive: true,
+                require_literal_separator: true,
+                require_literal_leading_dot: false,
+            };
+            // The patterns are constant and valid
+            Pattern::new(self.name).is_ok_and(|pattern| pattern.matches_with(name, options))
+        } else {
+            self.name == name
+        };
+        name_matches
+            && match self.marker {
+                Marker::None => true,
+                Marker::Sibling(files) => path
+                    .parent()
+                    .is_some_and(|parent| files.iter().any(|file| parent.join(file).is_file())),
+                Marker::Inside(files) => files.iter().any(|file| path.join(file).is_file()),
+            }
+    }
+}
+
+impl fmt::Display for PresetEntry {
+    /// The entry described in words, e.g. `target next to Cargo.toml`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.marker {
+            Marker::None => f.write_str(self.name),
+            Marker::Sibling(files) => write!(f, "{} next to {}", self.name, files.join(" or ")),
+            // Any directory
+            Marker::Inside(files) if self.name == "*" => {
+                write!(f, "directory containing {}", files.join(" or "))
+            }
+            Marker::Inside(files) => write!(f, "{} containing {}", self.name, files.join(" or ")),
+        }
+    }
+}
+
+/// Rule to not back up the items of the `presets`.
+pub fn preset_rule(presets: Vec<Preset>) -> WalkerRule {
+    WalkerRule {
+        name: "preset",
+        description: Some("Do not backup regenerable build and cache items".to_string()),
+        only_for: None,
+        matches: Box::new(move |path, _, _| {
+            presets.iter().any(|preset| preset.matching(path).is_some())
+        }),
+        action: Box::new(|_, _, _| Ok(WalkerRuleResult::ExcludeItem)),
+    }
+}
+
+/// Rule to not back up the directories tagged as caches, see [`is_cache_dir`].
+pub fn cachedir_rule() -> WalkerRule {
+    WalkerRule {
+        name: "cachedir",
+        description: Some("Do not backup directories tagged with CACHEDIR.TAG".to_string()),
+        only_for: Some(WalkerItemType::Directory),
+        matches: Box::new(|path, _, _| path.join(CACHEDIR_TAG).is_file()),
+        action: Box::new(|path, _, _| {
+            if is_cache_dir(path)? {
+                Ok(WalkerRuleResult::ExcludeItem)
+            } else {
+                Ok(WalkerRuleResult::IncludeItem)
+            }
+        }),
+    }
+}
+
+/// `true` if the directory `dir` has a `CACHEDIR.TAG` file starting with the signature of
+/// the specification. Files without the signature do not count.
+pub fn is_cache_dir(dir: &Path) -> io::Result<bool> {
+    let mut signature = Vec::with_capacity(CACHEDIR_TAG_SIGNATURE.len());
+    match File::open(dir.join(CACHEDIR_TAG)) {
+        Ok(file) => {
+            file.take(CACHEDIR_TAG_SIGNATURE.len() as u64)
+                .read_to_end(&mut signature)?;
+            Ok(signature == CACHEDIR_TAG_SIGNATURE)
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_matching() -> io::Result<()> {
+        let test_dir = tempfile::tempdir()?;
+        let root = test_dir.path();
+        for dir in [
+            "app/target",
+            "app/src",
+            "notes/target",
+            "web/node_modules",
+            "py/__pycache__",
+            "py/.venv",
+            "py/build",
+            "java/target",
+            "java/.gradle",
+        ] {
+            fs::create_dir_all(root.join(dir))?;
+        }
+        for file in [
+            "app/Cargo.toml",
+            "web/package.json",
+            "py/.venv/pyvenv.cfg",
+            "java/pom.xml",
+            "photos/.DS_Store",
+            "photos/._IMG_0001.jpg",
+        ] {
+            fs::create_dir_all(root.join(file).parent().unwrap())?;
+            fs::write(root.join(file), b"")?;
+        }
+
+        let matching = |preset: Preset, path: &str| {
+            preset
+                .matching(&root.join(path))
+                .map(|entry| entry.to_string())
+        };
+        assert_eq!(
+            matching(Preset::Rust, "app/target").as_deref(),
+            Some("target next to Cargo.toml")
+        );
+        assert_eq!(matching(Preset::Rust, "app/src"), None);
+        // Without a marker the directory is kept
+        assert_eq!(matching(Preset::Rust, "notes/target"), None);
+        assert_eq!(matching(Preset::Rust, "java/target"), None);
+        assert_eq!(
+            matching(Preset::Jvm, "java/target").as_deref(),
+            Some("target next to pom.xml")
+        );
+        // Maven does not use .gradle
+        assert_eq!(matching(Preset::Jvm, "java/.gradle"), None);
+        assert!(matching(Preset::Node, "web/node_modules").is_some());
+        assert!(matching(Preset::Python, "py/__pycache__").is_some());
+        assert_eq!(
+            matching(Preset::Python, "py/.venv").as_deref(),
+            Some("directory containing pyvenv.cfg")
+        );
+        assert_eq!(matching(Preset::Python, "py/build"), None);
+        assert!(matching(Preset::OsJunk, "photos/.DS_Store").is_some());
+        assert!(matching(Preset::OsJunk, "photos/._IMG_0001.jpg").is_some());
+        // Only directories are excluded by their name
+        assert_eq!(matching(Preset::Rust, "app/Cargo.toml"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_cache_dir() -> io::Result<()> {
+        let test_dir = tempfile::tempdir()?;
+        let root = test_dir.path();
+        fs::create_dir_all(root.join("cache"))?;
+        fs::create_dir_all(root.join("fake"))?;
+        fs::write(
+            root.join("cache").join(CACHEDIR_TAG),
+            b"Signature: 8a477f597d28d172789f06886806bc55\n# This is a cache directory\n",
+        )?;
+        fs::write(root.join("fake").join(CACHEDIR_TAG), b"Signature: none")?;
+
+        assert!(is_cache_dir(&root.join("cache"))?);
+        assert!(!is_cache_dir(&root.join("fake"))?);
+        assert!(!is_cache_dir(root)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_presets() {
+        #[derive(Deserialize)]
+        struct Presets {
+            presets: Vec<Preset>,
+        }
+        let parsed: Presets = toml::from_str("presets = [\"rust\", \"os-junk\"]").unwrap();
+        assert_eq!(parsed.presets, vec![Preset::Rust, Preset::OsJunk]);
+        for preset in Preset::ALL {
+            let parsed: Presets = toml::from_str(&format!("presets = [\"{}\"]", preset)).unwrap();
+            assert_eq!(parsed.presets, vec![preset]);
+        }
+    }
+}
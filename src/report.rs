@@ -0,0 +1,161 @@
+//! The result of a backup run.
+//!
+use crate::error::FileError;
+use crate::files::is_storage_full;
+use indicatif::HumanBytes;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// What happened to a single item found in the source directory.
+#[derive(Debug)]
+pub enum FileOutcome {
+    /// The file was copied into the backup.
+    Copied {
+        /// The number of bytes written.
+        bytes: u64,
+    },
+    /// The (empty) directory was created in the backup.
+    DirectoryCreated,
+    /// The backup is already up to date, so nothing was copied.
+    Unchanged,
+    /// Copying the file failed.
+    Failed(FileError),
+}
+
+/// The outcome for a single item.
+#[derive(Debug)]
+pub struct FileReport {
+    /// The path of the item in the source directory.
+    pub source: PathBuf,
+    /// The path of the item in the backup.
+    pub destination: PathBuf,
+    pub outcome: FileOutcome,
+}
+
+/// Report of a backup run, with the outcome of each item that was considered.
+#[derive(Debug, Default)]
+pub struct BackupReport {
+    /// The outcomes in the order in which the items were backed up.
+    pub files: Vec<FileReport>,
+    /// `true` if the backup was cancelled before all items were backed up. The items that
+    /// were not backed up are not in `files`.
+    pub interrupted: bool,
+    /// The files left out because they are over the size or age limit, see
+    /// [`BackupBuilder::max_file_size`](crate::BackupBuilder::max_file_size).
+    pub limited: Vec<LimitedFile>,
+}
+
+/// A file left out of the backup because it is over a limit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitedFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// The limit the file is over.
+    pub limit: Limit,
+}
+
+/// A limit of the files that are backed up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// The largest size of a file, in bytes.
+    Size(u64),
+    /// The longest time since a file has been modified.
+    Age(Duration),
+}
+
+impl fmt::Display for LimitedFile {
+    /// Why the file is left out, e.g. `2.5 GiB, larger than 2 GiB`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.limit {
+            Limit::Size(max) => write!(
+                f,
+                "{}, larger than {}",
+                HumanBytes(self.size),
+                HumanBytes(max)
+            ),
+            Limit::Age(max) => {
+                let age = self
+                    .modified
+                    .and_then(|modified| modified.elapsed().ok())
+                    .unwrap_or_default();
+                // Whole days are precise enough for ages in days or years
+                let round = |duration: Duration| {
+                    if duration.as_secs() >= 86400 {
+                        Duration::from_secs(duration.as_secs() / 86400 * 86400)
+                    } else {
+                        Duration::from_secs(duration.as_secs())
+                    }
+                };
+                write!(
+                    f,
+                    "{}, not modified for {}, longer than {}",
+                    HumanBytes(self.size),
+                    humantime::format_duration(round(age)),
+                    humantime::format_duration(max)
+                )
+            }
+        }
+    }
+}
+
+impl BackupReport {
+    /// The number of files copied into the backup.
+    pub fn copied(&self) -> usize {
+        self.count(|o| matches!(o, FileOutcome::Copied { .. }))
+    }
+
+    /// The number of directories created in the backup.
+    pub fn directories_created(&self) -> usize {
+        self.count(|o| matches!(o, FileOutcome::DirectoryCreated))
+    }
+
+    /// The number of items that were already up to date.
+    pub fn unchanged(&self) -> usize {
+        self.count(|o| matches!(o, FileOutcome::Unchanged))
+    }
+
+    /// The number of items that could not be copied.
+    pub fn failed(&self) -> usize {
+        self.count(|o| matches!(o, FileOutcome::Failed(_)))
+    }
+
+    /// The total number of bytes copied into the backup.
+    pub fn bytes_copied(&self) -> u64 {
+        self.files
+            .iter()
+            .map(|f| match f.outcome {
+                FileOutcome::Copied { bytes } => bytes,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// The items that could not be copied, together with their error.
+    pub fn errors(&self) -> impl Iterator<Item = (&FileReport, &FileError)> {
+        self.files.iter().filter_map(|f| match &f.outcome {
+            FileOutcome::Failed(err) => Some((f, err)),
+            _ => None,
+        })
+    }
+
+    /// `true` if the backup stopped because the destination is full.
+    pub fn destination_full(&self) -> bool {
+        self.errors().any(|(_, err)| is_storage_full(err.kind()))
+    }
+
+    /// `true` if all items were backed up.
+    pub fn is_success(&self) -> bool {
+        !self.interrupted && self.failed() == 0
+    }
+
+    /// The total size of the files left out because of the limits.
+    pub fn bytes_limited(&self) -> u64 {
+        self.limited.iter().map(|file| file.size).sum()
+    }
+
+    fn count(&self, predicate: impl Fn(&FileOutcome) -> bool) -> usize {
+        self.files.iter().filter(|f| predicate(&f.outcome)).count()
+    }
+}
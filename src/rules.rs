@@ -0,0 +1,673 @@
+//! The built-in [`WalkerRule`]s that decide which files are backed up.
+//!
+use rebackup::{WalkerItemType, WalkerRule, WalkerRuleResult};
+use serde::Deserialize;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The rules used when no other rules are given: [`marker_rule`], [`gitignore_rule`] and
+/// [`exe_rule`]. The markers come first, so that a `.backup-include` file also includes the
+/// files git ignores.
+pub fn default_rules() -> Vec<WalkerRule> {
+    vec![marker_rule(), gitignore_rule(), exe_rule()]
+}
+
+/// Rule to ignore the files that git ignores.
+pub fn gitignore_rule() -> WalkerRule {
+    // from https://docs.rs/rebackup/1.0.2/rebackup/index.html
+    WalkerRule {
+        name: "gitignore",
+        description: None,
+        only_for: None,
+        matches: Box::new(|path, _, _| path.ancestors().any(|path| path.join(".git").is_dir())),
+        action: Box::new(|dir, _, _| {
+            if check_ignore(dir).output()?.status.success() {
+                Ok(WalkerRuleResult::ExcludeItem)
+            } else {
+                Ok(WalkerRuleResult::IncludeItem)
+            }
+        }),
+    }
+}
+
+/// The `git check-ignore` command for the item at `path`.
+fn check_ignore(path: &Path) -> Command {
+    // Run git from within the directory of the item. This is set on the command
+    // rather than the process so that several backups can run at the same time.
+    let git_dir = if path.is_dir() {
+        Some(path)
+    } else {
+        path.parent()
+    };
+
+    let mut command = Command::new("git");
+    command
+        .arg("check-ignore")
+        .arg(path.to_string_lossy().to_string());
+    if let Some(git_dir) = git_dir {
+        command.current_dir(git_dir);
+    }
+    command
+}
+
+/// The ignore file pattern that makes git ignore an item.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IgnoreMatch {
+    /// The ignore file, e.g. a `.gitignore` or `.git/info/exclude`.
+    pub file: PathBuf,
+    /// The line of the pattern in the file, starting at 1.
+    pub line: usize,
+    pub pattern: String,
+}
+
+/// The pattern that makes git ignore the item at `path`, `None` if it is not ignored.
+pub fn gitignore_match(path: &Path) -> io::Result<Option<IgnoreMatch>> {
+    let mut command = check_ignore(path);
+    command.arg("--verbose");
+    let output = command.output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    // <file>:<line>:<pattern>\t<path>, the file is relative to the root of the repository
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let Some((source, _)) = stdout.split_once('\t') else {
+        return Ok(None);
+    };
+    let mut parts = source.splitn(3, ':');
+    let (Some(file), Some(line), Some(pattern)) = (parts.next(), parts.next(), parts.next()) else {
+        return Ok(None);
+    };
+    let root = path
+        .ancestors()
+        .find(|path| path.join(".git").is_dir())
+        .unwrap_or(Path::new(""));
+    Ok(Some(IgnoreMatch {
+        file: root.join(file),
+        line: line.parse().unwrap_or_default(),
+        pattern: pattern.to_string(),
+    }))
+}
+
+/// The file excluding the directory it is in from every backup.
+pub const NOBACKUP_FILE: &str = ".nobackup";
+/// The file forcing the directory it is in, and everything below, into every backup.
+pub const BACKUP_INCLUDE_FILE: &str = ".backup-include";
+/// The extended attribute excluding a file or directory from every backup (only on Unix).
+pub const EXCLUDE_XATTR: &str = "user.rackup.exclude";
+
+/// Rule to exclude the items marked with a [`NOBACKUP_FILE`] or the [`EXCLUDE_XATTR`]
+/// attribute, and to include the items in a directory marked with a
+/// [`BACKUP_INCLUDE_FILE`], see [`marker_decision`].
+pub fn marker_rule() -> WalkerRule {
+    WalkerRule {
+        name: "nobackup",
+        description: Some("Follow the .nobackup and .backup-include markers".to_string()),
+        only_for: None,
+        matches: Box::new(|_, _, _| true),
+        action: Box::new(|path, _, source| match marker_decision(path, source)? {
+            Some(MarkerDecision::Exclude(_)) => Ok(WalkerRuleResult::ExcludeItem),
+            Some(MarkerDecision::Include(_)) => Ok(WalkerRuleResult::IncludeItemAbsolute),
+            None => Ok(WalkerRuleResult::SkipRule),
+        }),
+    }
+}
+
+/// What a marker decides about an item, with the marker file or the item with the attribute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MarkerDecision {
+    Exclude(Marker),
+    Include(Marker),
+}
+
+/// Where a marker has been found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Marker {
+    /// A marker file.
+    File(PathBuf),
+    /// The [`EXCLUDE_XATTR`] attribute of a file or directory.
+    Xattr(PathBuf),
+}
+
+impl fmt::Display for Marker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Marker::File(path) => write!(f, "{}", path.to_string_lossy()),
+            Marker::Xattr(path) => write!(f, "{} on {}", EXCLUDE_XATTR, path.to_string_lossy()),
+        }
+    }
+}
+
+/// What is excluded by an exclusion marker of a directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Exclusion {
+    /// The directory with everything below it.
+    All,
+    /// Only the files directly in the directory, its subdirectories are still backed up.
+    Shallow,
+}
+
+impl Exclusion {
+    /// The exclusion given by the content of a marker: `shallow`, or nothing for all. `0`,
+    /// `false` or `no` do not exclude anything, which is how an attribute can be turned off.
+    fn parse(content: &[u8]) -> Option<Exclusion> {
+        match String::from_utf8_lossy(content)
+            .trim()
+            .to_lowercase()
+            .as_str()
+        {
+            "shallow" => Some(Exclusion::Shallow),
+            "0" | "false" | "no" => None,
+            _ => Some(Exclusion::All),
+        }
+    }
+}
+
+/// What the markers decide about the item at `path` in the `source` directory, `None` if
+/// they leave it to the other rules.
+///
+/// An item is excluded if it is a directory with a [`NOBACKUP_FILE`], or if it has the
+/// [`EXCLUDE_XATTR`] attribute. A marker with the content `shallow` only excludes the files
+/// directly in the directory. Otherwise an item is included if it or one of its ancestors
+/// in the source has a [`BACKUP_INCLUDE_FILE`].
+pub fn marker_decision(path: &Path, source: &Path) -> io::Result<Option<MarkerDecision>> {
+    let is_dir = path.is_dir() && !path.is_symlink();
+    if is_dir {
+        if let Some((Exclusion::All, marker)) = exclusion(path)? {
+            return Ok(Some(MarkerDecision::Exclude(marker)));
+        }
+    } else {
+        if let Some(marker) = excluding_xattr(path)? {
+            return Ok(Some(MarkerDecision::Exclude(marker)));
+        }
+        let parent = path.parent().filter(|parent| parent.starts_with(source));
+        if let Some(parent) = parent {
+            if let Some((Exclusion::Shallow, marker)) = exclusion(parent)? {
+                return Ok(Some(MarkerDecision::Exclude(marker)));
+            }
+        }
+    }
+
+    let start = if is_dir { Some(path) } else { path.parent() };
+    let include = start
+        .into_iter()
+        .flat_map(Path::ancestors)
+        .take_while(|dir| dir.starts_with(source))
+        .map(|dir| dir.join(BACKUP_INCLUDE_FILE))
+        .find(|marker| marker.is_file());
+    Ok(include.map(|marker| MarkerDecision::Include(Marker::File(marker))))
+}
+
+/// The exclusion marker of the directory `dir`, the marker file taking precedence.
+fn exclusion(dir: &Path) -> io::Result<Option<(Exclusion, Marker)>> {
+    let marker = dir.join(NOBACKUP_FILE);
+    match std::fs::read(&marker) {
+        Ok(content) => {
+            return Ok(Exclusion::parse(&content).map(|exclusion| (exclusion, Marker::File(marker))))
+        }
+        // Even a marker that cannot be read excludes the directory
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            return Ok(Some((Exclusion::All, Marker::File(marker))))
+        }
+        Err(_) => {}
+    }
+    Ok(xattr_exclusion(dir)?.map(|exclusion| (exclusion, Marker::Xattr(dir.to_path_buf()))))
+}
+
+/// The [`EXCLUDE_XATTR`] attribute of a file, which excludes it whatever its content.
+fn excluding_xattr(path: &Path) -> io::Result<Option<Marker>> {
+    Ok(xattr_exclusion(path)?.map(|_| Marker::Xattr(path.to_path_buf())))
+}
+
+/// The exclusion given by the [`EXCLUDE_XATTR`] attribute of the item at `path`, which is
+/// not followed if it is a symbolic link.
+#[cfg(unix)]
+fn xattr_exclusion(path: &Path) -> io::Result<Option<Exclusion>> {
+    match xattr::get(path, EXCLUDE_XATTR) {
+        Ok(value) => Ok(value.and_then(|value| Exclusion::parse(&value))),
+        // File systems without extended attributes, or items removed since they were found
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::Unsupported | io::ErrorKind::NotFound
+            ) || err.raw_os_error() == Some(libc::ENOTSUP) =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(not(unix))]
+fn xattr_exclusion(_path: &Path) -> io::Result<Option<Exclusion>> {
+    Ok(None)
+}
+
+/// Rule to not back up executable programs, see [`ExecutableKind::DEFAULT_EXCLUDED`].
+pub fn exe_rule() -> WalkerRule {
+    executable_rule(ExecutableKind::DEFAULT_EXCLUDED.to_vec())
+}
+
+/// Rule to not back up the executable files of the `exclude` kinds, recognized by their
+/// content (see [`ExecutableKind::detect`]).
+pub fn executable_rule(exclude: Vec<ExecutableKind>) -> WalkerRule {
+    let excludes_any = !exclude.is_empty();
+    WalkerRule {
+        name: "noexe",
+        description: Some("Do not backup executable files".to_string()),
+        only_for: Some(WalkerItemType::File),
+        matches: Box::new(move |path, _, _| excludes_any && path.is_file()),
+        action: Box::new(move |path, _, _| match ExecutableKind::detect(path) {
+            Some(kind) if exclude.contains(&kind) => Ok(WalkerRuleResult::ExcludeItem),
+            _ => Ok(WalkerRuleResult::IncludeItem),
+        }),
+    }
+}
+
+/// A kind of executable or library file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecutableKind {
+    /// A Linux or BSD program (ELF).
+    Elf,
+    /// A Windows program (PE), e.g. an `.exe` file.
+    Pe,
+    /// A macOS program (Mach-O).
+    MachO,
+    /// A shared library of any of the formats, e.g. a `.so`, `.dll` or `.dylib` file.
+    SharedObject,
+    /// A static library, e.g. an `.a` or `.lib` file.
+    StaticArchive,
+}
+
+impl ExecutableKind {
+    /// The kinds excluded by default: programs, but not libraries.
+    pub const DEFAULT_EXCLUDED: [ExecutableKind; 3] = [
+        ExecutableKind::Elf,
+        ExecutableKind::Pe,
+        ExecutableKind::MachO,
+    ];
+
+    /// The kind of executable file at `path`, by the magic bytes at its start.
+    ///
+    /// If the content is not recognized, e.g. because the file cannot be read, the kind is
+    /// taken from the extension of the file, ignoring its case.
+    pub fn detect(path: &Path) -> Option<ExecutableKind> {
+        let by_content = File::open(path).and_then(|mut file| Self::from_content(&mut file));
+        match by_content {
+            Ok(Some(kind)) => Some(kind),
+            _ => Self::from_extension(path),
+        }
+    }
+
+    /// The kind of executable by its extension, ignoring its case.
+    pub fn from_extension(path: &Path) -> Option<ExecutableKind> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "exe" => Some(ExecutableKind::Pe),
+            "so" | "dll" | "dylib" => Some(ExecutableKind::SharedObject),
+            "a" | "lib" => Some(ExecutableKind::StaticArchive),
+            _ => None,
+        }
+    }
+
+    /// The kind of executable by the magic bytes at the start of `content`.
+    pub fn from_content(content: &mut (impl Read + Seek)) -> io::Result<Option<ExecutableKind>> {
+        let mut header = [0; 64];
+        let len = read_up_to(content, &mut header)?;
+        let header = &header[..len];
+
+        let kind = if header.starts_with(b"\x7fELF") {
+            elf_kind(content, header)?
+        } else if header.starts_with(b"MZ") {
+            Some(pe_kind(content, header)?)
+        } else if let Some(file_type) = mach_o_file_type(header) {
+            match file_type {
+                MH_EXECUTE => Some(ExecutableKind::MachO),
+                MH_DYLIB | MH_BUNDLE => Some(ExecutableKind::SharedObject),
+                _ => None,
+            }
+        } else if is_mach_o_universal(header) {
+            Some(ExecutableKind::MachO)
+        } else if header.starts_with(b"!<arch>\n") {
+            // Debian packages are ar archives as well
+            (!header[8..].starts_with(b"debian-binary")).then_some(ExecutableKind::StaticArchive)
+        } else {
+            None
+        };
+        Ok(kind)
+    }
+}
+
+impl fmt::Display for ExecutableKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ExecutableKind::Elf => "ELF program",
+            ExecutableKind::Pe => "Windows program",
+            ExecutableKind::MachO => "Mach-O program",
+            ExecutableKind::SharedObject => "shared library",
+            ExecutableKind::StaticArchive => "static library",
+        })
+    }
+}
+
+// ELF object file types and program header types
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const PT_INTERP: u32 = 3;
+/// More program headers are not read, executables have far fewer.
+const MAX_PROGRAM_HEADERS: u64 = 128;
+
+// Mach-O file types
+const MH_EXECUTE: u32 = 2;
+const MH_DYLIB: u32 = 6;
+const MH_BUNDLE: u32 = 8;
+
+/// The PE characteristic of a DLL.
+const IMAGE_FILE_DLL: u16 = 0x2000;
+
+/// Reads into `buf` until it is full or the end of `content` is reached.
+fn read_up_to(content: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match content.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+/// Reads `N` bytes at `offset`, `None` if the content is shorter.
+fn read_at<const N: usize>(
+    content: &mut (impl Read + Seek),
+    offset: u64,
+) -> io::Result<Option<[u8; N]>> {
+    let mut buf = [0; N];
+    content.seek(SeekFrom::Start(offset))?;
+    Ok((read_up_to(content, &mut buf)? == N).then_some(buf))
+}
+
+/// An ELF program, or a shared object. Position independent programs have the same object
+/// type as shared objects, but unlike them they request a program interpreter.
+fn elf_kind(content: &mut (impl Read + Seek), header: &[u8]) -> io::Result<Option<ExecutableKind>> {
+    if header.len() < 64 {
+        return Ok(None);
+    }
+    let is_64 = header[4] == 2;
+    let big_endian = header[5] == 2;
+    let u16_at = |offset: usize| {
+        let bytes = [header[offset], header[offset + 1]];
+        if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }
+    };
+    let u32_from = |bytes: [u8; 4]| {
+        if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+
+    match u16_at(16) {
+        ET_EXEC => return Ok(Some(ExecutableKind::Elf)),
+        ET_DYN => {}
+        // Object files and core dumps
+        _ => return Ok(None),
+    }
+
+    let (program_headers, entry_size, entries) = if is_64 {
+        let mut offset = [0; 8];
+        offset.copy_from_slice(&header[32..40]);
+        let offset = if big_endian {
+            u64::from_be_bytes(offset)
+        } else {
+            u64::from_le_bytes(offset)
+        };
+        (offset, u16_at(54), u16_at(56))
+    } else {
+        let offset = u32_from([header[28], header[29], header[30], header[31]]);
+        (offset.into(), u16_at(42), u16_at(44))
+    };
+
+    for i in 0..u64::from(entries).min(MAX_PROGRAM_HEADERS) {
+        let offset = program_headers.saturating_add(i * u64::from(entry_size));
+        match read_at::<4>(content, offset)? {
+            Some(program_type) if u32_from(program_type) == PT_INTERP => {
+                return Ok(Some(ExecutableKind::Elf))
+            }
+            Some(_) => {}
+            None => break,
+        }
+    }
+    Ok(Some(ExecutableKind::SharedObject))
+}
+
+/// A PE program or DLL, or a DOS program without a PE header.
+fn pe_kind(content: &mut (impl Read + Seek), header: &[u8]) -> io::Result<ExecutableKind> {
+    if header.len() < 64 {
+        return Ok(ExecutableKind::Pe);
+    }
+    let pe_offset = u32::from_le_bytes([header[60], header[61], header[62], header[63]]);
+    // The characteristics follow the signature, machine, number of sections, time stamp,
+    // symbol table pointer, number of symbols and optional header size
+    match read_at::<24>(content, pe_offset.into())? {
+        Some(pe) if pe.starts_with(b"PE\0\0") => {
+            let characteristics = u16::from_le_bytes([pe[22], pe[23]]);
+            if characteristics & IMAGE_FILE_DLL != 0 {
+                Ok(ExecutableKind::SharedObject)
+            } else {
+                Ok(ExecutableKind::Pe)
+            }
+        }
+        _ => Ok(ExecutableKind::Pe),
+    }
+}
+
+/// The file type of a Mach-O file, `None` if it is not one.
+fn mach_o_file_type(header: &[u8]) -> Option<u32> {
+    let magic: [u8; 4] = header.get(..4)?.try_into().ok()?;
+    let file_type: [u8; 4] = header.get(12..16)?.try_into().ok()?;
+    match u32::from_be_bytes(magic) {
+        0xfeedface | 0xfeedfacf => Some(u32::from_be_bytes(file_type)),
+        0xcefaedfe | 0xcffaedfe => Some(u32::from_le_bytes(file_type)),
+        _ => None,
+    }
+}
+
+/// `true` for a universal Mach-O file, which contains the program for several architectures.
+/// Java class files start with the same magic number, but they are followed by a version
+/// that is much larger than the number of architectures.
+fn is_mach_o_universal(header: &[u8]) -> bool {
+    header.len() >= 8
+        && header.starts_with(&[0xca, 0xfe, 0xba, 0xbe])
+        && (1..20).contains(&u32::from_be_bytes([
+            header[4], header[5], header[6], header[7],
+        ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// The start of a 64 bit little endian ELF file of `object_type`, with a program header
+    /// of `program_type`.
+    fn elf(object_type: u16, program_type: u32) -> Vec<u8> {
+        let mut content = vec![0; 64 + 56];
+        content[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        content[16..18].copy_from_slice(&object_type.to_le_bytes());
+        content[32..40].copy_from_slice(&64u64.to_le_bytes());
+        content[54..56].copy_from_slice(&56u16.to_le_bytes());
+        content[56..58].copy_from_slice(&1u16.to_le_bytes());
+        content[64..68].copy_from_slice(&program_type.to_le_bytes());
+        content
+    }
+
+    /// The start of a PE file with `characteristics`.
+    fn pe(characteristics: u16) -> Vec<u8> {
+        let mut content = vec![0; 128 + 24];
+        content[..2].copy_from_slice(b"MZ");
+        content[60..64].copy_from_slice(&128u32.to_le_bytes());
+        content[128..132].copy_from_slice(b"PE\0\0");
+        content[150..152].copy_from_slice(&characteristics.to_le_bytes());
+        content
+    }
+
+    fn mach_o(magic: u32, file_type: u32) -> Vec<u8> {
+        let mut content = vec![0; 32];
+        content[..4].copy_from_slice(&magic.to_le_bytes());
+        content[12..16].copy_from_slice(&file_type.to_le_bytes());
+        content
+    }
+
+    fn kind(content: &[u8]) -> Option<ExecutableKind> {
+        ExecutableKind::from_content(&mut Cursor::new(content)).unwrap()
+    }
+
+    #[test]
+    fn test_executable_kind_from_content() {
+        assert_eq!(kind(&elf(ET_EXEC, 1)), Some(ExecutableKind::Elf));
+        // A position independent program and a shared object
+        assert_eq!(kind(&elf(ET_DYN, PT_INTERP)), Some(ExecutableKind::Elf));
+        assert_eq!(kind(&elf(ET_DYN, 1)), Some(ExecutableKind::SharedObject));
+        // An object file
+        assert_eq!(kind(&elf(1, 0)), None);
+
+        assert_eq!(kind(&pe(0x0102)), Some(ExecutableKind::Pe));
+        assert_eq!(kind(&pe(0x2102)), Some(ExecutableKind::SharedObject));
+        assert_eq!(kind(b"MZ"), Some(ExecutableKind::Pe));
+
+        assert_eq!(
+            kind(&mach_o(0xfeedfacf, MH_EXECUTE)),
+            Some(ExecutableKind::MachO)
+        );
+        assert_eq!(
+            kind(&mach_o(0xfeedfacf, MH_DYLIB)),
+            Some(ExecutableKind::SharedObject)
+        );
+        assert_eq!(
+            kind(b"\xca\xfe\xba\xbe\0\0\0\x02"),
+            Some(ExecutableKind::MachO)
+        );
+        // A Java class file
+        assert_eq!(kind(b"\xca\xfe\xba\xbe\0\0\0\x34"), None);
+
+        assert_eq!(
+            kind(b"!<arch>\nlibfoo.o/"),
+            Some(ExecutableKind::StaticArchive)
+        );
+        assert_eq!(kind(b"!<arch>\ndebian-binary   "), None);
+
+        assert_eq!(kind(b""), None);
+        assert_eq!(kind(b"#!/bin/sh\necho hello\n"), None);
+    }
+
+    #[test]
+    fn test_executable_kind_detect() -> io::Result<()> {
+        let test_dir = tempfile::tempdir()?;
+
+        // The content is recognized whatever the name
+        let program = test_dir.path().join("program");
+        std::fs::write(&program, elf(ET_EXEC, 1))?;
+        assert_eq!(ExecutableKind::detect(&program), Some(ExecutableKind::Elf));
+        let library = test_dir.path().join("library.txt");
+        std::fs::write(&library, pe(0x2102))?;
+        assert_eq!(
+            ExecutableKind::detect(&library),
+            Some(ExecutableKind::SharedObject)
+        );
+
+        // Otherwise the extension is used, ignoring its case
+        let setup = test_dir.path().join("SETUP.EXE");
+        File::create(&setup)?;
+        assert_eq!(ExecutableKind::detect(&setup), Some(ExecutableKind::Pe));
+        assert_eq!(
+            ExecutableKind::detect(Path::new("/removed/libfoo.DyLib")),
+            Some(ExecutableKind::SharedObject)
+        );
+        assert_eq!(
+            ExecutableKind::detect(Path::new("/removed/notes.txt")),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_marker_decision() -> io::Result<()> {
+        let test_dir = tempfile::tempdir()?;
+        let source = test_dir.path();
+        for dir in [
+            "cache/nested",
+            "downloads/keep",
+            "build/tools",
+            "build/tools/tmp",
+        ] {
+            std::fs::create_dir_all(source.join(dir))?;
+        }
+        for file in [
+            "cache/data.bin",
+            "downloads/big.iso",
+            "downloads/keep/notes.txt",
+        ] {
+            File::create(source.join(file))?;
+        }
+        File::create(source.join("cache").join(NOBACKUP_FILE))?;
+        std::fs::write(source.join("downloads").join(NOBACKUP_FILE), "shallow\n")?;
+        File::create(source.join("build/tools").join(BACKUP_INCLUDE_FILE))?;
+        File::create(source.join("build/tools/tmp").join(NOBACKUP_FILE))?;
+
+        let decision = |path: &str| marker_decision(&source.join(path), source).unwrap();
+        assert_eq!(
+            decision("cache"),
+            Some(MarkerDecision::Exclude(Marker::File(
+                source.join("cache").join(NOBACKUP_FILE)
+            )))
+        );
+
+        // A shallow marker only excludes the files next to it
+        assert_eq!(decision("downloads"), None);
+        assert!(matches!(
+            decision("downloads/big.iso"),
+            Some(MarkerDecision::Exclude(_))
+        ));
+        assert_eq!(decision("downloads/keep"), None);
+        assert_eq!(decision("downloads/keep/notes.txt"), None);
+
+        // Everything below an include marker is included, unless it is excluded itself
+        let include = Some(MarkerDecision::Include(Marker::File(
+            source.join("build/tools").join(BACKUP_INCLUDE_FILE),
+        )));
+        assert_eq!(decision("build/tools"), include);
+        assert_eq!(decision("build/tools/.backup-include"), include);
+        assert!(matches!(
+            decision("build/tools/tmp"),
+            Some(MarkerDecision::Exclude(_))
+        ));
+        assert_eq!(decision("build"), None);
+
+        // The attribute is not supported by every file system
+        #[cfg(unix)]
+        if xattr::set(source.join("downloads/keep/notes.txt"), EXCLUDE_XATTR, b"1").is_ok() {
+            assert_eq!(
+                decision("downloads/keep/notes.txt"),
+                Some(MarkerDecision::Exclude(Marker::Xattr(
+                    source.join("downloads/keep/notes.txt")
+                )))
+            );
+            xattr::set(source.join("downloads/keep"), EXCLUDE_XATTR, b"false")?;
+            assert_eq!(decision("downloads/keep"), None);
+        }
+
+        Ok(())
+    }
+}
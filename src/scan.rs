@@ -0,0 +1,267 @@
+//! Finding the items to back up below a single changed path, without walking the whole source.
+//!
+//! The rules are evaluated on the changed path and on each of its ancestors within the source,
+//! the same way the walk evaluates them, so that an item is only backed up if the walk would
+//! have found it as well.
+//!
+use rebackup::{
+    WalkerConfig, WalkerErr, WalkerItemType, WalkerRule, WalkerRuleErr, WalkerRuleResult,
+};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Scans changed paths of one source directory.
+pub(crate) struct Scan<'a> {
+    config: &'a WalkerConfig,
+    /// The canonicalized source directory.
+    source: &'a Path,
+    /// Whether the directories already checked are included.
+    included_dirs: HashMap<PathBuf, bool>,
+}
+
+impl<'a> Scan<'a> {
+    pub fn new(config: &'a WalkerConfig, source: &'a Path) -> Self {
+        Scan {
+            config,
+            source,
+            included_dirs: HashMap::new(),
+        }
+    }
+
+    /// The items the walk would find at `path`: the file itself, or the files and empty
+    /// directories below a directory. Nothing is returned if `path` or one of its ancestors
+    /// is excluded, or if it is not in the source.
+    pub fn items(&mut self, path: &Path) -> Result<Vec<PathBuf>, WalkerErr> {
+        let Ok(relative) = path.strip_prefix(self.source) else {
+            return Ok(Vec::new());
+        };
+
+        let mut ancestor = self.source.to_path_buf();
+        let mut components = relative.components().peekable();
+        while let Some(component) = components.next() {
+            ancestor.push(component);
+            let included = match self.included_dirs.get(&ancestor) {
+                Some(&included) => included,
+                None => {
+                    let included = self.is_included(&ancestor)?;
+                    if ancestor.is_dir() {
+                        self.included_dirs.insert(ancestor.clone(), included);
+                    }
+                    included
+                }
+            };
+            if !included {
+                return Ok(Vec::new());
+            }
+            // Only the changed path itself may be a file
+            if components.peek().is_some() && !ancestor.is_dir() {
+                return Ok(Vec::new());
+            }
+        }
+
+        let mut items = Vec::new();
+        if path == self.source || path.is_dir() {
+            let mut visited = HashSet::new();
+            self.walk_dir(path, &mut visited, &mut items)?;
+        } else {
+            items.push(path.to_path_buf());
+        }
+        Ok(items)
+    }
+
+    /// `true` if the item at `path` exists and is included by the rules. Its ancestors are
+    /// not checked.
+    fn is_included(&self, path: &Path) -> Result<bool, WalkerErr> {
+        // The item may have been removed again since it changed
+        match walked_item_type(self.config, path) {
+            Some(item_type) => evaluate_rules(self.config, self.source, path, item_type, |_, _| {}),
+            None => Ok(false),
+        }
+    }
+
+    /// Adds the included items below the directory `dir`, like the walk does.
+    fn walk_dir(
+        &self,
+        dir: &Path,
+        visited: &mut HashSet<PathBuf>,
+        items: &mut Vec<PathBuf>,
+    ) -> Result<(), WalkerErr> {
+        // Followed symbolic links may lead back to a directory that has been walked already
+        let canonicalized = fs::canonicalize(dir)
+            .map_err(|err| WalkerErr::FailedToCanonicalize(dir.into(), err))?;
+        if !visited.insert(canonicalized) {
+            return Ok(());
+        }
+
+        let mut contains_items = false;
+        for entry in fs::read_dir(dir).map_err(WalkerErr::FailedToWalkDir)? {
+            let path = entry.map_err(WalkerErr::FailedToReadDirEntry)?.path();
+            contains_items = true;
+
+            if !self.is_included(&path)? {
+                continue;
+            }
+            if path.is_dir() {
+                self.walk_dir(&path, visited, items)?;
+            } else {
+                items.push(path);
+            }
+        }
+
+        if !contains_items && !self.config.drop_empty_dirs {
+            items.push(dir.to_path_buf());
+        }
+        Ok(())
+    }
+}
+
+/// The type of the item at `path` as the walk sees it, `None` if it does not exist or is not
+/// walked, like symbolic links that are not followed.
+pub(crate) fn walked_item_type(config: &WalkerConfig, path: &Path) -> Option<WalkerItemType> {
+    let file_type = path.symlink_metadata().ok()?.file_type();
+    if file_type.is_symlink() {
+        config.follow_symlinks.then_some(WalkerItemType::Symlink)
+    } else if file_type.is_dir() {
+        Some(WalkerItemType::Directory)
+    } else if file_type.is_file() {
+        Some(WalkerItemType::File)
+    } else {
+        None
+    }
+}
+
+/// How a rule has been checked on an item by [`evaluate_rules`].
+pub(crate) enum RuleCheck<'a> {
+    /// The rule is only for other types of items.
+    NotFor,
+    /// The item does not match the rule.
+    NotMatching,
+    /// The action of the rule has run.
+    Result(&'a WalkerRuleResult),
+    /// The action of the rule has failed, which fails the walk.
+    Failed(&'a WalkerRuleErr),
+}
+
+/// Evaluates the rules of `config` on the item at `path` of `item_type` in the `source`
+/// directory, like the walk does. `checked` is called with every rule checked and how, until
+/// a rule decides. Returns `true` if the item is included.
+pub(crate) fn evaluate_rules<'c>(
+    config: &'c WalkerConfig,
+    source: &Path,
+    path: &Path,
+    item_type: WalkerItemType,
+    mut checked: impl FnMut(&'c WalkerRule, RuleCheck),
+) -> Result<bool, WalkerErr> {
+    for rule in &config.rules {
+        if rule.only_for.is_some_and(|only_for| only_for != item_type) {
+            checked(rule, RuleCheck::NotFor);
+            continue;
+        }
+        if !(rule.matches)(path, config, source) {
+            checked(rule, RuleCheck::NotMatching);
+            continue;
+        }
+
+        let result = match (rule.action)(path, config, source) {
+            Ok(WalkerRuleResult::StrError(err)) => Err(WalkerRuleErr::Str(err)),
+            result => result.map_err(WalkerRuleErr::Io),
+        };
+        let result = match result {
+            Ok(result) => result,
+            Err(err) => {
+                checked(rule, RuleCheck::Failed(&err));
+                return Err(rule_failed(rule, path, err));
+            }
+        };
+        checked(rule, RuleCheck::Result(&result));
+        match result {
+            WalkerRuleResult::IncludeItemAbsolute => break,
+            WalkerRuleResult::ExcludeItem => return Ok(false),
+            // The mapped items are not known without walking the parent, so the item is
+            // taken as it is
+            WalkerRuleResult::MapAsList(..) => break,
+            // Skipped and included items are checked by the next rule
+            _ => {}
+        }
+    }
+    Ok(true)
+}
+
+/// The error of a rule that failed on the item `path`, as the walk reports it.
+fn rule_failed(rule: &WalkerRule, path: &Path, err: WalkerRuleErr) -> WalkerErr {
+    WalkerErr::RuleFailedToRun {
+        rule_name: rule.name,
+        rule_description: rule
+            .description
+            .clone()
+            .unwrap_or_else(|| "<no rule description>".to_string()),
+        item_path: path.to_path_buf(),
+        err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::exe_rule;
+    use std::fs::File;
+    use std::io;
+
+    fn config(rules: Vec<WalkerRule>) -> WalkerConfig {
+        WalkerConfig {
+            rules,
+            follow_symlinks: false,
+            drop_empty_dirs: false,
+        }
+    }
+
+    fn dir_rule(name: &'static str) -> WalkerRule {
+        WalkerRule {
+            name: "dir",
+            description: None,
+            only_for: Some(WalkerItemType::Directory),
+            matches: Box::new(move |path, _, _| path.ends_with(name)),
+            action: Box::new(|_, _, _| Ok(WalkerRuleResult::ExcludeItem)),
+        }
+    }
+
+    #[test]
+    fn test_items() -> io::Result<()> {
+        let test_dir = tempfile::tempdir()?;
+        let source = fs::canonicalize(test_dir.path())?;
+        fs::create_dir_all(source.join("docs/empty"))?;
+        fs::create_dir_all(source.join("cache/nested"))?;
+        File::create(source.join("docs/notes.txt"))?;
+        File::create(source.join("docs/setup.exe"))?;
+        File::create(source.join("cache/nested/data.bin"))?;
+
+        let config = config(vec![exe_rule(), dir_rule("cache")]);
+        let mut scan = Scan::new(&config, &source);
+        let mut items = |path: &Path| {
+            let mut items = scan.items(path).expect("Failed to scan");
+            items.sort();
+            items
+        };
+
+        assert_eq!(
+            items(&source.join("docs/notes.txt")),
+            vec![source.join("docs/notes.txt")]
+        );
+        assert_eq!(
+            items(&source.join("docs")),
+            vec![source.join("docs/empty"), source.join("docs/notes.txt")]
+        );
+
+        // Excluded items, also below an excluded directory
+        assert!(items(&source.join("docs/setup.exe")).is_empty());
+        assert!(items(&source.join("cache/nested/data.bin")).is_empty());
+        assert!(items(&source.join("cache/nested")).is_empty());
+
+        // Removed items and paths outside the source
+        assert!(items(&source.join("docs/removed.txt")).is_empty());
+        assert!(items(Path::new("/etc/hostname")).is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+//! When profiles are backed up by `rackup daemon`.
+//!
+//! A schedule is either a cron pattern in local time, such as `"0 3 * * *"` for every night at
+//! 3:00 or `"@hourly"`, or an interval such as `"every 6h"`:
+//!
+//! ```toml
+//! [profiles.documents]
+//! source = "/home/bob/Documents"
+//! destination = "/media/backup"
+//! schedule = "30 2 * * mon-fri"
+//! ```
+//!
+use chrono::{DateTime, Local};
+use croner::Cron;
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+/// When a profile is backed up.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+    /// At the times matching a cron pattern.
+    Cron {
+        /// The pattern as it was given.
+        pattern: String,
+        cron: Box<Cron>,
+    },
+    /// Every time the interval has passed since the last run.
+    Interval(Duration),
+}
+
+impl Schedule {
+    /// When the profile has to run next, after a run that started at `last`.
+    ///
+    /// The time may be in the past if runs have been missed, e.g. while the computer was
+    /// asleep. `None` if the cron pattern never matches again.
+    pub fn next_after(&self, last: SystemTime) -> Option<SystemTime> {
+        match self {
+            Schedule::Cron { cron, .. } => {
+                let last: DateTime<Local> = last.into();
+                cron.find_next_occurrence(&last, false)
+                    .ok()
+                    .map(SystemTime::from)
+            }
+            Schedule::Interval(interval) => last.checked_add(*interval),
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(schedule: &str) -> Result<Self, Self::Err> {
+        let schedule = schedule.trim();
+        if let Some(interval) = schedule.strip_prefix("every ") {
+            let interval = humantime::parse_duration(interval.trim())
+                .map_err(|err| format!("Invalid interval in schedule {}: {}", schedule, err))?;
+            if interval.is_zero() {
+                return Err(format!("Invalid interval in schedule {}", schedule));
+            }
+            return Ok(Schedule::Interval(interval));
+        }
+
+        let cron = Cron::from_str(schedule)
+            .map_err(|err| format!("Invalid cron pattern {}: {}", schedule, err))?;
+        Ok(Schedule::Cron {
+            pattern: schedule.to_string(),
+            cron: Box::new(cron),
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Schedule::Cron { pattern, .. } => write!(f, "{}", pattern),
+            Schedule::Interval(interval) => {
+                write!(f, "every {}", humantime::format_duration(*interval))
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Schedule {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "every 6h".parse::<Schedule>(),
+            Ok(Schedule::Interval(Duration::from_secs(6 * 60 * 60)))
+        );
+        assert!(matches!(
+            "0 3 * * *".parse::<Schedule>(),
+            Ok(Schedule::Cron { .. })
+        ));
+        assert!(matches!(
+            "@daily".parse::<Schedule>(),
+            Ok(Schedule::Cron { .. })
+        ));
+        assert_eq!(
+            "30 2 * * mon-fri".parse::<Schedule>().unwrap().to_string(),
+            "30 2 * * mon-fri"
+        );
+
+        assert!("every day".parse::<Schedule>().is_err());
+        assert!("every 0s".parse::<Schedule>().is_err());
+        assert!("61 * * * *".parse::<Schedule>().is_err());
+        assert!("tomorrow".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn test_next_after() {
+        let interval: Schedule = "every 2h".parse().unwrap();
+        let last = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            interval.next_after(last),
+            Some(last + Duration::from_secs(2 * 60 * 60))
+        );
+
+        // The cron pattern is in local time
+        let nightly: Schedule = "0 3 * * *".parse().unwrap();
+        let last = Local.with_ymd_and_hms(2024, 5, 1, 3, 0, 0).unwrap();
+        let next: DateTime<Local> = nightly.next_after(last.into()).unwrap().into();
+        assert_eq!(next, Local.with_ymd_and_hms(2024, 5, 2, 3, 0, 0).unwrap());
+
+        let last = Local.with_ymd_and_hms(2024, 5, 1, 17, 25, 12).unwrap();
+        let next: DateTime<Local> = nightly.next_after(last.into()).unwrap().into();
+        assert_eq!(next, Local.with_ymd_and_hms(2024, 5, 2, 3, 0, 0).unwrap());
+    }
+}
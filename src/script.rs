@@ -0,0 +1,445 @@
+//! Rules written as [Rhai](https://rhai.rs) scripts, for decisions that the
+//! [declarative rules](crate::filter) cannot express.
+//!
+//! A script is evaluated for every item, with the item in the variable `item`. It returns
+//! `"include"` to back the item up regardless of the built-in rules, `"exclude"` to leave it
+//! out, or nothing to leave the decision to the following rules:
+//!
+//! ```rhai
+//! // Leave out the build directories of Rust projects
+//! if item.is_dir && item.name == "target" && exists(item.parent + "/Cargo.toml") {
+//!     "exclude"
+//! }
+//! ```
+//!
+//! `item` has the properties `path`, `relative` (the path relative to the source, with `/`
+//! separators), `parent` (the relative path of the parent directory), `name`, `extension`,
+//! `is_file`, `is_dir`, `is_symlink`, `size`, `modified` (a Unix timestamp), `age` (the
+//! seconds since it was modified), `hidden`, and on Unix `owner` and `group` (the ids).
+//! `exists(path)` tells whether a path relative to the source exists, without following
+//! symbolic links out of the source.
+//!
+//! Scripts are sandboxed: they cannot read files, load modules or `eval` code, and their
+//! evaluation is stopped after a timeout. A script that fails or times out on an item only
+//! fails that item (see [`RuleItemError`]), the backup goes on with the other items.
+//!
+//! The rule of a script is named after it, e.g. `script:rules/cargo.rhai`, so that
+//! explanations and events tell which script decided.
+//!
+use crate::error::RuleItemError;
+use rebackup::{WalkerRule, WalkerRuleResult};
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Dynamic, Engine, EvalAltResult, Map, ParseError, Position, Scope, AST};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long the evaluation of a script for one item may take by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A rule script, read from a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleScript {
+    pub path: PathBuf,
+    pub code: String,
+}
+
+impl RuleScript {
+    /// Reads the script at `path` and checks that it compiles.
+    pub fn load(path: &Path) -> Result<RuleScript, String> {
+        let code = fs::read_to_string(path)
+            .map_err(|err| format!("Cannot read the script {}: {}", path.to_string_lossy(), err))?;
+        let script = RuleScript {
+            path: path.to_path_buf(),
+            code,
+        };
+        script.compile(&sandboxed_engine())?;
+        Ok(script)
+    }
+
+    fn compile(&self, engine: &Engine) -> Result<AST, String> {
+        engine
+            .compile(&self.code)
+            .map_err(|ParseError(err, position)| self.error(position, err))
+    }
+
+    /// An error at `position` in the script, naming the script and the line.
+    fn error(&self, position: Position, message: impl std::fmt::Display) -> String {
+        match position.line() {
+            Some(line) => format!("{} line {}: {}", self.path.to_string_lossy(), line, message),
+            None => format!("{}: {}", self.path.to_string_lossy(), message),
+        }
+    }
+}
+
+/// An engine that cannot touch anything outside the script.
+fn sandboxed_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_module_resolver(DummyModuleResolver::new())
+        .disable_symbol("eval")
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(1024 * 1024)
+        .set_max_array_size(10_000)
+        .set_max_map_size(10_000)
+        .on_print(|_| {})
+        .on_debug(|_, _, _| {});
+    engine
+}
+
+/// Rule evaluating `script` for every item. An evaluation that fails or takes longer than
+/// `timeout` fails the item with a [`RuleItemError`].
+pub fn script_rule(script: RuleScript, timeout: Duration) -> WalkerRule {
+    let mut engine = sandboxed_engine();
+
+    // The deadline of the running evaluation
+    let deadline = Rc::new(Cell::new(Instant::now()));
+    let progress_deadline = deadline.clone();
+    engine.on_progress(move |operations| {
+        // Reading the clock for every operation would slow scripts down
+        if operations % 256 == 0 && Instant::now() > progress_deadline.get() {
+            Some(Dynamic::UNIT)
+        } else {
+            None
+        }
+    });
+
+    // The source directory of the item, for `exists`
+    let source_dir = Rc::new(RefCell::new(PathBuf::new()));
+    let exists_source = source_dir.clone();
+    engine.register_fn("exists", move |path: &str| {
+        let path = Path::new(path.trim_start_matches('/'));
+        // Only paths within the source can be checked
+        if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return false;
+        }
+        // Symbolic links on the way to the path cannot lead out of the source either
+        let source = exists_source.borrow();
+        let path = source.join(path);
+        let within = match (
+            path.parent().map(fs::canonicalize),
+            fs::canonicalize(&*source),
+        ) {
+            (Some(Ok(parent)), Ok(source)) => parent.starts_with(source),
+            _ => false,
+        };
+        within && path.symlink_metadata().is_ok()
+    });
+
+    let ast = script.compile(&engine);
+    let description = format!("Script {}", script.path.to_string_lossy());
+    WalkerRule {
+        name: rule_name(&script.path),
+        description: Some(description),
+        only_for: None,
+        matches: Box::new(|_, _, _| true),
+        action: Box::new(move |path, _, source| {
+            let ast = match &ast {
+                Ok(ast) => ast,
+                Err(err) => return Ok(WalkerRuleResult::StrError(err.clone())),
+            };
+
+            *source_dir.borrow_mut() = source.to_path_buf();
+            let mut scope = Scope::new();
+            scope.push_constant("item", item(path, source));
+
+            deadline.set(Instant::now() + timeout);
+            let result = match engine.eval_ast_with_scope::<Dynamic>(&mut scope, ast) {
+                Ok(result) => result,
+                Err(err) => {
+                    let mut err = *err;
+                    let position = err.take_position();
+                    let message = match err {
+                        EvalAltResult::ErrorTerminated(..) => format!(
+                            "evaluation for {} took longer than {}",
+                            path.to_string_lossy(),
+                            humantime::format_duration(timeout)
+                        ),
+                        err => format!("{} (evaluating {})", err, path.to_string_lossy()),
+                    };
+                    return Err(RuleItemError(script.error(position, message)).into());
+                }
+            };
+
+            if result.is_unit() {
+                return Ok(WalkerRuleResult::SkipRule);
+            }
+            match result.into_string().as_deref() {
+                Ok("include") => Ok(WalkerRuleResult::IncludeItemAbsolute),
+                Ok("exclude") => Ok(WalkerRuleResult::ExcludeItem),
+                Ok(other) => Err(RuleItemError(script.error(
+                    Position::NONE,
+                    format!(
+                        "returned \"{}\" instead of \"include\", \"exclude\" or nothing",
+                        other
+                    ),
+                ))
+                .into()),
+                Err(type_name) => Err(RuleItemError(script.error(
+                    Position::NONE,
+                    format!(
+                        "returned a {} instead of \"include\", \"exclude\" or nothing",
+                        type_name
+                    ),
+                ))
+                .into()),
+            }
+        }),
+    }
+}
+
+/// The name of the rule of the script at `path`. Rule names are `'static`, so every name is
+/// leaked once, however often the rule is created.
+fn rule_name(path: &Path) -> &'static str {
+    static NAMES: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+
+    let name = format!("script:{}", path.to_string_lossy());
+    let mut names = NAMES.lock().unwrap_or_else(|err| err.into_inner());
+    match names.get(name.as_str()) {
+        Some(name) => name,
+        None => {
+            let name = Box::leak(name.into_boxed_str());
+            names.insert(name);
+            name
+        }
+    }
+}
+
+/// The `item` variable of a script for the item at `path` in the `source` directory.
+fn item(path: &Path, source: &Path) -> Map {
+    let relative: Vec<String> = path
+        .strip_prefix(source)
+        .unwrap_or(path)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect();
+    let name = relative.last().cloned().unwrap_or_default();
+    let parent = relative[..relative.len().saturating_sub(1)].join("/");
+
+    let mut item = Map::new();
+    item.insert("path".into(), path.to_string_lossy().to_string().into());
+    item.insert("relative".into(), relative.join("/").into());
+    item.insert("parent".into(), parent.into());
+    item.insert("hidden".into(), name.starts_with('.').into());
+    item.insert("name".into(), name.into());
+    item.insert(
+        "extension".into(),
+        path.extension()
+            .map(|ext| ext.to_string_lossy().to_string())
+            .unwrap_or_default()
+            .into(),
+    );
+
+    let metadata = path.symlink_metadata().ok();
+    let file_type = metadata.as_ref().map(|m| m.file_type());
+    item.insert(
+        "is_file".into(),
+        file_type.is_some_and(|t| t.is_file()).into(),
+    );
+    item.insert(
+        "is_dir".into(),
+        file_type.is_some_and(|t| t.is_dir()).into(),
+    );
+    item.insert(
+        "is_symlink".into(),
+        file_type.is_some_and(|t| t.is_symlink()).into(),
+    );
+    let size = metadata.as_ref().map_or(0, |m| m.len());
+    item.insert("size".into(), (size as rhai::INT).into());
+
+    let modified = metadata
+        .as_ref()
+        .and_then(|m| m.modified().ok())
+        .unwrap_or(UNIX_EPOCH);
+    let seconds = |duration: Duration| duration.as_secs() as rhai::INT;
+    item.insert(
+        "modified".into(),
+        seconds(modified.duration_since(UNIX_EPOCH).unwrap_or_default()).into(),
+    );
+    item.insert(
+        "age".into(),
+        seconds(
+            SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default(),
+        )
+        .into(),
+    );
+
+    #[cfg(unix)]
+    if let Some(metadata) = &metadata {
+        use std::os::unix::fs::MetadataExt;
+        item.insert("owner".into(), rhai::INT::from(metadata.uid()).into());
+        item.insert("group".into(), rhai::INT::from(metadata.gid()).into());
+    }
+
+    item
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io;
+
+    fn script(code: &str) -> RuleScript {
+        RuleScript {
+            path: PathBuf::from("rules.rhai"),
+            code: code.to_string(),
+        }
+    }
+
+    /// Evaluates the rule on `path` like the walk does.
+    fn try_evaluate(rule: &WalkerRule, path: &Path, source: &Path) -> io::Result<WalkerRuleResult> {
+        let config = rebackup::WalkerConfig {
+            rules: Vec::new(),
+            follow_symlinks: false,
+            drop_empty_dirs: false,
+        };
+        (rule.action)(path, &config, source)
+    }
+
+    fn evaluate(rule: &WalkerRule, path: &Path, source: &Path) -> WalkerRuleResult {
+        try_evaluate(rule, path, source).expect("Failed to evaluate")
+    }
+
+    #[test]
+    fn test_script_rule() -> io::Result<()> {
+        let test_dir = tempfile::tempdir()?;
+        let source = test_dir.path();
+        fs::create_dir_all(source.join("app/target"))?;
+        fs::create_dir_all(source.join("notes/target"))?;
+        File::create(source.join("app/Cargo.toml"))?;
+        fs::write(source.join("app/big.bin"), vec![0; 2000])?;
+
+        let rule = script_rule(
+            script(
+                r#"
+                if item.is_dir && item.name == "target" && exists(item.parent + "/Cargo.toml") {
+                    "exclude"
+                } else if item.is_file && item.size > 1000 {
+                    "include"
+                }
+                "#,
+            ),
+            DEFAULT_TIMEOUT,
+        );
+        assert_eq!(rule.name, "script:rules.rhai");
+
+        assert!(matches!(
+            evaluate(&rule, &source.join("app/target"), source),
+            WalkerRuleResult::ExcludeItem
+        ));
+        assert!(matches!(
+            evaluate(&rule, &source.join("notes/target"), source),
+            WalkerRuleResult::SkipRule
+        ));
+        assert!(matches!(
+            evaluate(&rule, &source.join("app/big.bin"), source),
+            WalkerRuleResult::IncludeItemAbsolute
+        ));
+        assert!(matches!(
+            evaluate(&rule, &source.join("app/Cargo.toml"), source),
+            WalkerRuleResult::SkipRule
+        ));
+
+        // A symbolic link in the source does not let a script check paths outside of it
+        #[cfg(unix)]
+        {
+            let outside = tempfile::tempdir()?;
+            File::create(outside.path().join("secret"))?;
+            std::os::unix::fs::symlink(outside.path(), source.join("link"))?;
+            let exists = |path: &str| {
+                let code = format!("if exists(\"{}\") {{ \"exclude\" }}", path);
+                let rule = script_rule(script(&code), DEFAULT_TIMEOUT);
+                matches!(
+                    evaluate(&rule, &source.join("app/Cargo.toml"), source),
+                    WalkerRuleResult::ExcludeItem
+                )
+            };
+            assert!(exists("link"));
+            assert!(!exists("link/secret"));
+            assert!(exists("app/Cargo.toml"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_errors() {
+        let source = Path::new("/home/bob");
+        let error = |code: &str, timeout: Duration| match try_evaluate(
+            &script_rule(script(code), timeout),
+            &source.join("notes.txt"),
+            source,
+        ) {
+            // Only the item fails, not the whole walk
+            Err(err) if err.get_ref().is_some_and(|err| err.is::<RuleItemError>()) => {
+                err.to_string()
+            }
+            _ => panic!("The script did not fail on the item"),
+        };
+
+        assert_eq!(
+            error("let size = item.size;\nsize + missing", DEFAULT_TIMEOUT),
+            "rules.rhai line 2: Variable not found: missing (evaluating /home/bob/notes.txt)"
+        );
+        assert_eq!(
+            error("loop { }", Duration::from_millis(50)),
+            "rules.rhai line 1: evaluation for /home/bob/notes.txt took longer than 50ms"
+        );
+        assert_eq!(
+            error("42", DEFAULT_TIMEOUT),
+            "rules.rhai: returned a i64 instead of \"include\", \"exclude\" or nothing"
+        );
+        assert!(error("import \"os\" as os;", DEFAULT_TIMEOUT).starts_with("rules.rhai line 1: "));
+
+        // Scripts that do not compile are refused when they are loaded
+        let compile_error = |code: &str| match evaluate(
+            &script_rule(script(code), DEFAULT_TIMEOUT),
+            &source.join("notes.txt"),
+            source,
+        ) {
+            WalkerRuleResult::StrError(err) => err,
+            _ => panic!("The script compiled"),
+        };
+        assert!(compile_error("eval(\"1\")").starts_with("rules.rhai line 1: "));
+
+        // Paths outside the source cannot be checked
+        assert!(matches!(
+            evaluate(
+                &script_rule(
+                    script("if exists(\"../../etc/passwd\") { \"exclude\" }"),
+                    DEFAULT_TIMEOUT
+                ),
+                &source.join("notes.txt"),
+                source
+            ),
+            WalkerRuleResult::SkipRule
+        ));
+    }
+
+    #[test]
+    fn test_load() -> io::Result<()> {
+        let test_dir = tempfile::tempdir()?;
+        let path = test_dir.path().join("broken.rhai");
+        fs::write(&path, "if item.is_dir {\n  \"exclude\"\n")?;
+        let err = RuleScript::load(&path).unwrap_err();
+        assert!(
+            err.starts_with(&format!("{} line 3: ", path.to_string_lossy())),
+            "{}",
+            err
+        );
+
+        fs::write(&path, "if item.is_dir { \"exclude\" }")?;
+        assert!(RuleScript::load(&path).is_ok());
+        assert!(RuleScript::load(&test_dir.path().join("missing.rhai")).is_err());
+
+        Ok(())
+    }
+}
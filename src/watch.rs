@@ -0,0 +1,251 @@
+//! Continuous backup of the files that change, using file system notifications.
+//!
+//! [`watch`] runs a full backup and then watches the sources (with inotify on Linux). Changed
+//! items are collected until no more changes arrive for a moment and then backed up with
+//! [`Backup::run_paths`], which evaluates the rules on them like the walk does. A full backup
+//! is run again when notifications have been lost, and periodically to catch anything the
+//! notifications missed.
+//!
+use crate::error::{self, Error};
+use crate::{Backup, BackupReport, CancelToken};
+use notify::event::EventKind;
+use notify::{RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// How often the cancel token is checked while waiting for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Changes are backed up at the latest after this many times the debounce time, even if
+/// files keep changing.
+const MAX_DEBOUNCE_FACTOR: u32 = 10;
+
+/// How soon a full backup is tried again after a backup failed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How the sources are watched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchOptions {
+    /// How long no more changes have to arrive before the changed items are backed up.
+    pub debounce: Duration,
+    /// How often a full backup is run.
+    pub reconcile: Duration,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        WatchOptions {
+            debounce: Duration::from_secs(2),
+            reconcile: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Why a backup is run while watching.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchRun {
+    /// The full backup when the watch starts.
+    Initial,
+    /// The backup of the items that changed.
+    Changes { paths: usize },
+    /// A full backup because notifications have been lost, e.g. when the notification queue
+    /// overflowed.
+    Rescan,
+    /// The periodic full backup.
+    Reconcile,
+}
+
+impl WatchRun {
+    /// `true` if the whole sources are backed up.
+    pub fn is_full(&self) -> bool {
+        !matches!(self, WatchRun::Changes { .. })
+    }
+}
+
+/// Something that happened while watching.
+#[derive(Debug)]
+pub enum WatchEvent<'a> {
+    /// The sources are watched for changes.
+    Watching { sources: &'a [PathBuf] },
+    /// A backup has started.
+    RunStarted { run: &'a WatchRun },
+    /// A backup has finished.
+    RunFinished {
+        run: &'a WatchRun,
+        result: &'a error::Result<BackupReport>,
+    },
+}
+
+/// Backs up the sources of `backup` whenever they change, until `cancel` is cancelled.
+///
+/// `on_event` is called before and after every backup. A backup that fails does not stop the
+/// watch, a full backup is tried again a minute later instead. Only an error watching the
+/// sources is returned.
+pub fn watch(
+    backup: &Backup,
+    options: &WatchOptions,
+    cancel: &CancelToken,
+    mut on_event: impl FnMut(&WatchEvent),
+) -> error::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(|source| Error::Watch {
+        path: backup.sources()[0].clone(),
+        source,
+    })?;
+    // Watching starts before the initial backup, so that no change is missed
+    for source in backup.sources() {
+        watcher
+            .watch(source, RecursiveMode::Recursive)
+            .map_err(|source_err| Error::Watch {
+                path: source.clone(),
+                source: source_err,
+            })?;
+    }
+
+    let mut next_full = Instant::now();
+    let mut full_run = Some(WatchRun::Initial);
+    let mut changed = BTreeSet::new();
+    // When the first and the last of the collected changes arrived
+    let mut first_change = Instant::now();
+    let mut last_change = Instant::now();
+    let mut watching = false;
+
+    while !cancel.is_cancelled() {
+        if full_run.is_none() && Instant::now() >= next_full {
+            full_run = Some(WatchRun::Reconcile);
+        }
+        if let Some(run_kind) = full_run.take() {
+            // A full backup also backs up everything that has changed so far
+            changed.clear();
+            next_full = if run_backup(backup, run_kind, &[], &mut on_event) {
+                Instant::now() + options.reconcile
+            } else {
+                Instant::now() + RETRY_INTERVAL.min(options.reconcile)
+            };
+            continue;
+        }
+
+        if !watching {
+            on_event(&WatchEvent::Watching {
+                sources: backup.sources(),
+            });
+            watching = true;
+        }
+
+        if !changed.is_empty()
+            && (last_change.elapsed() >= options.debounce
+                || first_change.elapsed() >= options.debounce * MAX_DEBOUNCE_FACTOR)
+        {
+            let paths: Vec<PathBuf> = std::mem::take(&mut changed).into_iter().collect();
+            let run = WatchRun::Changes { paths: paths.len() };
+            if !run_backup(backup, run, &paths, &mut on_event) {
+                next_full = next_full.min(Instant::now() + RETRY_INTERVAL);
+            }
+            continue;
+        }
+
+        match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(event)) if event.need_rescan() => full_run = Some(WatchRun::Rescan),
+            // Removed items stay in the backup, and reading a file does not change it
+            Ok(Ok(event)) if matches!(event.kind, EventKind::Access(_) | EventKind::Remove(_)) => {}
+            Ok(Ok(event)) => {
+                if changed.is_empty() {
+                    first_change = Instant::now();
+                }
+                last_change = Instant::now();
+                changed.extend(event.paths);
+            }
+            // Changes may have been lost
+            Ok(Err(_)) => full_run = Some(WatchRun::Rescan),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs a full backup, or one of the changed `paths`. `true` if it did not fail.
+fn run_backup(
+    backup: &Backup,
+    run: WatchRun,
+    paths: &[PathBuf],
+    on_event: &mut impl FnMut(&WatchEvent),
+) -> bool {
+    on_event(&WatchEvent::RunStarted { run: &run });
+    let result = if run.is_full() {
+        backup.run()
+    } else {
+        backup.run_paths(paths)
+    };
+    on_event(&WatchEvent::RunFinished {
+        run: &run,
+        result: &result,
+    });
+    result.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::io::{self, Write};
+    use std::thread;
+
+    #[test]
+    fn test_watch() -> io::Result<()> {
+        let test_dir = tempfile::tempdir()?;
+        let source = test_dir.path().join("source");
+        let destination = test_dir.path().join("backup");
+        fs::create_dir_all(source.join("docs"))?;
+        File::create(source.join("docs/old.txt"))?;
+
+        let backup = Backup::builder()
+            .source(&source)
+            .destination(&destination)
+            .build()
+            .expect("Failed to build the backup");
+        let options = WatchOptions {
+            debounce: Duration::from_millis(100),
+            ..WatchOptions::default()
+        };
+
+        let cancel = CancelToken::new();
+        let mut runs = Vec::new();
+        let changed_source = source.clone();
+        watch(&backup, &options, &cancel, |event| match event {
+            WatchEvent::Watching { .. } if runs.len() == 1 => {
+                // Change files once the initial backup has finished
+                let source = changed_source.clone();
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(200));
+                    let mut file = File::create(source.join("docs/new.txt"))?;
+                    file.write_all(b"new")?;
+                    File::create(source.join("docs/setup.exe"))?;
+                    Ok::<(), io::Error>(())
+                });
+            }
+            WatchEvent::RunFinished { run, result } => {
+                let report = result.as_ref().expect("Failed to run the backup");
+                runs.push((run.is_full(), report.copied()));
+                if !run.is_full() {
+                    cancel.cancel();
+                }
+            }
+            _ => {}
+        })
+        .expect("Failed to watch");
+
+        // The executable is excluded by the default rules
+        assert_eq!(runs, vec![(true, 1), (false, 1)]);
+        let backup_path = crate::create_backup_file_path(
+            &fs::canonicalize(&source)?.join("docs/new.txt"),
+            &destination,
+        );
+        assert_eq!(fs::read(backup_path)?, b"new");
+
+        Ok(())
+    }
+}